@@ -0,0 +1,202 @@
+use std::path::Path;
+
+use crate::Res;
+
+/// the server used when nothing else is configured
+pub const DEFAULT_ENDPOINT: &str = "http://localhost:11434/api/generate";
+/// the model used for every task without one of its own, when nothing else is configured
+pub const DEFAULT_MODEL: &str = "codellama:7b-instruct";
+
+/// file in the current directory that settings are read from, if it exists
+pub const CONFIG_FILE: &str = "rakune.toml";
+pub const ENDPOINT_VAR: &str = "RAKUNE_OLLAMA_ENDPOINT";
+pub const MODEL_VAR: &str = "RAKUNE_MODEL";
+
+/// the settings found in one place, each of which may be missing
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Settings {
+    pub endpoint: Option<String>,
+    pub model: Option<String>,
+}
+
+impl Settings {
+    /// Read the settings from the `RAKUNE_*` environment variables
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Read the settings from variables looked up by `var`
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        Self {
+            endpoint: var(ENDPOINT_VAR).filter(|value| !value.is_empty()),
+            model: var(MODEL_VAR).filter(|value| !value.is_empty()),
+        }
+    }
+
+    /// Read the settings from a config file, none of them being set when it doesn't exist
+    pub fn from_file(path: impl AsRef<Path>) -> Res<Self> {
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Self::parse(&content)
+                .map_err(|e| format!("{}: {e}", path.as_ref().display()).into()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Parse the `key = "value"` lines of a config file. Only the subset of TOML needed for
+    /// plain string settings is understood
+    pub fn parse(content: &str) -> Res<Self> {
+        let mut settings = Self::default();
+
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(format!("line {} should look like `key = \"value\"`", i + 1))?;
+            let value = value
+                .trim()
+                .strip_prefix('"')
+                .and_then(|value| value.split_once('"'))
+                .filter(|(_, rest)| rest.trim().is_empty() || rest.trim().starts_with('#'))
+                .map(|(value, _)| value.to_string())
+                .ok_or(format!(
+                    "the value on line {} should be a quoted string",
+                    i + 1
+                ))?;
+
+            match key.trim() {
+                "endpoint" => settings.endpoint = Some(value),
+                "model" => settings.model = Some(value),
+                key => return Err(format!("unknown setting `{key}` on line {}", i + 1).into()),
+            }
+        }
+
+        Ok(settings)
+    }
+
+    /// Fill in the settings missing here from `fallback`
+    pub fn or(self, fallback: Settings) -> Settings {
+        Settings {
+            endpoint: self.endpoint.or(fallback.endpoint),
+            model: self.model.or(fallback.model),
+        }
+    }
+}
+
+/// the settings the CLI runs with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub endpoint: String,
+    pub model: String,
+}
+
+impl Config {
+    /// Settings given as flags win over those in the environment, which win over those in the
+    /// config file. Whatever is still missing takes the default
+    pub fn resolve(flags: Settings, env: Settings, file: Settings) -> Self {
+        let settings = flags.or(env).or(file);
+
+        Self {
+            endpoint: settings
+                .endpoint
+                .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string()),
+            model: settings.model.unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(endpoint: Option<&str>, model: Option<&str>) -> Settings {
+        Settings {
+            endpoint: endpoint.map(str::to_string),
+            model: model.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn flags_win_over_env_over_file() {
+        let config = Config::resolve(
+            settings(None, Some("flag")),
+            settings(Some("http://env"), Some("env")),
+            settings(Some("http://file"), Some("file")),
+        );
+
+        assert_eq!(config.model, "flag");
+        assert_eq!(config.endpoint, "http://env");
+    }
+
+    #[test]
+    fn file_fills_in_what_is_left() {
+        let config = Config::resolve(
+            Settings::default(),
+            settings(None, Some("env")),
+            settings(Some("http://file"), Some("file")),
+        );
+
+        assert_eq!(config.endpoint, "http://file");
+        assert_eq!(config.model, "env");
+    }
+
+    #[test]
+    fn defaults_apply_when_nothing_is_set() {
+        let config = Config::resolve(
+            Settings::default(),
+            Settings::default(),
+            Settings::default(),
+        );
+
+        assert_eq!(config.endpoint, DEFAULT_ENDPOINT);
+        assert_eq!(config.model, DEFAULT_MODEL);
+    }
+
+    #[test]
+    fn empty_variables_are_unset() {
+        let env = Settings::from_vars(|name| match name {
+            MODEL_VAR => Some("llama3".to_string()),
+            _ => Some(String::new()),
+        });
+
+        assert_eq!(env, settings(None, Some("llama3")));
+    }
+
+    #[test]
+    fn file_is_parsed() {
+        let content =
+            "# rakune\nendpoint = \"http://host:1/api/generate\"\n\nmodel = \"m\" # comment\n";
+
+        let parsed = Settings::parse(content).unwrap();
+
+        assert_eq!(
+            parsed,
+            settings(Some("http://host:1/api/generate"), Some("m"))
+        );
+    }
+
+    #[test]
+    fn unquoted_value_is_rejected() {
+        let error = Settings::parse("model = llama3\n").unwrap_err();
+
+        assert!(error.to_string().contains("quoted string"));
+    }
+
+    #[test]
+    fn unknown_key_is_rejected() {
+        let error = Settings::parse("model = \"m\"\ntemperature = \"1\"\n").unwrap_err();
+
+        assert_eq!(error.to_string(), "unknown setting `temperature` on line 2");
+    }
+
+    #[test]
+    fn missing_file_sets_nothing() {
+        let path = std::env::temp_dir().join("rakune-no-such-config.toml");
+
+        assert_eq!(Settings::from_file(path).unwrap(), Settings::default());
+    }
+}
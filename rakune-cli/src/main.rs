@@ -1,248 +1,281 @@
-use regex::Regex;
-use std::{env::args, error::Error, process::Command};
+use std::{collections::HashMap, env::args, error::Error};
 
+mod config;
 mod test;
 
 use rakune::{
-    llm::{Ollama, LLM},
-    repository::{Comment, Fragment, GitRepository, Transformation},
+    agent::{Agent, CommitPolicy, FixMode, DEFAULT_MAX_CONCURRENT_REQUESTS},
+    audit::AuditLog,
+    builder::{ClippyLinter, Pipeline, RustBuilder, RustTester, Severity, Stage},
+    coder::{Coder, CommitStyle, FileSize, FileSummaryCache, Task, CONVENTIONAL_TYPES},
+    llm::Ollama,
+    parser::TemplateParser,
+    prompt::Prompter,
+    repository::{Comment, CommitOptions, Fragment, GitRepository, TransformationKind},
 };
 
+use config::{Config, Settings, CONFIG_FILE};
+
 type Res<T> = Result<T, Box<dyn Error>>;
 
-fn detect_language() -> String {
-    "Rust".to_string()
+/// flags that pick the model of a task, e.g. `--commit-model llama3:8b`
+const MODEL_FLAGS: &[(&str, Task)] = &[
+    ("--edit-model", Task::Edit),
+    ("--commit-model", Task::Commit),
+    ("--explain-model", Task::Explain),
+    ("--summary-model", Task::Summarize),
+];
+
+const USAGE: &str = "usage: rakune-cli [--endpoint <url>] [--model <name>] \
+[--edit-model <name>] [--commit-model <name>] [--explain-model <name>] \
+[--summary-model <name>] [--force] <comment>";
+
+/// what rakune was asked to do on the command line
+struct Args<'a> {
+    /// the comment to address
+    message: &'a str,
+    /// the models picked by flags, by the task they're for
+    models: HashMap<Task, &'a str>,
+    /// the settings given with `--endpoint` and `--model`
+    settings: Settings,
+    /// run on a working tree that already has changes
+    force: bool,
 }
 
-struct RustBuilder<'a> {
-    /// Command arguments to run in order to build the project
-    build_args: &'a [&'a str],
-    lint_args: &'a [&'a str],
-}
-impl RustBuilder<'_> {
-    /// Builds the repository and returns an optional string of the build output if the build was
-    /// not successful, else do not return anything
-    fn build(&self, _: &GitRepository) -> Result<(), Vec<Comment>> {
-        Command::new(self.lint_args[0])
-            .args(&self.lint_args[1..])
-            .output()
-            .expect(&format!(
-                "failed to execute lint command {:?}",
-                self.lint_args
-            ));
-
-        let output = Command::new(self.build_args[0])
-            .args(&self.build_args[1..])
-            .output()
-            .expect(&format!(
-                "failed to call build command {:?}",
-                self.build_args
-            ));
-
-        if let Some(code) = output.status.code() {
-            if code == 0 {
-                return Ok(());
-            }
+fn parse_args(args: &[String]) -> Res<Args<'_>> {
+    let mut models = HashMap::new();
+    let mut settings = Settings::default();
+    let mut positional = Vec::new();
+    let mut force = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} expects a value."));
+        match arg.as_str() {
+            "--endpoint" => settings.endpoint = Some(value()?.clone()),
+            "--model" => settings.model = Some(value()?.clone()),
+            "--force" => force = true,
+            _ => match MODEL_FLAGS.iter().find(|(flag, _)| flag == arg) {
+                Some((_, task)) => {
+                    models.insert(*task, value()?.as_str());
+                }
+                None if arg.starts_with("--") => return Err(format!("Unknown flag {arg}.").into()),
+                None => positional.push(arg.as_str()),
+            },
         }
-
-        let output = std::str::from_utf8(&output.stderr).expect("failed to read stderr");
-
-        let file_regex = Regex::new("error: ([\\s\\S]*?)\n --> (.*?):(\\d+):(\\d+)")
-            .expect("Regex failed to compile.");
-
-        let errors = file_regex
-            .captures_iter(output)
-            .map(|c| c.extract())
-            .map(|(_, [error, file, line_no, _])| Comment {
-                message: Prompter::template_debug(error),
-                fragments: vec![Fragment {
-                    filepath: file.to_string(),
-                    line_range: (
-                        line_no.parse::<usize>().unwrap() - 1,
-                        line_no.parse::<usize>().unwrap(),
-                    ),
-                }],
-            })
-            .collect();
-
-        #[cfg(debug_assertions)]
-        eprintln!("################################# {:?}", errors);
-
-        Err(errors)
     }
-}
 
-/// performs the actions to edit the code in the repository
-pub struct Coder<M: LLM> {
-    pub transformation_count: usize,
-    pub repository: GitRepository,
-    pub llm: M,
+    let message = match positional.as_slice() {
+        [message] => *message,
+        [] => return Err("Expected a comment to address.".into()),
+        [_, extra @ ..] => {
+            let error_message = format!(
+                "Expected a single comment, but also got {}. Quote the comment if it has spaces.",
+                extra.join(" ")
+            );
+            return Err(error_message.into());
+        }
+    };
+
+    Ok(Args {
+        message,
+        models,
+        settings,
+        force,
+    })
 }
 
-impl<T: LLM> Coder<T> {
-    fn prompt(&self, prompt: &str) -> Res<String> {
-        self.llm.prompt(&prompt)
+fn ollama<'a>(endpoint: &'a str, model: &'a str) -> Ollama<'a> {
+    Ollama {
+        model,
+        endpoint,
+        keep_alive: Some("5m"),
+        stop: &[],
+        num_predict: Some(1024),
+        format: None,
     }
+}
 
-    // prompt -> embedding -> context(s) (code blocks fetched by the embedding)
-    //
-    // when you use a particular context "block", if it fails or succeeds the
-    // build/validation, then it will decrease or increase its score related to the prompt embedding
+// emulated a single comment on a current state of the repository
+fn main() -> Res<()> {
+    let args = args().skip(1).collect::<Vec<_>>();
+    let args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let config = Config::resolve(
+        args.settings.clone(),
+        Settings::from_env(),
+        Settings::from_file(CONFIG_FILE)?,
+    );
+
+    let comments = vec![Comment {
+        message: args.message.to_string(),
+        fragments: vec![Fragment {
+            filepath: "src/test.rs".to_string(),
+            line_range: (0, 6),
+            column: None,
+        }],
+    }];
 
-    fn generate_transformations(&mut self, comment: &Comment) -> Res<Vec<Transformation>> {
-        let mut prompt = Prompter::template_code(&comment.message);
+    let repo = GitRepository {
+        max_file_size: Some(256 * 1024),
+        reindent_updates: true,
+        ..GitRepository::open(".")?
+    };
+    for fragment in comments.iter().flat_map(|comment| &comment.fragments) {
+        if let Err(e) = repo.ensure_file(&fragment.filepath) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+    let builder = Pipeline {
+        stages: vec![
+            Stage {
+                name: "build",
+                builder: Box::new(RustBuilder {
+                    build_args: &["cargo", "build"],
+                    lint_args: &["cargo", "fmt"],
+                    scope_to_changes: true,
+                    scope_to_targets: false,
+                    check_only: true,
+                }),
+            },
+            Stage {
+                name: "clippy",
+                builder: Box::new(ClippyLinter {
+                    clippy_args: &["cargo", "clippy"],
+                    min_severity: Severity::Warning,
+                }),
+            },
+        ],
+    };
+    // tasks without a model of their own fall back to the configured one
+    let llm = ollama(&config.endpoint, &config.model);
+    let models = args
+        .models
+        .iter()
+        .map(|(task, model)| (*task, ollama(&config.endpoint, model)))
+        .collect::<HashMap<_, _>>();
+
+    if let Err(e) = llm.health_check() {
+        eprintln!("{e}\nstart the server with `ollama serve` or point rakune at a running one.");
+        std::process::exit(1);
+    }
 
-        if let Some(fragment) = comment.fragments.get(0) {
-            prompt += "\n### Here is the current context:\n";
+    let mut agent = Agent {
+        coder: Coder {
+            transformation_count: 2,
+            max_parse_attempts: 3,
+            max_context_chars: None,
+            whole_file_threshold: Some(FileSize::Lines(200)),
+            file_summaries: Some(FileSummaryCache::default()),
+            max_diff_chars: Some(8000),
+            prompt_examples: Prompter::EXAMPLES.len(),
+            example_commits: Some(1),
+            allowed_transformations: TransformationKind::ALL.iter().copied().collect(),
+            repository: repo,
+            llm,
+            models,
+            parser: TemplateParser,
+            sanitize_responses: true,
+            audit: AuditLog::from_env(),
+            on_event: None,
+        },
+        builder,
+        tester: Some(Box::new(RustTester {
+            test_args: &["cargo", "test"],
+        })),
+        fix_mode: FixMode::OneAtATime,
+        max_fix_attempts: Some(10),
+        commit_policy: CommitPolicy::Squashed,
+        commit_style: CommitStyle::Conventional {
+            types: CONVENTIONAL_TYPES,
+        },
+        force: args.force,
+        touched: Vec::new(),
+        skip_unaffected_builds: true,
+        last_passed: None,
+        max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+    };
 
-            let _temporal_context = self.repository.temporal_context(&fragment)?;
-            let spatial_context = self.repository.spatial_context(&fragment)?;
+    agent.ensure_clean()?;
 
-            for context in _temporal_context {
-                prompt += "\n";
-                prompt += &context;
-            }
+    let options = CommitOptions::default();
+    for comment in &comments {
+        agent.address(comment, &options)?;
+    }
 
-            for context in spatial_context {
-                prompt += "\n";
-                prompt += &context;
-            }
-        }
+    agent.finish(&comments, &options)?;
 
-        // TODO: jump from answer to transformations
-        // use the answer to construct a sequence of transformations
+    Ok(())
+}
 
-        let mut transformations = Vec::new();
-        while transformations.is_empty() {
-            let answer = self.prompt(&prompt)?;
-            transformations = Transformation::parse_from(answer.as_str())?;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        assert!(!transformations.is_empty());
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
 
-        transformations
-            .iter()
-            .try_for_each(|t| self.repository.transform(t))?;
+    #[test]
+    fn force_is_off_by_default() {
+        let args = args(&["fix this"]);
 
-        Ok(transformations)
+        assert!(!parse_args(&args).unwrap().force);
     }
 
-    fn generate_commit(&self, repo: &GitRepository) -> Res<String> {
-        // summarize the diff when creating a commit message
-        let diff = repo.diff(None)?;
-        let prompt = &format!(
-            "summarize the following diff as a commit message in less than 20 words:\n\n{}",
-            diff
-        );
-        self.prompt(prompt)
-    }
-}
+    #[test]
+    fn force_flag_is_parsed() {
+        let args = args(&["--force", "fix this"]);
+        let parsed = parse_args(&args).unwrap();
 
-struct Prompter;
-impl Prompter {
-    fn template_code(p: &str) -> String {
-        format!(
-            r#"You are a {} programmer. {}
-
-Please use the following template to describe where to update the code:
-
-```
-UpdateFragment:
-    filepath: the path to the file being changes (string)
-    start_line: the starting line to update (int)
-    end_line: the ending line to update (int)
-    content: the code the replace within the lines (string)
-```
-
-Do NOT provide any extra content beyond this template.
-
-## Here are a couple of examples:
-
-Update the function foo to print "hello!"
-
->>>>
-0 fn foo() {{
-1     println!("chili dogs")
-2 }}
-<<<<
-
-```
-UpdateFragment:
-    filepath: src/hello.rs
-    start_line: 1
-    end_line: 1
-    content: println!("hello!")
-```
-
----
-
-Remove the uneeded code in add_5().
-
->>>>
-0 fn add_5(x: u8) -> u8 {{
-1   let ans = x + 5;
-2   return ans;
-3 }}
-<<<<
-
-```
-UpdateFragment:
-    filepath: src/addition.rs
-    start_line: 1
-    end_line: 2
-    content: return x + 5;
-```
-"#,
-            detect_language(),
-            p
-        )
+        assert!(parsed.force);
+        assert_eq!(parsed.message, "fix this");
     }
 
-    fn template_debug(p: &str) -> String {
-        format!("fix this build error:\n\n{}", p)
+    #[test]
+    fn missing_comment_is_rejected() {
+        let error = parse_args(&args(&["--model", "m"])).err().unwrap();
+
+        assert_eq!(error.to_string(), "Expected a comment to address.");
     }
-}
 
-// emulated a single comment on a current state of the repository
-fn main() -> Res<()> {
-    let args = args().collect::<Vec<_>>();
+    #[test]
+    fn unknown_flag_is_rejected() {
+        let error = parse_args(&args(&["--modle", "m", "fix this"]))
+            .err()
+            .unwrap();
 
-    let mut comments = vec![Comment {
-        message: args[1].clone(),
-        fragments: vec![Fragment {
-            filepath: "src/test.rs".to_string(),
-            line_range: (0, 6),
-        }],
-    }];
+        assert_eq!(error.to_string(), "Unknown flag --modle.");
+    }
 
-    let repo = GitRepository::default();
-    let builder = RustBuilder {
-        build_args: &["cargo", "build"],
-        lint_args: &["cargo", "fmt"],
-    };
-    let ollama = Ollama {
-        model: "codellama:7b-instruct",
-        endpoint: "http://localhost:11434/api/generate",
-    };
+    #[test]
+    fn extra_positional_is_rejected() {
+        let error = parse_args(&args(&["fix", "this"])).err().unwrap();
 
-    let mut coder = Coder {
-        transformation_count: 2,
-        repository: repo,
-        llm: ollama,
-    };
+        assert!(error.to_string().contains("also got this"));
+    }
 
-    while let Some(comment) = comments.pop() {
-        coder.generate_transformations(&comment)?;
+    #[test]
+    fn flag_without_a_value_is_rejected() {
+        let error = parse_args(&args(&["fix this", "--endpoint"]))
+            .err()
+            .unwrap();
 
-        // self-correct until the program compiles
-        while let Err(errors) = builder.build(&coder.repository) {
-            if let Some(error) = errors.first() {
-                coder.generate_transformations(&error)?;
-            }
-        }
+        assert_eq!(error.to_string(), "--endpoint expects a value.");
     }
 
-    let _commit_message = coder.generate_commit(&coder.repository)?;
+    #[test]
+    fn models_are_picked_by_task() {
+        let args = args(&["--commit-model", "small", "fix this"]);
+        let parsed = parse_args(&args).unwrap();
 
-    Ok(())
+        assert_eq!(parsed.models.get(&Task::Commit), Some(&"small"));
+        assert_eq!(parsed.models.get(&Task::Edit), None);
+    }
 }
@@ -0,0 +1,769 @@
+use std::cmp::Reverse;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::PathBuf;
+
+use crate::audit::AuditEvent;
+use crate::builder::Builder;
+use crate::coder::{parse_with_retries, retry_empty, Coder, CoderEvent, CommitStyle, Task};
+use crate::llm::{Semaphore, LLM};
+use crate::parser::{TemplateParser, TransformationParser};
+use crate::repository::{Comment, CommitOptions, ScoredTransformation, Transformation};
+use crate::Result;
+
+/// requests to the model in flight at once by default, low enough for a local server
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 2;
+
+/// how the comments from a failed build are addressed before building again
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FixMode {
+    /// fix only the first error per build cycle. slower, but easier on models that get confused
+    /// by many edits at once
+    #[default]
+    OneAtATime,
+    /// fix every distinct error from the build before rebuilding
+    Batched,
+}
+
+/// when the changes made for comments are committed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommitPolicy {
+    /// commit once each comment is addressed and the repository builds, leaving a reviewable
+    /// history of the steps taken
+    PerStep,
+    /// a single commit for all comments at the end
+    #[default]
+    Squashed,
+    /// commit only the files changed for each comment once it builds, with the comment as the
+    /// message, so that every commit can be reviewed and bisected on its own
+    PerComment,
+}
+
+/// drives a [`Coder`] against a [`Builder`] until review comments are addressed and the
+/// repository builds again
+pub struct Agent<M: LLM, B: Builder, P: TransformationParser = TemplateParser> {
+    pub coder: Coder<M, P>,
+    pub builder: B,
+    /// run once the build is clean, e.g. a [`crate::builder::RustTester`], so that failing
+    /// tests get fixed as well
+    pub tester: Option<Box<dyn Builder>>,
+    pub fix_mode: FixMode,
+    /// number of fix rounds after which the agent gives up on getting the repository to pass.
+    /// unlimited when unset
+    pub max_fix_attempts: Option<usize>,
+    pub commit_policy: CommitPolicy,
+    /// how the messages of the commits made with a generated message are written
+    pub commit_style: CommitStyle,
+    /// edit a working tree that already has changes, mixing them up with those of the agent
+    pub force: bool,
+    /// files changed by the transformations applied since the last commit
+    pub touched: Vec<String>,
+    /// skip the build when every file changed since it last passed is one that the build
+    /// doesn't read, e.g. documentation. see [`crate::repository::GitRepository::affects_build`]
+    pub skip_unaffected_builds: bool,
+    /// the files that differed from HEAD when the build last passed, with a hash of their
+    /// content or `None` for those that were removed
+    pub last_passed: Option<Vec<(String, Option<u64>)>>,
+    /// most requests to the model in flight at once while comments are fixed or blocks are
+    /// embedded concurrently, see [`DEFAULT_MAX_CONCURRENT_REQUESTS`]
+    pub max_concurrent_requests: usize,
+}
+
+impl<M: LLM, B: Builder, P: TransformationParser> Agent<M, B, P> {
+    /// Refuse to start on a working tree with changes that aren't the agent's, unless `force` is
+    /// set. Returns the current branch so that it can be restored afterwards
+    pub fn ensure_clean(&self) -> Result<String> {
+        let branch = self.coder.repository.current_branch()?;
+
+        if !self.force && !self.coder.repository.is_clean()? {
+            let error_message = format!(
+                "The working tree of {branch} has uncommitted changes. Commit or stash them first, or force the agent to run anyway."
+            );
+            return Err(error_message.into());
+        }
+
+        Ok(branch)
+    }
+
+    /// Apply the transformations the coder generates for a single comment
+    pub fn fix_comment(&mut self, comment: &Comment) -> Result<Vec<Transformation>> {
+        let transformations = self.coder.generate_transformations(comment)?;
+        self.record(comment, &transformations)?;
+        Ok(transformations)
+    }
+
+    /// Remember what was applied for a comment, in the history and among the touched files
+    fn record(&mut self, comment: &Comment, transformations: &[Transformation]) -> Result<()> {
+        if let Some(history) = &mut self.coder.repository.history {
+            history.record(comment, transformations)?;
+        }
+
+        for file in self.coder.repository.touched_files(transformations)? {
+            if !self.touched.contains(&file) {
+                self.touched.push(file);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Commit the changes made so far, returning the new revision
+    pub fn commit(&mut self, commit_message: &str, options: &CommitOptions) -> Result<String> {
+        let revision = self.coder.repository.commit(commit_message, options)?;
+        self.touched.clear();
+        self.coder.emit(CoderEvent::Committed(&revision));
+        Ok(revision)
+    }
+
+    /// Commit the touched files with the comment they were changed for as the message. Nothing
+    /// is committed when none of them changed
+    fn commit_touched(
+        &mut self,
+        comment: &Comment,
+        options: &CommitOptions,
+    ) -> Result<Option<String>> {
+        let files = self.touched_changes()?;
+        if files.is_empty() {
+            return Ok(None);
+        }
+
+        let message = comment.message.trim();
+        let summary = message.lines().next().unwrap_or_default();
+        let commit_message = match summary == message {
+            true => summary.to_string(),
+            false => format!("{summary}\n\n{message}"),
+        };
+
+        let options = CommitOptions {
+            paths: Some(files),
+            ..options.clone()
+        };
+        self.commit(&commit_message, &options).map(Some)
+    }
+
+    /// The touched files that have changes to commit, as git names them
+    fn touched_changes(&self) -> Result<Vec<PathBuf>> {
+        let repository = &self.coder.repository;
+        let changed = repository.changed_files()?;
+
+        let mut files = Vec::new();
+        for file in &self.touched {
+            let file = repository.relative_path(file)?;
+            if changed.contains(&file) && !files.contains(&file) {
+                files.push(file);
+            }
+        }
+
+        Ok(files.into_iter().map(PathBuf::from).collect())
+    }
+
+    /// Fix a comment and self-correct until the repository builds again, committing the result
+    /// under [`CommitPolicy::PerStep`]. Returns the new revision if a commit was made
+    pub fn address(
+        &mut self,
+        comment: &Comment,
+        options: &CommitOptions,
+    ) -> Result<Option<String>> {
+        self.fix_comment(comment)?;
+        self.run_until_builds()?;
+
+        match self.commit_policy {
+            CommitPolicy::PerStep => self.commit_generated(&[comment], options),
+            CommitPolicy::PerComment => self.commit_touched(comment, options),
+            CommitPolicy::Squashed => Ok(None),
+        }
+    }
+
+    /// Commit everything left over from addressing `comments` under [`CommitPolicy::Squashed`].
+    /// Returns the new revision if a commit was made
+    pub fn finish(
+        &mut self,
+        comments: &[Comment],
+        options: &CommitOptions,
+    ) -> Result<Option<String>> {
+        match self.commit_policy {
+            CommitPolicy::PerStep | CommitPolicy::PerComment => Ok(None),
+            CommitPolicy::Squashed => {
+                self.commit_generated(&comments.iter().collect::<Vec<_>>(), options)
+            }
+        }
+    }
+
+    /// Commit the working tree with a generated message, quoting the comments that led to the
+    /// changes in the body. Nothing is committed when there are no changes
+    fn commit_generated(
+        &mut self,
+        comments: &[&Comment],
+        options: &CommitOptions,
+    ) -> Result<Option<String>> {
+        if self.coder.repository.changed_files()?.is_empty() {
+            return Ok(None);
+        }
+
+        let summary = self.coder.generate_commit(self.commit_style)?;
+        let body = comments
+            .iter()
+            .map(|comment| comment.message.trim())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        let commit_message = format!("{}\n\n{}", summary.trim(), body);
+
+        // a forced run started out with changes of its own, which are left out of the commit
+        if self.force {
+            let paths = self.touched_changes()?;
+            if paths.is_empty() {
+                return Ok(None);
+            }
+            let options = CommitOptions {
+                paths: Some(paths),
+                ..options.clone()
+            };
+            return self.commit(&commit_message, &options).map(Some);
+        }
+
+        self.commit(&commit_message, options).map(Some)
+    }
+
+    /// Build the repository, then run the tester once the build is clean
+    fn check(&self) -> std::result::Result<(), Vec<Comment>> {
+        let on_line = &mut |line: &str| self.coder.emit(CoderEvent::BuildOutput(line));
+        self.builder
+            .build_with_output(&self.coder.repository, on_line)?;
+
+        match &self.tester {
+            Some(tester) => tester.build_with_output(&self.coder.repository, on_line),
+            None => Ok(()),
+        }
+    }
+
+    /// The files that differ from HEAD, with a hash of their content or `None` once removed
+    fn changed_state(&self) -> Result<Vec<(String, Option<u64>)>> {
+        let repository = &self.coder.repository;
+        Ok(repository
+            .changed_files()?
+            .into_iter()
+            .map(|file| {
+                let hash = std::fs::read(repository.path(&file)).ok().map(|content| {
+                    let mut hasher = DefaultHasher::new();
+                    content.hash(&mut hasher);
+                    hasher.finish()
+                });
+                (file, hash)
+            })
+            .collect())
+    }
+
+    /// Whether the build can be skipped, because nothing it reads changed since it last passed
+    fn build_unaffected(&self) -> Result<bool> {
+        let (true, Some(passed)) = (self.skip_unaffected_builds, &self.last_passed) else {
+            return Ok(false);
+        };
+
+        let current = self.changed_state()?;
+        let hash_in = |state: &[(String, Option<u64>)], file: &str| {
+            state.iter().find(|(f, _)| f == file).map(|(_, hash)| *hash)
+        };
+
+        Ok(current
+            .iter()
+            .chain(passed)
+            .map(|(file, _)| file)
+            .filter(|file| hash_in(&current, file) != hash_in(passed, file))
+            .all(|file| !self.coder.repository.affects_build(file)))
+    }
+
+    /// self-correct until the repository builds and, with a tester, its tests pass
+    pub fn run_until_builds(&mut self) -> Result<()> {
+        for attempt in 0.. {
+            if self.build_unaffected()? {
+                #[cfg(debug_assertions)]
+                eprintln!("*************** Skipped Build **************\nnothing the build reads changed since it last passed\n");
+
+                break;
+            }
+
+            self.coder.emit(CoderEvent::BuildStarted);
+            let result = self.check();
+            if let Err(errors) = &result {
+                self.coder.emit(CoderEvent::BuildFailed(errors.len()));
+            }
+
+            self.coder.audit(&AuditEvent::Build {
+                success: result.is_ok(),
+                errors: result.as_ref().err().map_or(0, Vec::len),
+            })?;
+
+            if let Some(history) = &mut self.coder.repository.history {
+                history.resolve(result.is_ok())?;
+            }
+
+            let Err(errors) = result else {
+                if self.skip_unaffected_builds {
+                    self.last_passed = Some(self.changed_state()?);
+                }
+                break;
+            };
+
+            if errors.is_empty() {
+                return Err("The build failed without reporting anything to fix.".into());
+            }
+
+            if self.max_fix_attempts.is_some_and(|max| attempt >= max) {
+                let error_message = format!("The repository still fails after {attempt} fixes.");
+                return Err(error_message.into());
+            }
+
+            match self.fix_mode {
+                FixMode::OneAtATime => {
+                    if let Some(error) = errors.first() {
+                        self.fix_comment(error)?;
+                    }
+                }
+                FixMode::Batched => {
+                    for error in dedup_errors(errors) {
+                        self.fix_comment(&error)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Embed every one of `texts` with `embed`, e.g. [`crate::llm::Ollama::embed`], several at
+    /// once but never more than `max_concurrent_requests`, so that a file with many context
+    /// blocks doesn't flood the server. The embeddings are in the order of `texts`
+    pub fn embed_concurrently<E>(&self, texts: &[&str], embed: E) -> Result<Vec<Vec<f32>>>
+    where
+        E: Fn(&str) -> Result<Vec<f32>> + Sync,
+    {
+        let limit = &Semaphore::new(self.max_concurrent_requests);
+        let embed = &embed;
+
+        std::thread::scope(|scope| {
+            let handles = texts
+                .iter()
+                .map(|text| {
+                    scope.spawn(move || {
+                        let _permit = limit.acquire();
+                        embed(text).map_err(|e| e.to_string())
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|handle| match handle.join() {
+                    Ok(embedding) => Ok(embedding?),
+                    Err(_) => Err("The request for an embedding panicked.".into()),
+                })
+                .collect()
+        })
+    }
+}
+
+impl<M: LLM + Sync, B: Builder, P: TransformationParser + Sync> Agent<M, B, P> {
+    /// Fix many comments at once, asking the model about comments on different files
+    /// concurrently. Comments sharing any file are fixed one after another so that each of them
+    /// is shown the lines as left by the previous edit, see [`group_by_files`]. Returns the
+    /// outcome of every comment, in the order they were given.
+    pub fn fix_comments(&mut self, comments: Vec<Comment>) -> Vec<Result<Vec<Transformation>>> {
+        let groups = group_by_files(&comments);
+
+        let mut results = comments.iter().map(|_| None).collect::<Vec<_>>();
+        for round in 0.. {
+            let batch = groups
+                .iter()
+                .filter_map(|group| group.get(round).copied())
+                .collect::<Vec<_>>();
+            if batch.is_empty() {
+                break;
+            }
+
+            // requests are built after the previous round was applied, so line numbers are fresh
+            let mut requests = Vec::new();
+            for i in batch {
+                match self.coder.request(&comments[i]) {
+                    Ok(request) => requests.push((i, request)),
+                    Err(e) => results[i] = Some(Err(e)),
+                }
+            }
+
+            for _ in &requests {
+                self.coder.emit(CoderEvent::PromptStarted);
+            }
+
+            let llm = self.coder.llm_for(Task::Edit);
+            let parser = &self.coder.parser;
+            let max_parse_attempts = self.coder.max_parse_attempts;
+            let sanitize = self.coder.sanitize_responses;
+            let allowed = &self.coder.allowed_transformations;
+            let limit = &Semaphore::new(self.max_concurrent_requests);
+            let answers = std::thread::scope(|scope| {
+                let handles = requests
+                    .iter()
+                    .map(|(_, (system, prompt))| {
+                        scope.spawn(move || {
+                            let mut exchanges = Vec::new();
+                            let parsed = parse_with_retries(
+                                parser,
+                                max_parse_attempts,
+                                sanitize,
+                                allowed,
+                                prompt,
+                                |request| {
+                                    let answer = retry_empty(|| {
+                                        let _permit = limit.acquire();
+                                        llm.prompt_with_system(system, request)
+                                    });
+                                    exchanges
+                                        .push((request.to_string(), answer.as_ref().ok().cloned()));
+                                    answer
+                                },
+                            );
+                            (parsed.map_err(|e| e.to_string()), exchanges)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle.join().unwrap_or_else(|_| {
+                            (
+                                Err("The request for the comment panicked.".to_string()),
+                                Vec::new(),
+                            )
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            });
+
+            for ((i, (system, _)), (parsed, exchanges)) in requests.into_iter().zip(answers) {
+                results[i] = Some(self.apply_answer(&comments[i], &system, &exchanges, parsed));
+            }
+        }
+
+        results.into_iter().flatten().collect()
+    }
+
+    /// Record the exchanges with the model for a comment, then apply what it answered
+    fn apply_answer(
+        &mut self,
+        comment: &Comment,
+        system: &str,
+        exchanges: &[(String, Option<String>)],
+        parsed: std::result::Result<Vec<ScoredTransformation>, String>,
+    ) -> Result<Vec<Transformation>> {
+        for (prompt, response) in exchanges {
+            self.coder.audit(&AuditEvent::Prompt {
+                system: Some(system),
+                prompt,
+            })?;
+            if let Some(response) = response {
+                self.coder.audit(&AuditEvent::Response { response })?;
+            }
+        }
+
+        let transformations = self
+            .coder
+            .apply(comment, parsed?)?
+            .into_iter()
+            .map(|scored| scored.transformation)
+            .collect::<Vec<_>>();
+        self.record(comment, &transformations)?;
+        Ok(transformations)
+    }
+}
+
+/// Drop errors that point at the same location, ordering the rest from the bottom of each file
+/// upwards so that fixing one error does not shift the lines of those still pending
+fn dedup_errors(mut errors: Vec<Comment>) -> Vec<Comment> {
+    let location = |comment: &Comment| {
+        comment
+            .fragments
+            .first()
+            .map(|f| (f.filepath.clone(), f.line_range))
+    };
+
+    errors.sort_by_key(|comment| location(comment).map(|(file, range)| (file, Reverse(range))));
+    errors.dedup_by(|a, b| location(a).is_some() && location(a) == location(b));
+
+    errors
+}
+
+/// The indices of `comments` grouped so that comments sharing a file through any of their
+/// fragments land in the same group, even when they only share it through a third comment.
+/// Comments without fragments may edit anything and are grouped together. Groups are in the
+/// order of their first comment
+fn group_by_files(comments: &[Comment]) -> Vec<Vec<usize>> {
+    let mut parent = (0..comments.len()).collect::<Vec<_>>();
+    let root = |parent: &mut Vec<usize>, mut i: usize| {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    };
+
+    let mut owners: Vec<(Option<&str>, usize)> = Vec::new();
+    for (i, comment) in comments.iter().enumerate() {
+        let files = match comment.fragments.is_empty() {
+            true => vec![None],
+            false => comment
+                .fragments
+                .iter()
+                .map(|fragment| Some(fragment.filepath.as_str()))
+                .collect(),
+        };
+        for file in files {
+            match owners.iter().find(|(owned, _)| *owned == file) {
+                Some(&(_, owner)) => {
+                    let (a, b) = (root(&mut parent, owner), root(&mut parent, i));
+                    // the earlier comment stays the root, keeping the groups in order
+                    parent[a.max(b)] = a.min(b);
+                }
+                None => owners.push((file, i)),
+            }
+        }
+    }
+
+    let mut groups: Vec<(usize, Vec<usize>)> = Vec::new();
+    for i in 0..comments.len() {
+        let group = root(&mut parent, i);
+        match groups.iter_mut().find(|(root, _)| *root == group) {
+            Some((_, members)) => members.push(i),
+            None => groups.push((group, vec![i])),
+        }
+    }
+
+    groups.into_iter().map(|(_, members)| members).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::coder::tests::{coder, comment, update_answer, Scripted};
+    use crate::llm::tests::Gauge;
+    use crate::repository::tests::{temp_repo, write_file};
+    use crate::repository::GitRepository;
+
+    /// a builder whose builds always pass, counting them
+    #[derive(Default)]
+    struct Passing {
+        builds: std::cell::Cell<usize>,
+    }
+
+    impl Builder for Passing {
+        fn build(&self, _: &GitRepository) -> std::result::Result<(), Vec<Comment>> {
+            self.builds.set(self.builds.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn agent<M: LLM>(repository: GitRepository, llm: M) -> Agent<M, Passing> {
+        Agent {
+            coder: coder(repository, llm),
+            builder: Passing::default(),
+            tester: None,
+            fix_mode: FixMode::OneAtATime,
+            max_fix_attempts: Some(1),
+            commit_policy: CommitPolicy::Squashed,
+            commit_style: CommitStyle::Plain,
+            force: false,
+            touched: Vec::new(),
+            skip_unaffected_builds: false,
+            last_passed: None,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+        }
+    }
+
+    #[test]
+    fn dirty_tree_is_refused() {
+        let repository = temp_repo("dirty-refused", &[("a.txt", "a\n")]);
+        write_file(&repository, "a.txt", "changed\n");
+        let agent = agent(repository, Scripted::default());
+
+        let error = agent.ensure_clean().unwrap_err();
+
+        assert!(error.to_string().contains("uncommitted changes"));
+    }
+
+    #[test]
+    fn dirty_tree_is_accepted_when_forced() {
+        let repository = temp_repo("dirty-forced", &[("a.txt", "a\n")]);
+        write_file(&repository, "a.txt", "changed\n");
+        let agent = Agent {
+            force: true,
+            ..agent(repository, Scripted::default())
+        };
+
+        assert!(agent.ensure_clean().is_ok());
+    }
+
+    #[test]
+    fn touched_paths_are_compared_as_git_names_them() {
+        let repository = temp_repo("touched-paths", &[("src/a.rs", "a\n"), ("b.rs", "b\n")]);
+        write_file(&repository, "src/a.rs", "changed\n");
+        let agent = Agent {
+            touched: vec![
+                "./src/a.rs".to_string(),
+                "src/../src/a.rs".to_string(),
+                "b.rs".to_string(),
+            ],
+            ..agent(repository, Scripted::default())
+        };
+
+        let files = agent.touched_changes().unwrap();
+
+        assert_eq!(files, [PathBuf::from("src/a.rs")]);
+    }
+
+    #[test]
+    fn build_is_skipped_when_only_other_files_changed() {
+        let repository = temp_repo(
+            "skip-build",
+            &[
+                ("Cargo.toml", "[package]\nname = \"x\"\n"),
+                ("src/lib.rs", ""),
+                ("README.md", ""),
+            ],
+        );
+        let mut agent = Agent {
+            skip_unaffected_builds: true,
+            ..agent(repository, Scripted::default())
+        };
+
+        agent.run_until_builds().unwrap();
+        assert_eq!(agent.builder.builds.get(), 1);
+
+        write_file(&agent.coder.repository, "README.md", "docs\n");
+        agent.run_until_builds().unwrap();
+        assert_eq!(agent.builder.builds.get(), 1);
+
+        write_file(&agent.coder.repository, "src/lib.rs", "fn f() {}\n");
+        agent.run_until_builds().unwrap();
+        assert_eq!(agent.builder.builds.get(), 2);
+    }
+
+    fn on_files(files: &[&str]) -> Comment {
+        Comment {
+            message: "fix this".to_string(),
+            fragments: files
+                .iter()
+                .map(|file| comment(file, (0, 0)).fragments[0].clone())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn comments_sharing_any_file_are_grouped() {
+        let comments = [
+            on_files(&["a.rs"]),
+            on_files(&["c.rs"]),
+            on_files(&["b.rs", "a.rs"]),
+            on_files(&["d.rs"]),
+            on_files(&["d.rs", "b.rs"]),
+            on_files(&[]),
+            on_files(&[]),
+        ];
+
+        assert_eq!(
+            group_by_files(&comments),
+            [vec![0, 2, 3, 4], vec![1], vec![5, 6]]
+        );
+    }
+
+    /// a model that fixes a comment by replacing the first line of the first of `files` that
+    /// its prompt mentions with `fixed`, measuring how many prompts overlap
+    struct Fixer {
+        files: &'static [&'static str],
+        gauge: Gauge,
+    }
+
+    impl Fixer {
+        fn new(files: &'static [&'static str]) -> Self {
+            Self {
+                files,
+                gauge: Gauge::default(),
+            }
+        }
+    }
+
+    impl LLM for Fixer {
+        fn prompt(&self, prompt: &str) -> Result<String> {
+            let file = self
+                .files
+                .iter()
+                .find(|file| prompt.contains(*file))
+                .ok_or("The prompt mentions none of the files.")?;
+            Ok(self.gauge.measure(|| update_answer(file, 0, "fixed")))
+        }
+    }
+
+    #[test]
+    fn comments_on_two_files_are_both_fixed() {
+        let repository = temp_repo("fix-comments", &[("a.txt", "a\n"), ("b.txt", "b\n")]);
+        let mut agent = agent(repository, Fixer::new(&["a.txt", "b.txt"]));
+
+        let results = agent.fix_comments(vec![comment("a.txt", (0, 0)), comment("b.txt", (0, 0))]);
+
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(
+            agent.coder.repository.read_file("a.txt").unwrap(),
+            "fixed\n"
+        );
+        assert_eq!(
+            agent.coder.repository.read_file("b.txt").unwrap(),
+            "fixed\n"
+        );
+    }
+
+    const FILES: &[&str] = &["a.txt", "b.txt", "c.txt", "d.txt", "e.txt"];
+
+    #[test]
+    fn concurrent_prompts_respect_the_limit() {
+        let files = FILES.iter().map(|file| (*file, "x\n")).collect::<Vec<_>>();
+        let repository = temp_repo("prompt-limit", &files);
+        let mut agent = Agent {
+            max_concurrent_requests: 2,
+            ..agent(repository, Fixer::new(FILES))
+        };
+
+        let comments = FILES.iter().map(|file| comment(file, (0, 0))).collect();
+        let results = agent.fix_comments(comments);
+
+        assert!(results.iter().all(Result::is_ok));
+        assert!(agent.coder.llm.gauge.peak.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn concurrent_embeddings_respect_the_limit() {
+        let agent = Agent {
+            max_concurrent_requests: 2,
+            ..agent(temp_repo("embed-limit", &[]), Scripted::default())
+        };
+        let gauge = Gauge::default();
+
+        let embeddings = agent
+            .embed_concurrently(FILES, |text| Ok(gauge.measure(|| vec![text.len() as f32])))
+            .unwrap();
+
+        assert_eq!(embeddings, vec![vec![5.0]; FILES.len()]);
+        assert!(gauge.peak.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn failed_embedding_is_an_error() {
+        let agent = agent(temp_repo("embed-error", &[]), Scripted::default());
+
+        let result = agent.embed_concurrently(&["a", "b"], |text| match text {
+            "b" => Err("no model".into()),
+            _ => Ok(vec![1.0]),
+        });
+
+        assert_eq!(result.unwrap_err().to_string(), "no model");
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::repository::Transformation;
+use crate::Result;
+
+/// environment variable naming the file to write the audit log of a run to
+pub const AUDIT_LOG_ENV: &str = "RAKUNE_AUDIT_LOG";
+
+/// something that happened during a run, recorded so that sessions can be replayed later
+#[derive(Serialize)]
+#[serde(tag = "event")]
+pub enum AuditEvent<'a> {
+    Prompt {
+        system: Option<&'a str>,
+        prompt: &'a str,
+    },
+    Response {
+        response: &'a str,
+    },
+    Transformations {
+        transformations: &'a [Transformation],
+    },
+    Build {
+        success: bool,
+        errors: usize,
+    },
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    /// milliseconds since the unix epoch
+    timestamp: u128,
+    run_id: &'a str,
+    #[serde(flatten)]
+    event: &'a AuditEvent<'a>,
+}
+
+/// appends every [`AuditEvent`] of a run to a JSONL file
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    pub path: PathBuf,
+    /// identifies the records of a single run among others written to the same file
+    pub run_id: String,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        Self {
+            path: path.into(),
+            run_id: format!("{:x}-{:x}", started, std::process::id()),
+        }
+    }
+
+    /// An audit log at the path in [`AUDIT_LOG_ENV`], if it is set
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os(AUDIT_LOG_ENV).map(Self::new)
+    }
+
+    pub fn record(&self, event: &AuditEvent) -> Result<()> {
+        let record = AuditRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            run_id: &self.run_id,
+            event,
+        };
+
+        let mut file = File::options().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(&record)?)?;
+
+        Ok(())
+    }
+}
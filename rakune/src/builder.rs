@@ -0,0 +1,804 @@
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::prompt::Prompter;
+use crate::repository::{Comment, Fragment, GitRepository};
+
+/// builds a repository and reports the problems it finds as review comments
+pub trait Builder {
+    /// Builds the repository, returning the comments describing what failed if the build was
+    /// not successful, else do not return anything
+    fn build(&self, repository: &GitRepository) -> Result<(), Vec<Comment>>;
+
+    /// Build like [`Builder::build`], passing every line of output to `on_line` as soon as it is
+    /// printed, so that long or hung builds can be followed. Builders that can't stream only
+    /// report the result
+    fn build_with_output(
+        &self,
+        repository: &GitRepository,
+        on_line: &mut dyn FnMut(&str),
+    ) -> Result<(), Vec<Comment>> {
+        let _ = on_line;
+        self.build(repository)
+    }
+}
+
+/// Run `command` to completion like [`Command::output`], while passing each line it prints on
+/// stdout or stderr to `on_line` as it arrives
+pub fn output_streaming(
+    command: &mut Command,
+    on_line: &mut dyn FnMut(&str),
+) -> std::io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let (sender, receiver) = mpsc::channel();
+    let forward = |stream: Option<Box<dyn Read + Send>>, is_stdout: bool| {
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            let Some(stream) = stream else {
+                return;
+            };
+            let mut reader = BufReader::new(stream);
+            let mut line = Vec::new();
+            while reader
+                .read_until(b'\n', &mut line)
+                .is_ok_and(|read| read > 0)
+            {
+                if sender.send((is_stdout, std::mem::take(&mut line))).is_err() {
+                    break;
+                }
+            }
+        })
+    };
+    let readers = [
+        forward(child.stdout.take().map(|s| Box::new(s) as _), true),
+        forward(child.stderr.take().map(|s| Box::new(s) as _), false),
+    ];
+    drop(sender);
+
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    for (is_stdout, line) in receiver {
+        on_line(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']));
+        match is_stdout {
+            true => stdout.extend(line),
+            false => stderr.extend(line),
+        }
+    }
+
+    for reader in readers {
+        let _ = reader.join();
+    }
+
+    Ok(Output {
+        status: child.wait()?,
+        stdout,
+        stderr,
+    })
+}
+
+/// any function checking a repository is a builder, for plugging in other languages' checkers
+/// or a stand-in that always passes
+impl<F: Fn(&GitRepository) -> Result<(), Vec<Comment>>> Builder for F {
+    fn build(&self, repository: &GitRepository) -> Result<(), Vec<Comment>> {
+        self(repository)
+    }
+}
+
+pub struct RustBuilder<'a> {
+    /// Command arguments to run in order to build the project
+    pub build_args: &'a [&'a str],
+    pub lint_args: &'a [&'a str],
+    /// only build the packages containing changed files, falling back to the full build when
+    /// one of them can't be determined
+    pub scope_to_changes: bool,
+    /// with `scope_to_changes`, also only build the `--bin`, `--lib`, `--example`, or `--test`
+    /// targets that the changed files belong to, when they all belong to one package and their
+    /// targets can be told from their paths
+    pub scope_to_targets: bool,
+    /// run `cargo check` first and only do the full build once it passes, since the check
+    /// reports the same errors without spending time on codegen
+    pub check_only: bool,
+}
+
+impl RustBuilder<'_> {
+    /// The `-p <package>` arguments selecting the packages that own the changed files, followed
+    /// by the arguments selecting their targets with `scope_to_targets`
+    fn package_args(&self, repository: &GitRepository) -> Option<Vec<String>> {
+        let mut packages = Vec::new();
+        let mut targets = Some(Vec::new());
+
+        for file in repository.changed_files().ok()? {
+            let file = repository.path(&file);
+            let (package, directory) = cargo_package(&file)?;
+
+            let target = cargo_target(&file, &directory, &package);
+            match (&mut targets, target) {
+                (Some(targets), Some(target)) if !targets.contains(&target) => targets.push(target),
+                (_, None) => targets = None,
+                _ => {}
+            }
+
+            if !packages.contains(&package) {
+                packages.push(package);
+            }
+        }
+
+        if packages.is_empty() {
+            return None;
+        }
+
+        // target flags apply to every selected package, which may not all have such a target
+        let targets = match self.scope_to_targets && packages.len() == 1 {
+            true => targets.unwrap_or_default(),
+            false => Vec::new(),
+        };
+
+        Some(
+            packages
+                .into_iter()
+                .flat_map(|package| ["-p".to_string(), package])
+                .chain(targets.into_iter().flatten())
+                .collect(),
+        )
+    }
+}
+
+/// Find the name and directory of the package whose manifest is closest to `file`
+fn cargo_package(file: &Path) -> Option<(String, PathBuf)> {
+    let package_name =
+        Regex::new("(?m)^\\s*name\\s*=\\s*\"([^\"]+)\"").expect("Regex failed to compile.");
+
+    file.ancestors().skip(1).find_map(|directory| {
+        let manifest = std::fs::read_to_string(directory.join("Cargo.toml")).ok()?;
+        let package = &manifest[manifest.find("[package]")?..];
+        // stop at the next table so names of dependencies or binaries aren't picked up
+        let package = match package[1..].find("\n[") {
+            Some(end) => &package[..end + 1],
+            None => package,
+        };
+
+        package_name
+            .captures(package)
+            .map(|c| (c.extract::<1>().1[0].to_string(), directory.to_path_buf()))
+    })
+}
+
+/// The arguments selecting the cargo target that `file` belongs to, following cargo's layout
+/// conventions. Files under `src/` other than the crate roots are only attributed to a target
+/// when the package has just one of a library or a binary.
+fn cargo_target(file: &Path, package_dir: &Path, package: &str) -> Option<Vec<String>> {
+    let relative = file.strip_prefix(package_dir).ok()?;
+    let components = relative
+        .iter()
+        .map(|c| c.to_str())
+        .collect::<Option<Vec<_>>>()?;
+    let stem = |name: &str| name.strip_suffix(".rs").map(str::to_string);
+
+    let target = match components.as_slice() {
+        ["src", "lib.rs"] => ("--lib", None),
+        ["src", "main.rs"] => ("--bin", Some(package.to_string())),
+        ["src", "bin", name] => ("--bin", stem(name)),
+        ["src", "bin", name, ..] => ("--bin", Some(name.to_string())),
+        ["examples", name] => ("--example", stem(name)),
+        ["examples", name, ..] => ("--example", Some(name.to_string())),
+        ["tests", name] => ("--test", stem(name)),
+        ["tests", name, ..] => ("--test", Some(name.to_string())),
+        ["src", ..] => {
+            let lib = package_dir.join("src/lib.rs").is_file();
+            let bin = package_dir.join("src/main.rs").is_file();
+            match (lib, bin) {
+                (true, false) => ("--lib", None),
+                (false, true) => ("--bin", Some(package.to_string())),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    match target {
+        ("--lib", _) => Some(vec!["--lib".to_string()]),
+        (flag, Some(name)) => Some(vec![flag.to_string(), name]),
+        (_, None) => None,
+    }
+}
+
+impl Builder for RustBuilder<'_> {
+    fn build(&self, repository: &GitRepository) -> Result<(), Vec<Comment>> {
+        self.build_with_output(repository, &mut |_| {})
+    }
+
+    fn build_with_output(
+        &self,
+        repository: &GitRepository,
+        on_line: &mut dyn FnMut(&str),
+    ) -> Result<(), Vec<Comment>> {
+        repository
+            .command(self.lint_args[0])
+            .args(&self.lint_args[1..])
+            .output()
+            .unwrap_or_else(|_| panic!("failed to execute lint command {:?}", self.lint_args));
+
+        let package_args = match self.scope_to_changes {
+            true => self.package_args(repository),
+            false => None,
+        };
+
+        if self.check_only {
+            let mut check = repository.command("cargo");
+            check.args(["check", "--message-format=json"]);
+            check.args(package_args.iter().flatten());
+
+            let output = output_streaming(&mut check, on_line)
+                .unwrap_or_else(|_| panic!("failed to call cargo check"));
+
+            if !output.status.success() {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                return Err(cargo_diagnostics(&stdout, Severity::Error));
+            }
+        }
+
+        let mut build = repository.command(self.build_args[0]);
+        build.args(&self.build_args[1..]);
+        build.args(package_args.iter().flatten());
+
+        let output = output_streaming(&mut build, on_line)
+            .unwrap_or_else(|_| panic!("failed to call build command {:?}", self.build_args));
+
+        if let Some(code) = output.status.code() {
+            if code == 0 {
+                return Ok(());
+            }
+        }
+
+        let errors = rustc_errors(&output.stderr);
+
+        #[cfg(debug_assertions)]
+        eprintln!("################################# {:?}", errors);
+
+        Err(errors)
+    }
+}
+
+/// Parse the errors rendered by rustc on stderr into comments at their locations
+pub fn rustc_errors(stderr: &[u8]) -> Vec<Comment> {
+    // a stray invalid byte, e.g. in a path, shouldn't hide the errors around it
+    let output = String::from_utf8_lossy(stderr);
+
+    // the whole rendered error up to the blank line ending it, with the source snippet and
+    // any notes below the location
+    let file_regex = Regex::new(
+        "(?m)^error(?:\\[E\\d+\\])?: (.*)\n\\s*--> (.*?):(\\d+):(\\d+)\n([\\s\\S]*?)(?:\n\n|\\z)",
+    )
+    .expect("Regex failed to compile.");
+
+    file_regex
+        .captures_iter(&output)
+        .map(|c| c.extract())
+        // skip locations that aren't line numbers rather than failing the whole report
+        .filter_map(|(_, [error, file, line_no, column, details])| {
+            let line_no = line_no.parse::<usize>().ok()?;
+            Some(Comment {
+                message: Prompter::template_debug(&format!("{error}\n{details}")),
+                fragments: vec![Fragment {
+                    filepath: file.to_string(),
+                    line_range: (line_no.saturating_sub(1), line_no.saturating_sub(1)),
+                    column: column.parse::<usize>().ok().map(|c| c.saturating_sub(1)),
+                }],
+            })
+        })
+        .collect()
+}
+
+/// runs the test suite, turning each failing test into a comment at its assertion
+pub struct RustTester<'a> {
+    /// Command arguments to run in order to test the project
+    pub test_args: &'a [&'a str],
+}
+
+impl RustTester<'_> {
+    /// Run the tests of the project, returning a validation for every test that failed. A failed
+    /// run where no test panicked, e.g. because the tests didn't compile, yields none
+    pub fn generate_validations(&self, repository: &GitRepository) -> Vec<Validation> {
+        self.failed_output(repository, &mut |_| {})
+            .map(|output| test_validations(&output))
+            .unwrap_or_default()
+    }
+
+    /// Run the tests, returning their output if they failed
+    fn failed_output(
+        &self,
+        repository: &GitRepository,
+        on_line: &mut dyn FnMut(&str),
+    ) -> Option<String> {
+        let mut test = repository.command(self.test_args[0]);
+        test.args(&self.test_args[1..]);
+
+        let output = output_streaming(&mut test, on_line)
+            .unwrap_or_else(|_| panic!("failed to call test command {:?}", self.test_args));
+
+        if output.status.success() {
+            return None;
+        }
+
+        // panics of failed tests are reported on stdout, while panics of the harness are on stderr
+        Some(format!(
+            "{}\n{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
+
+impl Builder for RustTester<'_> {
+    fn build(&self, repository: &GitRepository) -> Result<(), Vec<Comment>> {
+        self.build_with_output(repository, &mut |_| {})
+    }
+
+    fn build_with_output(
+        &self,
+        repository: &GitRepository,
+        on_line: &mut dyn FnMut(&str),
+    ) -> Result<(), Vec<Comment>> {
+        match self.failed_output(repository, on_line) {
+            Some(output) => Err(test_validations(&output)
+                .iter()
+                .map(|validation| validation.to_comment_in(repository))
+                .collect()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// a test that failed
+#[derive(Clone, Debug)]
+pub struct Validation {
+    /// name of the failed test
+    pub test: String,
+    /// what the test reported when it failed
+    pub message: String,
+    /// the assertion that failed, when the output points at one
+    pub location: Option<Fragment>,
+}
+
+impl Validation {
+    /// Turn the failure into a comment for the coder at the failed assertion
+    pub fn to_comment(&self) -> Comment {
+        Comment {
+            message: Prompter::template_test_failure(&self.test, &self.message),
+            fragments: self.location.iter().cloned().collect(),
+        }
+    }
+
+    /// Turn the failure into a comment like [`Validation::to_comment`], which also points at the
+    /// function of the failed test in `repository`. Its source is then shown to the model along
+    /// with the definitions of the functions it calls, i.e. the code under test
+    pub fn to_comment_in(&self, repository: &GitRepository) -> Comment {
+        let mut comment = self.to_comment();
+        if let Some(test) = self.test_fragment(repository) {
+            comment.message += &format!(
+                "\n\nthe test is defined at lines {}-{} of {}.",
+                test.line_range.0 + repository.line_base,
+                test.line_range.1 + repository.line_base,
+                test.filepath
+            );
+            comment.fragments.push(test);
+        }
+        comment
+    }
+
+    /// The function of the failed test, looked up by the last segment of its name in the file
+    /// of the failure first, then in the one other Rust file defining it
+    fn test_fragment(&self, repository: &GitRepository) -> Option<Fragment> {
+        let name = self.test.rsplit("::").next()?;
+
+        if let Some(location) = &self.location {
+            if let Ok(fragment) = repository.fragment_for_symbol(&location.filepath, name) {
+                return Some(fragment);
+            }
+        }
+
+        let found = repository
+            .tracked_files()
+            .ok()?
+            .into_iter()
+            .filter_map(|file| file.to_str().map(str::to_string))
+            .filter(|file| file.ends_with(".rs"))
+            .filter_map(|file| repository.fragment_for_symbol(&file, name).ok())
+            .collect::<Vec<_>>();
+        match <[Fragment; 1]>::try_from(found) {
+            Ok([fragment]) => Some(fragment),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Turn every failure into a comment for the coder
+pub fn to_comments(validations: &[Validation]) -> Vec<Comment> {
+    validations.iter().map(Validation::to_comment).collect()
+}
+
+/// Parse the panics of failed tests out of `cargo test` output into comments pointing at the
+/// location of the failed assertion
+pub fn test_failures(output: &str) -> Vec<Comment> {
+    to_comments(&test_validations(output))
+}
+
+/// Parse the panics of failed tests out of `cargo test` output
+pub fn test_validations(output: &str) -> Vec<Validation> {
+    // rust >= 1.73: thread 'name' panicked at src/file.rs:1:2:\nmessage
+    let located_first = Regex::new(
+        "thread '([^']*)'(?: \\(\\d+\\))? panicked at ([^\\s:]+):(\\d+):(\\d+):\n([\\s\\S]*?)\n(?:note: |stack backtrace:|\n|$)",
+    )
+    .expect("Regex failed to compile.");
+    // older: thread 'name' panicked at 'message', src/file.rs:1:2
+    let message_first = Regex::new(
+        "thread '([^']*)'(?: \\(\\d+\\))? panicked at '([\\s\\S]*?)', ([^\\s:]+):(\\d+):(\\d+)",
+    )
+    .expect("Regex failed to compile.");
+
+    let failures = located_first
+        .captures_iter(output)
+        .map(|c| c.extract())
+        .map(|(_, [test, file, line_no, column, message])| (test, message, file, line_no, column))
+        .chain(
+            message_first
+                .captures_iter(output)
+                .map(|c| c.extract())
+                .map(|(_, [test, message, file, line_no, column])| {
+                    (test, message, file, line_no, column)
+                }),
+        );
+
+    failures
+        .filter_map(|(test, message, file, line_no, column)| {
+            let line_no = line_no.parse::<usize>().ok()?;
+            Some(Validation {
+                test: test.to_string(),
+                message: message.to_string(),
+                location: Some(Fragment {
+                    filepath: file.to_string(),
+                    line_range: (line_no.saturating_sub(1), line_no.saturating_sub(1)),
+                    column: column.parse::<usize>().ok().map(|c| c.saturating_sub(1)),
+                }),
+            })
+        })
+        .collect()
+}
+
+/// how serious a compiler diagnostic is, from least to most
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Help,
+    Note,
+    #[default]
+    Warning,
+    Error,
+    #[serde(rename = "error: internal compiler error")]
+    InternalCompilerError,
+}
+
+/// a line printed by cargo with `--message-format=json`
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<Diagnostic>,
+}
+
+#[derive(Deserialize)]
+struct Diagnostic {
+    message: String,
+    rendered: Option<String>,
+    level: Severity,
+    spans: Vec<DiagnosticSpan>,
+}
+
+#[derive(Deserialize)]
+struct DiagnosticSpan {
+    file_name: String,
+    line_start: usize,
+    line_end: usize,
+    column_start: usize,
+    is_primary: bool,
+}
+
+/// Turn the diagnostics in JSON output of cargo into comments at their primary spans, keeping
+/// only those at least as serious as `min_severity`
+pub fn cargo_diagnostics(output: &str, min_severity: Severity) -> Vec<Comment> {
+    output
+        .lines()
+        .filter_map(|line| serde_json::from_str::<CargoMessage>(line).ok())
+        .filter(|m| m.reason == "compiler-message")
+        .filter_map(|m| m.message)
+        .filter(|d| d.level >= min_severity)
+        .filter_map(|d| {
+            let span = d.spans.iter().find(|s| s.is_primary)?;
+            Some(Comment {
+                message: Prompter::template_lint(d.rendered.as_ref().unwrap_or(&d.message)),
+                fragments: vec![Fragment {
+                    filepath: span.file_name.clone(),
+                    line_range: (
+                        span.line_start.saturating_sub(1),
+                        span.line_end.saturating_sub(1),
+                    ),
+                    column: Some(span.column_start.saturating_sub(1)),
+                }],
+            })
+        })
+        .collect()
+}
+
+/// runs clippy, turning its lints into comments so that idiomatic issues get fixed as well
+pub struct ClippyLinter<'a> {
+    /// Command arguments to run clippy with. `--message-format=json` is added to them
+    pub clippy_args: &'a [&'a str],
+    /// lints below this severity are ignored
+    pub min_severity: Severity,
+}
+
+impl Builder for ClippyLinter<'_> {
+    fn build(&self, repository: &GitRepository) -> Result<(), Vec<Comment>> {
+        let output = repository
+            .command(self.clippy_args[0])
+            .args(&self.clippy_args[1..])
+            .arg("--message-format=json")
+            .output()
+            .unwrap_or_else(|_| panic!("failed to call clippy command {:?}", self.clippy_args));
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let lints = cargo_diagnostics(&stdout, self.min_severity);
+
+        match lints.is_empty() && output.status.success() {
+            true => Ok(()),
+            false => Err(lints),
+        }
+    }
+}
+
+/// runs any command, reporting its output as a single comment when it fails, e.g. a formatter
+/// run with `--check`
+pub struct CommandBuilder<'a> {
+    /// Command arguments to run
+    pub args: &'a [&'a str],
+    /// turns the output of a failed run into the comment for the coder, such as
+    /// [`Prompter::template_lint`]
+    pub template: fn(&str) -> String,
+}
+
+impl Builder for CommandBuilder<'_> {
+    fn build(&self, repository: &GitRepository) -> Result<(), Vec<Comment>> {
+        self.build_with_output(repository, &mut |_| {})
+    }
+
+    fn build_with_output(
+        &self,
+        repository: &GitRepository,
+        on_line: &mut dyn FnMut(&str),
+    ) -> Result<(), Vec<Comment>> {
+        let mut command = repository.command(self.args[0]);
+        command.args(&self.args[1..]);
+
+        let output = output_streaming(&mut command, on_line)
+            .unwrap_or_else(|_| panic!("failed to call command {:?}", self.args));
+
+        if output.status.success() {
+            return Ok(());
+        }
+
+        let output = format!(
+            "{}\n{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Err(vec![Comment {
+            message: (self.template)(output.trim()),
+            fragments: Vec::new(),
+        }])
+    }
+}
+
+/// a named step of a [`Pipeline`]
+pub struct Stage<'a> {
+    /// what the stage is called in the comments it reports, e.g. `clippy`
+    pub name: &'a str,
+    pub builder: Box<dyn Builder + 'a>,
+}
+
+/// runs its stages in order like a CI pipeline, stopping at the first one that fails
+pub struct Pipeline<'a> {
+    pub stages: Vec<Stage<'a>>,
+}
+
+impl Builder for Pipeline<'_> {
+    fn build(&self, repository: &GitRepository) -> Result<(), Vec<Comment>> {
+        self.build_with_output(repository, &mut |_| {})
+    }
+
+    fn build_with_output(
+        &self,
+        repository: &GitRepository,
+        on_line: &mut dyn FnMut(&str),
+    ) -> Result<(), Vec<Comment>> {
+        for stage in &self.stages {
+            // later stages usually assume the earlier ones passed, e.g. tests need a build
+            stage
+                .builder
+                .build_with_output(repository, on_line)
+                .map_err(|comments| {
+                    comments
+                        .into_iter()
+                        .map(|comment| Comment {
+                            message: Prompter::template_stage(stage.name, &comment.message),
+                            fragments: comment.fragments,
+                        })
+                        .collect::<Vec<_>>()
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_with_a_bad_line_is_skipped() {
+        let stderr = "error: first\n --> src/a.rs:one:1\n  |\n1 | x\n  |\n\n\
+            error: second\n --> src/a.rs:99999999999999999999999:1\n  |\n1 | x\n  |\n\n\
+            error[E0308]: third\n --> src/b.rs:3:5\n  |\n3 | x\n  |\n\n";
+
+        let comments = rustc_errors(stderr.as_bytes());
+
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].fragments[0].filepath, "src/b.rs");
+        assert_eq!(comments[0].fragments[0].line_range, (2, 2));
+        assert_eq!(comments[0].fragments[0].column, Some(4));
+    }
+
+    #[test]
+    fn test_failure_with_a_bad_line_is_skipped() {
+        let output = "thread 'tests::a' panicked at src/a.rs:one:2:\nboom\n\n\
+            thread 'tests::b' panicked at 'boom', src/b.rs:18446744073709551616:2\n\
+            thread 'tests::c' panicked at src/c.rs:7:9:\nboom\n\n";
+
+        let validations = test_validations(output);
+
+        assert_eq!(validations.len(), 1);
+        assert_eq!(validations[0].test, "tests::c");
+        let location = validations[0].location.as_ref().unwrap();
+        assert_eq!(location.line_range, (6, 6));
+    }
+
+    #[test]
+    fn invalid_utf8_in_an_error_is_kept() {
+        let comments = rustc_errors(b"error: x\n --> src/\xff.rs:1:1\n");
+
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].fragments[0].filepath, "src/\u{fffd}.rs");
+        assert_eq!(comments[0].fragments[0].line_range, (0, 0));
+    }
+
+    #[test]
+    fn streamed_lines_arrive_in_order() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "for i in 1 2 3; do echo out $i; done; echo err >&2"]);
+        let mut lines = Vec::new();
+
+        let output =
+            output_streaming(&mut command, &mut |line| lines.push(line.to_string())).unwrap();
+
+        let stdout = lines
+            .iter()
+            .filter(|line| line.starts_with("out"))
+            .collect::<Vec<_>>();
+        assert_eq!(stdout, ["out 1", "out 2", "out 3"]);
+        assert!(lines.contains(&"err".to_string()));
+        assert_eq!(output.stdout, b"out 1\nout 2\nout 3\n");
+        assert_eq!(output.stderr, b"err\n");
+        assert!(output.status.success());
+    }
+
+    #[test]
+    fn pipeline_stops_at_the_first_failing_stage() {
+        let ran = std::cell::RefCell::new(Vec::new());
+        let log = &ran;
+        let stage = |name: &'static str, passes: bool| Stage {
+            name,
+            builder: Box::new(move |_: &GitRepository| {
+                log.borrow_mut().push(name);
+                match passes {
+                    true => Ok(()),
+                    false => Err(vec![Comment {
+                        message: format!("{name} failed"),
+                        fragments: Vec::new(),
+                    }]),
+                }
+            }),
+        };
+        let pipeline = Pipeline {
+            stages: vec![
+                stage("fmt", true),
+                stage("clippy", false),
+                stage("test", true),
+            ],
+        };
+
+        let comments = pipeline.build(&GitRepository::default()).unwrap_err();
+
+        assert_eq!(*ran.borrow(), ["fmt", "clippy"]);
+        assert_eq!(comments.len(), 1);
+        assert_eq!(
+            comments[0].message,
+            Prompter::template_stage("clippy", "clippy failed")
+        );
+    }
+
+    #[test]
+    fn pipeline_passes_when_every_stage_does() {
+        let pipeline = Pipeline {
+            stages: vec![Stage {
+                name: "build",
+                builder: Box::new(|_: &GitRepository| Ok(())),
+            }],
+        };
+
+        assert!(pipeline.build(&GitRepository::default()).is_ok());
+    }
+
+    const TESTED: &str = "fn add(a: u8, b: u8) -> u8 {\n    a - b\n}\n\n#[cfg(test)]\nmod tests {\n    use super::*;\n\n    #[test]\n    fn adds() {\n        let sum = add(2, 1);\n        assert_eq!(sum, 3);\n    }\n}\n";
+
+    #[test]
+    fn failed_test_points_at_its_function() {
+        let repository = crate::repository::tests::temp_repo(
+            "test-source",
+            &[
+                ("Cargo.toml", "[package]\nname = \"x\"\n"),
+                ("src/lib.rs", TESTED),
+            ],
+        );
+        let output = "thread 'tests::adds' panicked at src/lib.rs:12:9:\nassertion `left == right` failed\n\n";
+
+        let comment = test_validations(output)[0].to_comment_in(&repository);
+
+        assert!(comment
+            .message
+            .contains("defined at lines 9-12 of src/lib.rs"));
+        let ranges = comment
+            .fragments
+            .iter()
+            .map(|fragment| fragment.line_range)
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, [(11, 11), (9, 12)]);
+    }
+
+    #[test]
+    fn failed_test_source_is_in_the_prompt() {
+        let repository = crate::repository::tests::temp_repo(
+            "test-source-prompt",
+            &[
+                ("Cargo.toml", "[package]\nname = \"x\"\n"),
+                ("src/lib.rs", TESTED),
+            ],
+        );
+        let output = "thread 'tests::adds' panicked at src/lib.rs:12:9:\nassertion `left == right` failed\n\n";
+        let comment = test_validations(output)[0].to_comment_in(&repository);
+        let llm = crate::coder::tests::Scripted::default();
+        let coder = crate::coder::tests::coder(repository, llm);
+
+        let (_, prompt) = coder.request(&comment).unwrap();
+
+        assert!(prompt.contains("let sum = add(2, 1);"));
+        assert!(prompt.contains("a - b"));
+    }
+}
@@ -0,0 +1,1332 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::audit::{AuditEvent, AuditLog};
+use crate::llm::{LLMError, LLM};
+use crate::parser::{TemplateParser, TransformationParser};
+use crate::prompt::Prompter;
+use crate::repository::{
+    Comment, Fragment, GitRepository, ScoredTransformation, Transformation, TransformationKind,
+};
+use crate::{Diff, RakuneError, Result};
+
+/// performs the actions to edit the code in the repository
+pub struct Coder<M: LLM, P: TransformationParser = TemplateParser> {
+    /// maximum number of transformations applied for a single comment
+    pub transformation_count: usize,
+    /// number of times the model is asked for a reply that parses before giving up
+    pub max_parse_attempts: usize,
+    /// upper bound on the size of an assembled prompt, in characters. context blocks are dropped
+    /// from least to most relevant until the prompt fits
+    pub max_context_chars: Option<usize>,
+    /// show the whole file of a fragment as context when it is no larger than this, instead of
+    /// only the lines around the fragment
+    pub whole_file_threshold: Option<FileSize>,
+    /// show a summary of the public API and key functions of files over `whole_file_threshold`
+    /// next to the lines around their fragments, remembering each until its file changes
+    pub file_summaries: Option<FileSummaryCache>,
+    /// largest diff of a file, in characters, sent as is when summarizing changes for a commit.
+    /// larger diffs are cut down to their line counts and biggest hunks, see [`shrink_diff`]
+    pub max_diff_chars: Option<usize>,
+    /// number of [`Prompter::EXAMPLES`] of edits shown to the model. capable models do fine
+    /// without any, while small ones follow the template more closely with all of them
+    pub prompt_examples: usize,
+    /// show the changes of this many recent commits as an example of the repository's code
+    /// style, see [`GitRepository::recent_diff`]
+    pub example_commits: Option<usize>,
+    /// the kinds of transformation the model may make, e.g. only [`TransformationKind::EDITS`]
+    /// to keep it from creating or deleting files. the prompt only describes these, and any
+    /// other kind is rejected before anything is applied
+    pub allowed_transformations: HashSet<TransformationKind>,
+    pub repository: GitRepository,
+    /// the model for every task without one of its own in `models`
+    pub llm: M,
+    /// models used instead of `llm` for particular tasks, e.g. a small fast one for commits
+    pub models: HashMap<Task, M>,
+    /// reads transformations out of the model's answers
+    pub parser: P,
+    /// strip the prose around the fenced template blocks of an answer before parsing it, see
+    /// [`sanitize_response`]. can be turned off for models that already answer cleanly
+    pub sanitize_responses: bool,
+    /// records prompts, responses, and transformations for replaying a run
+    pub audit: Option<AuditLog>,
+    /// notified as the coder makes progress, e.g. to show live status in a frontend
+    pub on_event: Option<EventHandler>,
+}
+
+/// progress reported to an [`EventHandler`]
+#[derive(Debug)]
+pub enum CoderEvent<'a> {
+    PromptStarted,
+    TransformationApplied(&'a Transformation),
+    BuildStarted,
+    /// a line printed by the build while it runs
+    BuildOutput(&'a str),
+    /// the build failed with this many comments to address
+    BuildFailed(usize),
+    /// a commit was made at this revision
+    Committed(&'a str),
+}
+
+type EventCallback = Box<dyn FnMut(CoderEvent)>;
+
+/// a callback receiving every [`CoderEvent`]
+pub struct EventHandler(RefCell<EventCallback>);
+
+impl EventHandler {
+    pub fn new(handler: impl FnMut(CoderEvent) + 'static) -> Self {
+        Self(RefCell::new(Box::new(handler)))
+    }
+}
+
+impl<T: LLM, P: TransformationParser> Coder<T, P> {
+    /// longest diff shown as an example of the repository's code style
+    const MAX_EXAMPLE_LINES: usize = 80;
+
+    /// Notify the event handler, if there is one
+    pub fn emit(&self, event: CoderEvent) {
+        if let Some(EventHandler(handler)) = &self.on_event {
+            (handler.borrow_mut())(event);
+        }
+    }
+
+    /// The model to use for `task`
+    pub fn llm_for(&self, task: Task) -> &T {
+        self.models.get(&task).unwrap_or(&self.llm)
+    }
+
+    fn prompt(&self, task: Task, prompt: &str) -> Result<String> {
+        self.emit(CoderEvent::PromptStarted);
+        self.audit(&AuditEvent::Prompt {
+            system: None,
+            prompt,
+        })?;
+        let response = retry_empty(|| self.llm_for(task).prompt(prompt))?;
+        self.audit(&AuditEvent::Response {
+            response: &response,
+        })?;
+        Ok(response)
+    }
+
+    fn prompt_with_system(&self, task: Task, system: &str, prompt: &str) -> Result<String> {
+        self.emit(CoderEvent::PromptStarted);
+        self.audit(&AuditEvent::Prompt {
+            system: Some(system),
+            prompt,
+        })?;
+        let response = retry_empty(|| self.llm_for(task).prompt_with_system(system, prompt))?;
+        self.audit(&AuditEvent::Response {
+            response: &response,
+        })?;
+        Ok(response)
+    }
+
+    /// Record an event in the audit log, if there is one
+    pub fn audit(&self, event: &AuditEvent) -> Result<()> {
+        match &self.audit {
+            Some(audit) => audit.record(event),
+            None => Ok(()),
+        }
+    }
+
+    // prompt -> embedding -> context(s) (code blocks fetched by the embedding)
+    //
+    // when you use a particular context "block", if it fails or succeeds the
+    // build/validation, then it will decrease or increase its score related to the prompt embedding
+
+    pub fn generate_transformations(&mut self, comment: &Comment) -> Result<Vec<Transformation>> {
+        Ok(self
+            .generate_scored_transformations(comment)?
+            .into_iter()
+            .map(|scored| scored.transformation)
+            .collect())
+    }
+
+    /// Generate and apply the transformations for `comment` like
+    /// [`Coder::generate_transformations`], returning them with their scores and the location
+    /// they were made for
+    pub fn generate_scored_transformations(
+        &mut self,
+        comment: &Comment,
+    ) -> Result<Vec<ScoredTransformation>> {
+        let (system, prompt) = self.request(comment)?;
+
+        // TODO: jump from answer to transformations
+        // use the answer to construct a sequence of transformations
+
+        let transformations = parse_with_retries(
+            &self.parser,
+            self.max_parse_attempts,
+            self.sanitize_responses,
+            &self.allowed_transformations,
+            &prompt,
+            |request| self.prompt_with_system(Task::Edit, &system, request),
+        )?;
+
+        self.apply(comment, transformations)
+    }
+
+    /// The files that addressing `comment` is expected to touch, for confirming the changes with
+    /// the user before any are made. Only looks at the comment, so the model is not called
+    pub fn planned_files(&self, comment: &Comment) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for fragment in &comment.fragments {
+            let file = PathBuf::from(&fragment.filepath);
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+
+        files
+    }
+
+    /// The system prompt and the prompt asking for the edits of `comment`, along with the
+    /// context around the fragments it points at
+    pub(crate) fn request(&self, comment: &Comment) -> Result<(String, String)> {
+        let language = self.repository.detect_language(comment.fragments.first());
+        let mut system = Prompter::system_code(
+            &language,
+            self.repository.line_base,
+            self.prompt_examples,
+            &self.allowed_transformations,
+        );
+        if let Some(example) = self.example_diff()? {
+            system += &Prompter::template_example(&example);
+        }
+        let mut prompt = comment.message.clone();
+
+        let fragments = Fragment::merge_overlapping(comment.fragments.clone());
+        if !fragments.is_empty() {
+            for fragment in &comment.fragments {
+                if let Some(column) = fragment.column {
+                    prompt += &format!(
+                        "\nThe problem is at line {}, column {} of {}.",
+                        fragment.line_range.0 + self.repository.line_base,
+                        column,
+                        fragment.filepath
+                    );
+                }
+            }
+
+            let files = self.planned_files(comment);
+            if files.len() > 1 {
+                prompt += &format!(
+                    "\nThis takes edits in each of {}, so give one template per edit.",
+                    files
+                        .iter()
+                        .map(|file| file.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+
+            prompt += "\n### Here is the current context:\n";
+
+            // ordered from most to least relevant, leading with the lines of each fragment and
+            // then the functions around them
+            let mut context = Vec::new();
+            let mut enclosing = Vec::new();
+            let mut related = Vec::new();
+            let mut summarized = Vec::new();
+            for fragment in &fragments {
+                if summarized.contains(&&fragment.filepath) {
+                    continue;
+                }
+                summarized.push(&fragment.filepath);
+                if let Some(summary) = self.file_summary(&fragment.filepath)? {
+                    related.push(format!("Summary of {}:\n{}", fragment.filepath, summary));
+                }
+            }
+            for fragment in &fragments {
+                let shown = self.shown_fragment(fragment)?;
+                let mut spatial_context = self.repository.spatial_context(&shown)?.into_iter();
+                context.extend(spatial_context.next());
+                related.extend(spatial_context);
+                enclosing.extend(self.repository.enclosing_context(&shown)?);
+            }
+            for fragment in &fragments {
+                related.extend(self.repository.temporal_context(comment, fragment)?);
+            }
+
+            let required = context.len();
+            context.extend(enclosing);
+            context.extend(related);
+
+            for context in self.fit_context(system.len() + prompt.len(), context, required)? {
+                prompt += "\n";
+                prompt += &context;
+            }
+        }
+
+        Ok((system, prompt))
+    }
+
+    /// The recent changes shown as an example, left out when they are too long to be worth the
+    /// space in the prompt
+    fn example_diff(&self) -> Result<Option<String>> {
+        let Some(commits) = self.example_commits else {
+            return Ok(None);
+        };
+
+        Ok(self
+            .repository
+            .recent_diff(commits)?
+            .filter(|diff| diff.lines().count() <= Self::MAX_EXAMPLE_LINES))
+    }
+
+    /// Apply the transformations parsed for `comment` to the repository, keeping at most
+    /// `transformation_count`. The highest scored are kept, in the order they were given.
+    /// Nothing is applied when any of them isn't in `allowed_transformations`
+    pub(crate) fn apply(
+        &mut self,
+        comment: &Comment,
+        transformations: Vec<ScoredTransformation>,
+    ) -> Result<Vec<ScoredTransformation>> {
+        let source = comment_source(comment, self.repository.line_base);
+        let mut scored = transformations
+            .into_iter()
+            .map(|scored| {
+                let kind = scored.transformation.kind();
+                if !self.allowed_transformations.contains(&kind) {
+                    return Err(RakuneError::DisallowedTransformation {
+                        kind,
+                        filepath: scored.transformation.filepath().map(str::to_string),
+                    }
+                    .into());
+                }
+                let transformation = scored
+                    .transformation
+                    .into_zero_based(self.repository.line_base)?;
+                Ok(ScoredTransformation {
+                    transformation: transformation.or_noop(&self.repository),
+                    source: scored.source.or_else(|| source.clone()),
+                    ..scored
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.audit(&AuditEvent::Transformations {
+            transformations: &scored
+                .iter()
+                .map(|scored| scored.transformation.clone())
+                .collect::<Vec<_>>(),
+        })?;
+
+        if scored.len() > self.transformation_count {
+            // unscored edits rank last, so without any scores the first ones are kept
+            let mut ranked = (0..scored.len()).collect::<Vec<_>>();
+            ranked.sort_by(|a, b| {
+                let score = |i: &usize| scored[*i].score.unwrap_or(f32::MIN);
+                score(b).total_cmp(&score(a))
+            });
+            let kept = &ranked[..self.transformation_count];
+
+            let (kept, _dropped): (Vec<_>, Vec<_>) = scored
+                .into_iter()
+                .enumerate()
+                .partition(|(i, _)| kept.contains(i));
+            scored = kept.into_iter().map(|(_, scored)| scored).collect();
+
+            #[cfg(debug_assertions)]
+            eprintln!(
+                "*************** Dropped {} transformations over the limit of {} **************\n{:#?}\n",
+                _dropped.len(),
+                self.transformation_count,
+                _dropped
+            );
+        }
+
+        let transformations = scored
+            .iter()
+            .map(|scored| scored.transformation.clone())
+            .collect::<Vec<_>>();
+        self.repository.transform_all(&transformations)?;
+        for transformation in &transformations {
+            self.emit(CoderEvent::TransformationApplied(transformation));
+        }
+
+        Ok(scored)
+    }
+
+    /// The fragment to show as context for `fragment`, which is its whole file when the file is
+    /// small enough to fit under `whole_file_threshold`
+    fn shown_fragment(&self, fragment: &Fragment) -> Result<Fragment> {
+        let Some(threshold) = self.whole_file_threshold else {
+            return Ok(fragment.clone());
+        };
+
+        let content = self.repository.read_file(&fragment.filepath)?;
+        let line_count = content.lines().count();
+
+        Ok(match threshold.fits(&content) {
+            true => Fragment {
+                filepath: fragment.filepath.clone(),
+                line_range: (0, line_count.saturating_sub(1)),
+                column: None,
+            },
+            false => fragment.clone(),
+        })
+    }
+
+    /// A summary of the file `filepath` when it is too large to be shown whole, written by the
+    /// model the first time the file is seen with its current content
+    fn file_summary(&self, filepath: &str) -> Result<Option<String>> {
+        let (Some(cache), Some(threshold)) = (&self.file_summaries, self.whole_file_threshold)
+        else {
+            return Ok(None);
+        };
+
+        let content = self.repository.read_file(filepath)?;
+        if threshold.fits(&content) {
+            return Ok(None);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        if let Some(summary) = cache.0.borrow().get(&hash) {
+            return Ok(Some(summary.clone()));
+        }
+
+        let prompt = format!(
+            "summarize the public API and the key functions of {} in a few short lines, for \
+            someone about to edit part of it:\n\n{}",
+            filepath, content
+        );
+        let summary = self.prompt(Task::Summarize, &prompt)?.trim().to_string();
+        cache.0.borrow_mut().insert(hash, summary.clone());
+
+        Ok(Some(summary))
+    }
+
+    /// Trim the least relevant context blocks until the prompt fits within `max_context_chars`.
+    /// The first `required` blocks are always kept since they hold the lines being edited, so
+    /// a budget that can't even fit them is an error rather than a prompt over budget
+    fn fit_context(
+        &self,
+        prompt_len: usize,
+        mut context: Vec<String>,
+        required: usize,
+    ) -> Result<Vec<String>> {
+        let Some(budget) = self.max_context_chars else {
+            return Ok(context);
+        };
+
+        let len =
+            |context: &[String]| prompt_len + context.iter().map(|c| c.len() + 1).sum::<usize>();
+
+        let needed = len(&context[..required.min(context.len())]);
+        if needed > budget {
+            let error_message = format!(
+                "The prompt takes {} characters with only the lines to edit, over the {} of `max_context_chars`.",
+                needed, budget
+            );
+            return Err(error_message.into());
+        }
+
+        while len(&context) > budget && context.len() > required {
+            context.pop();
+        }
+
+        Ok(context)
+    }
+
+    /// Write a commit message for the changes in the working tree, summarizing each changed file
+    /// on its own so that large diffs don't drown out the message
+    pub fn generate_commit(&self, style: CommitStyle) -> Result<String> {
+        let files = self.repository.diff_files(None)?;
+        let summaries = self.summarize_files(&files)?;
+
+        let types = match style {
+            CommitStyle::Plain => {
+                let prompt = &format!(
+                    "summarize the following changes as a commit message in less than 20 words:\n\n{}",
+                    summaries
+                );
+                return self.prompt(Task::Commit, prompt);
+            }
+            CommitStyle::Conventional { types } => types,
+        };
+
+        let prompt = Prompter::template_conventional_commit(types, &summaries);
+        let mut message = self.prompt(Task::Commit, &prompt)?;
+        // models get the format right most of the time, so a single retry is enough
+        if let Err(error) = check_conventional_commit(&message, types) {
+            let retry = format!(
+                "{}\n\n{}",
+                prompt,
+                Prompter::template_conventional_retry(&message, &error)
+            );
+            message = self.prompt(Task::Commit, &retry)?;
+        }
+
+        Ok(match check_conventional_commit(&message, types) {
+            Ok(()) => message.trim().to_string(),
+            // the summary is still worth keeping, under the type guessed from the changed files
+            Err(_) => {
+                let inferred = commit_type(&files);
+                let commit_type = match types.contains(&inferred) || types.is_empty() {
+                    true => inferred,
+                    false => types[0],
+                };
+                let subject = message.trim().lines().next().unwrap_or_default();
+                format!("{commit_type}: {subject}")
+            }
+        })
+    }
+
+    /// Describe everything committed on the current branch since it forked from `base`, in the
+    /// manner of a pull request description
+    pub fn generate_branch_summary(&self, base: &str) -> Result<String> {
+        let files = self.repository.diff_files_against(base)?;
+        let summaries = self.summarize_files(&files)?;
+
+        let prompt = &format!(
+            "write a pull request description for the following changes, starting with a one line \
+            title followed by a short paragraph explaining what changed and why:\n\n{}",
+            summaries
+        );
+        self.prompt(Task::Commit, prompt)
+    }
+
+    /// Explain in plain English what `transformations` would change and why, file by file, so
+    /// that they can be approved before being applied. See [`outline`] for a description
+    /// that doesn't need the model
+    pub fn explain(&self, transformations: &[Transformation]) -> Result<String> {
+        let diffs = transformations
+            .iter()
+            .map(|transformation| transformation.to_diff(&self.repository))
+            .collect::<Result<Vec<_>>>()?
+            .join("\n");
+
+        let prompt = format!(
+            "explain the following proposed changes in plain English, with a short paragraph per \
+            file:\n\n{}\n\n{}",
+            outline(transformations),
+            diffs
+        );
+        self.prompt(Task::Explain, &prompt)
+    }
+
+    /// Summarize the diff of each file on its own, one line per file
+    fn summarize_files(&self, files: &[(String, Diff)]) -> Result<String> {
+        Ok(files
+            .iter()
+            .map(|(file, diff)| {
+                let diff = match self.max_diff_chars {
+                    Some(max) if diff.len() > max => shrink_diff(diff, max),
+                    _ => diff.clone(),
+                };
+                let prompt = format!(
+                    "summarize the following changes to {} in one sentence:\n\n{}",
+                    file, diff
+                );
+                Ok(format!(
+                    "{}: {}",
+                    file,
+                    self.prompt(Task::Commit, &prompt)?.trim()
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .join("\n"))
+    }
+}
+
+/// List what `transformations` do keyed by the file they change, without asking the model
+pub fn outline(transformations: &[Transformation]) -> String {
+    let mut files: Vec<(Option<&str>, Vec<String>)> = Vec::new();
+    for transformation in transformations {
+        let file = transformation.filepath();
+        let summary = transformation.summary();
+        match files.iter_mut().find(|(f, _)| *f == file) {
+            Some((_, summaries)) => summaries.push(summary),
+            None => files.push((file, vec![summary])),
+        }
+    }
+
+    files
+        .into_iter()
+        .map(|(file, summaries)| {
+            format!(
+                "{}\n{}",
+                file.unwrap_or("repository"),
+                summaries
+                    .iter()
+                    .map(|summary| format!("- {summary}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Ask once more when the model replies with nothing, which tends to be a passing hiccup
+pub(crate) fn retry_empty(ask: impl Fn() -> Result<String>) -> Result<String> {
+    match ask() {
+        Err(e) if e.downcast_ref::<LLMError>() == Some(&LLMError::EmptyResponse) => ask(),
+        response => response,
+    }
+}
+
+/// Where the edits for `comment` were asked for: the first line it points at, numbered from
+/// `line_base`, or else the first line of its message
+fn comment_source(comment: &Comment, line_base: usize) -> Option<String> {
+    match comment.fragments.first() {
+        Some(fragment) => Some(format!(
+            "{}:{}",
+            fragment.filepath,
+            fragment.line_range.0 + line_base
+        )),
+        None => comment
+            .message
+            .lines()
+            .next()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.trim().to_string()),
+    }
+}
+
+/// Prompt the model until its answer parses into transformations, showing it what went wrong
+/// with each reply that didn't. Gives up after `max_parse_attempts` replies.
+pub(crate) fn parse_with_retries<P: TransformationParser>(
+    parser: &P,
+    max_parse_attempts: usize,
+    sanitize: bool,
+    allowed: &HashSet<TransformationKind>,
+    prompt: &str,
+    mut ask: impl FnMut(&str) -> Result<String>,
+) -> Result<Vec<ScoredTransformation>> {
+    // distinct replies only, as models often repeat the same mistake verbatim
+    let mut answers: Vec<String> = Vec::new();
+    let mut request = prompt.to_string();
+    let mut attempt = 0;
+    loop {
+        if attempt >= max_parse_attempts {
+            let error_message = format!(
+                "No transformations could be parsed after {} attempts. The replies were:\n\n{}",
+                attempt,
+                answers.join("\n\n---\n\n"),
+            );
+            return Err(error_message.into());
+        }
+
+        let answer = ask(&request)?;
+        let parsed = match sanitize {
+            true => parser.parse_scored(&sanitize_response(&answer)),
+            false => parser.parse_scored(&answer),
+        };
+        let error = match parsed {
+            Ok(parsed) if !parsed.is_empty() => return Ok(parsed),
+            Ok(_) => "no transformations were found".to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        #[cfg(debug_assertions)]
+        eprintln!(
+            "*************** Parse Error (attempt {} of {}) **************\n{error}\n\n{answer}\n",
+            attempt + 1,
+            max_parse_attempts
+        );
+
+        // show the model what went wrong rather than repeating a prompt it already failed
+        request = format!(
+            "{}\n\n{}",
+            prompt,
+            Prompter::template_retry(&answer, &error, allowed)
+        );
+        if !answers.contains(&answer) {
+            answers.push(answer);
+        }
+        attempt += 1;
+    }
+}
+
+/// Keep only the fenced blocks of a response that hold an `UpdateFragment` or `InsertFragment`
+/// template, dropping the explanations models like to put around them. Responses without such
+/// a block, e.g. those using another format, are returned as they are.
+pub fn sanitize_response(response: &str) -> String {
+    let fenced = Regex::new("```[^\n]*\n[\\s\\S]*?```").expect("Regex failed to compile.");
+
+    let blocks = fenced
+        .find_iter(response)
+        .map(|m| m.as_str())
+        .filter(|block| block.contains("UpdateFragment:") || block.contains("InsertFragment:"))
+        .collect::<Vec<_>>();
+
+    match blocks.is_empty() {
+        true => response.to_string(),
+        false => blocks.join("\n"),
+    }
+}
+
+/// a size limit for files, measured in lines or bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileSize {
+    Lines(usize),
+    Bytes(usize),
+}
+
+impl FileSize {
+    /// Whether `content` is within the limit
+    pub fn fits(&self, content: &str) -> bool {
+        match *self {
+            FileSize::Lines(lines) => content.lines().count() <= lines,
+            FileSize::Bytes(bytes) => content.len() <= bytes,
+        }
+    }
+}
+
+/// summaries of files written by the model for [`Coder::file_summaries`], keyed by a hash of the
+/// content they summarize so that a file is summarized again once it changes
+#[derive(Default)]
+pub struct FileSummaryCache(RefCell<HashMap<u64, String>>);
+
+/// Cut a diff down to about `max` characters: how many lines it adds and removes, followed by
+/// as many of its largest hunks as fit, in the order they appear
+pub fn shrink_diff(diff: &str, max: usize) -> String {
+    let mut header = Vec::new();
+    let mut hunks: Vec<Vec<&str>> = Vec::new();
+    for line in diff.lines() {
+        match (line.starts_with("@@"), hunks.last_mut()) {
+            (true, _) => hunks.push(vec![line]),
+            (false, Some(hunk)) => hunk.push(line),
+            (false, None) => header.push(line),
+        }
+    }
+
+    let count = |prefix: char| {
+        hunks
+            .iter()
+            .flatten()
+            .filter(|line| line.starts_with(prefix))
+            .count()
+    };
+    let mut shrunk = format!(
+        "{}\n{} lines added and {} removed in {} hunks, the largest of which are:\n",
+        header.join("\n"),
+        count('+'),
+        count('-'),
+        hunks.len()
+    );
+
+    let mut largest = hunks
+        .iter()
+        .enumerate()
+        .map(|(i, hunk)| (i, hunk.join("\n")))
+        .collect::<Vec<_>>();
+    largest.sort_by_key(|(_, hunk)| std::cmp::Reverse(hunk.len()));
+
+    let mut budget = max.saturating_sub(shrunk.len());
+    let mut shown = largest
+        .into_iter()
+        .filter(|(_, hunk)| match hunk.len() < budget {
+            true => {
+                budget -= hunk.len() + 1;
+                true
+            }
+            false => false,
+        })
+        .collect::<Vec<_>>();
+    shown.sort_by_key(|(i, _)| *i);
+
+    for (_, hunk) in shown {
+        shrunk += &hunk;
+        shrunk += "\n";
+    }
+
+    shrunk
+}
+
+/// the kinds of work a [`Coder`] asks its models to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Task {
+    /// edits that address comments
+    Edit,
+    /// commit messages, pull request descriptions, and the summaries they are written from
+    Commit,
+    /// explanations of proposed changes
+    Explain,
+    /// summaries of files too large to be shown whole as context
+    Summarize,
+}
+
+/// how generated commit messages are formatted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommitStyle {
+    /// the summary as the model wrote it
+    #[default]
+    Plain,
+    /// a message following Conventional Commits, with one of `types` such as `feat` or `fix`
+    Conventional { types: &'static [&'static str] },
+}
+
+/// the commit types of Conventional Commits, from which a team may pick the ones it uses
+pub const CONVENTIONAL_TYPES: &[&str] = &[
+    "feat", "fix", "docs", "style", "refactor", "perf", "test", "build", "ci", "chore", "revert",
+];
+
+/// Check that `message` follows Conventional Commits with one of `types`: a
+/// `type(scope): subject` line, optionally followed by a blank line and a body
+pub fn check_conventional_commit(message: &str, types: &[&str]) -> std::result::Result<(), String> {
+    let header =
+        Regex::new("^([a-z]+)(?:\\([^()\\s]+\\))?!?: \\S.*$").expect("Regex failed to compile.");
+
+    let mut lines = message.trim().lines();
+    let first = lines.next().unwrap_or_default();
+    let Some(captures) = header.captures(first) else {
+        return Err("the first line should look like `type(scope): subject`".to_string());
+    };
+
+    let commit_type = &captures[1];
+    if !types.is_empty() && !types.contains(&commit_type) {
+        return Err(format!(
+            "`{commit_type}` is not one of the allowed types: {}",
+            types.join(", ")
+        ));
+    }
+
+    if lines.next().is_some_and(|line| !line.trim().is_empty()) {
+        return Err("the body should be separated from the subject by a blank line".to_string());
+    }
+
+    Ok(())
+}
+
+/// Infer the conventional commit type of a set of changed files from their paths and diffs
+fn commit_type(files: &[(String, Diff)]) -> &'static str {
+    let is_docs = |file: &str| file.ends_with(".md") || file.starts_with("docs/");
+    // by whole path components, so that e.g. `src/attestation.rs` isn't taken for a test
+    let is_test = |file: &str| {
+        let path = Path::new(file);
+        let in_test_dir = path
+            .parent()
+            .into_iter()
+            .flat_map(Path::components)
+            .any(|c| matches!(c.as_os_str().to_str(), Some("tests" | "benches")));
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("");
+        in_test_dir
+            || matches!(stem, "test" | "tests")
+            || stem.ends_with("_test")
+            || stem.ends_with("_tests")
+    };
+    let is_new = |diff: &str| diff.lines().any(|l| l.starts_with("new file mode"));
+
+    if files.is_empty() {
+        "chore"
+    } else if files.iter().all(|(file, _)| is_docs(file)) {
+        "docs"
+    } else if files.iter().all(|(file, _)| is_test(file)) {
+        "test"
+    } else if files.iter().any(|(_, diff)| is_new(diff)) {
+        "feat"
+    } else {
+        "fix"
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::repository::tests::{temp_repo, write_file};
+
+    /// a model answering with `answers` in turn, keeping every prompt it was sent
+    #[derive(Default)]
+    pub(crate) struct Scripted {
+        answers: Mutex<Vec<String>>,
+        pub(crate) prompts: Mutex<Vec<String>>,
+    }
+
+    impl Scripted {
+        pub(crate) fn new(answers: &[&str]) -> Self {
+            Self {
+                answers: Mutex::new(answers.iter().map(|a| a.to_string()).collect()),
+                prompts: Mutex::default(),
+            }
+        }
+    }
+
+    impl LLM for Scripted {
+        fn prompt(&self, prompt: &str) -> Result<String> {
+            self.prompts.lock().unwrap().push(prompt.to_string());
+            let mut answers = self.answers.lock().unwrap();
+            match answers.is_empty() {
+                true => Err("No answers are left.".into()),
+                false => Ok(answers.remove(0)),
+            }
+        }
+    }
+
+    pub(crate) fn coder<M: LLM>(repository: GitRepository, llm: M) -> Coder<M> {
+        coder_with(repository, llm, TemplateParser)
+    }
+
+    fn coder_with<M: LLM, P: TransformationParser>(
+        repository: GitRepository,
+        llm: M,
+        parser: P,
+    ) -> Coder<M, P> {
+        Coder {
+            transformation_count: 2,
+            max_parse_attempts: 1,
+            max_context_chars: None,
+            whole_file_threshold: None,
+            file_summaries: None,
+            max_diff_chars: None,
+            prompt_examples: 0,
+            example_commits: None,
+            allowed_transformations: TransformationKind::ALL.iter().copied().collect(),
+            repository,
+            llm,
+            models: HashMap::new(),
+            parser,
+            sanitize_responses: true,
+            audit: None,
+            on_event: None,
+        }
+    }
+
+    pub(crate) fn comment(filepath: &str, line_range: (usize, usize)) -> Comment {
+        Comment {
+            message: "fix this".to_string(),
+            fragments: vec![Fragment {
+                filepath: filepath.to_string(),
+                line_range,
+                column: None,
+            }],
+        }
+    }
+
+    /// A rust file with a function `long` of `lines` statements starting on line 1
+    fn long_function(lines: usize) -> String {
+        let body = (0..lines)
+            .map(|i| format!("    let x{i} = {i};\n"))
+            .collect::<String>();
+        format!("// long\nfn long() {{\n{body}}}\n")
+    }
+
+    #[test]
+    fn oversized_context_is_trimmed_to_budget() {
+        let content = long_function(200);
+        let repository = temp_repo("context-trim", &[("src/lib.rs", &content)]);
+        let mut coder = coder(repository, Scripted::default());
+
+        let (system, prompt) = coder.request(&comment("src/lib.rs", (100, 100))).unwrap();
+        let full = system.len() + prompt.len();
+        assert!(prompt.contains("The function around these lines is:"));
+
+        coder.max_context_chars = Some(full - 100);
+        let (system, prompt) = coder.request(&comment("src/lib.rs", (100, 100))).unwrap();
+        assert!(system.len() + prompt.len() <= full - 100);
+        assert!(prompt.contains("let x98 = 98;"));
+        assert!(!prompt.contains("The function around these lines is:"));
+    }
+
+    #[test]
+    fn enclosing_function_follows_the_edited_lines() {
+        let content = format!("{}fn caller() {{\n    long();\n}}\n", long_function(40));
+        let repository = temp_repo("context-enclosing", &[("src/lib.rs", &content)]);
+        let coder = coder(repository, Scripted::default());
+
+        let (_, prompt) = coder.request(&comment("src/lib.rs", (20, 20))).unwrap();
+
+        let lines = prompt.find("The existing lines of code are:").unwrap();
+        let enclosing = prompt.find("The function around these lines is:").unwrap();
+        let caller = prompt.find("    long();").unwrap();
+        assert!(lines < enclosing && enclosing < caller);
+    }
+
+    #[test]
+    fn budget_below_the_edited_lines_is_an_error() {
+        let repository = temp_repo("context-budget", &[("src/lib.rs", &long_function(20))]);
+        let mut coder = coder(repository, Scripted::default());
+        coder.max_context_chars = Some(10);
+
+        let error = coder
+            .request(&comment("src/lib.rs", (5, 5)))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("max_context_chars"));
+    }
+
+    /// reads every answer as lines to append to `notes.txt`
+    struct AppendEverything;
+
+    impl TransformationParser for AppendEverything {
+        fn parse(&self, response: &str) -> Result<Vec<Transformation>> {
+            Ok(vec![Transformation::InsertFragment {
+                filepath: "notes.txt".to_string(),
+                line_no: 1,
+                content: response.lines().map(str::to_string).collect(),
+            }])
+        }
+    }
+
+    #[test]
+    fn custom_parser_is_used() {
+        let repository = temp_repo("custom-parser", &[("notes.txt", "first\n")]);
+        let mut coder = coder_with(repository, Scripted::new(&["second"]), AppendEverything);
+        coder.sanitize_responses = false;
+
+        let transformations = coder
+            .generate_transformations(&comment("notes.txt", (0, 0)))
+            .unwrap();
+
+        assert_eq!(transformations.len(), 1);
+        let content = coder.repository.read_file("notes.txt").unwrap();
+        assert_eq!(content, "first\nsecond\n");
+    }
+
+    /// An answer in the template of an update replacing `line` of `filepath` with `content`
+    pub(crate) fn update_answer(filepath: &str, line: usize, content: &str) -> String {
+        format!(
+            "```\nUpdateFragment:\n    filepath: {filepath}\n    start_line: {line}\n    end_line: {line}\n    content: {content}\n```\n"
+        )
+    }
+
+    #[test]
+    fn transformations_are_capped() {
+        let repository = temp_repo("transformation-cap", &[("a.txt", "a\nb\nc\n")]);
+        let answer = [
+            update_answer("a.txt", 0, "A"),
+            update_answer("a.txt", 1, "B"),
+            update_answer("a.txt", 2, "C"),
+        ]
+        .concat();
+        let mut coder = coder(repository, Scripted::new(&[&answer]));
+        coder.transformation_count = 2;
+
+        let transformations = coder
+            .generate_transformations(&comment("a.txt", (0, 2)))
+            .unwrap();
+
+        assert_eq!(transformations.len(), 2);
+        let content = coder.repository.read_file("a.txt").unwrap();
+        assert_eq!(content, "A\nB\nc\n");
+    }
+
+    fn changed(files: &[&str]) -> Vec<(String, Diff)> {
+        files
+            .iter()
+            .map(|file| (file.to_string(), String::new()))
+            .collect()
+    }
+
+    #[test]
+    fn commit_type_matches_test_paths() {
+        assert_eq!(commit_type(&changed(&["tests/agent.rs"])), "test");
+        assert_eq!(commit_type(&changed(&["src/parser_test.rs"])), "test");
+        assert_eq!(commit_type(&changed(&["benches/parse.rs"])), "test");
+        assert_eq!(commit_type(&changed(&["src/attestation.rs"])), "fix");
+        assert_eq!(commit_type(&changed(&["src/latest.rs"])), "fix");
+        assert_eq!(commit_type(&changed(&["README.md"])), "docs");
+        assert_eq!(commit_type(&[]), "chore");
+    }
+
+    #[test]
+    fn commit_summarizes_each_file() {
+        let repository = temp_repo("commit-summaries", &[("a.txt", "a\n"), ("b.txt", "b\n")]);
+        crate::repository::tests::write_file(&repository, "a.txt", "A\n");
+        crate::repository::tests::write_file(&repository, "b.txt", "B\n");
+        let llm = Scripted::new(&["uppercase a", "uppercase b", "fix: uppercase a and b"]);
+        let coder = coder(repository, llm);
+
+        let message = coder
+            .generate_commit(CommitStyle::Conventional {
+                types: CONVENTIONAL_TYPES,
+            })
+            .unwrap();
+
+        assert_eq!(message, "fix: uppercase a and b");
+        let prompts = coder.llm.prompts.lock().unwrap();
+        assert_eq!(prompts.len(), 3);
+        assert!(prompts[0].contains("changes to a.txt") && prompts[0].contains("+A"));
+        assert!(prompts[1].contains("changes to b.txt") && prompts[1].contains("+B"));
+        assert!(prompts[2].contains("a.txt: uppercase a\nb.txt: uppercase b"));
+    }
+
+    #[test]
+    fn prose_around_the_blocks_is_dropped() {
+        let block = "```\nUpdateFragment:\n    filepath: src/a.rs\n    start_line: 1\n    end_line: 1\n    content: x\n```";
+        let response = format!(
+            "Sure! The problem is the variable name.\n\n```rust\nlet y = 1;\n```\n\nHere's the fix:\n\n{block}\n\nThis renames it. Let me know if you need anything else!"
+        );
+
+        assert_eq!(sanitize_response(&response), block);
+    }
+
+    #[test]
+    fn response_without_a_template_is_kept() {
+        let response = "[{\"kind\": \"Noop\"}]\n\nNothing needs to change.";
+
+        assert_eq!(sanitize_response(response), response);
+    }
+
+    #[test]
+    fn comment_on_two_files_edits_both() {
+        let repository = temp_repo("two-file-comment", &[("a.txt", "a\n"), ("b.txt", "b\n")]);
+        let answer = [
+            update_answer("a.txt", 0, "A"),
+            update_answer("b.txt", 0, "B"),
+        ]
+        .concat();
+        let mut coder = coder(repository, Scripted::new(&[&answer]));
+        let mut comment = comment("a.txt", (0, 0));
+        comment
+            .fragments
+            .extend(super::tests::comment("b.txt", (0, 0)).fragments);
+
+        coder.generate_transformations(&comment).unwrap();
+
+        let prompts = coder.llm.prompts.lock().unwrap();
+        assert!(prompts[0].contains("a.txt") && prompts[0].contains("b.txt"));
+        assert_eq!(coder.repository.read_file("a.txt").unwrap(), "A\n");
+        assert_eq!(coder.repository.read_file("b.txt").unwrap(), "B\n");
+    }
+
+    #[test]
+    fn shown_line_numbers_round_trip() {
+        for line_base in [0, 1] {
+            let name = format!("line-base-{line_base}");
+            let repository = GitRepository {
+                line_base,
+                context_window: 5,
+                ..temp_repo(&name, &[("a.txt", "a\nb\nc\n")])
+            };
+            let mut coder = coder(repository, Scripted::default());
+            let comment = comment("a.txt", (0, 2));
+
+            // answer with the number the prompt shows for `b`
+            let (_, prompt) = coder.request(&comment).unwrap();
+            let shown = prompt
+                .lines()
+                .find_map(|line| line.strip_suffix(" b"))
+                .unwrap();
+            let answer = update_answer("a.txt", shown.parse().unwrap(), "B");
+            coder.llm = Scripted::new(&[&answer]);
+
+            coder.generate_transformations(&comment).unwrap();
+
+            let content = coder.repository.read_file("a.txt").unwrap();
+            assert_eq!(content, "a\nB\nc\n", "with line_base {line_base}");
+        }
+    }
+
+    #[test]
+    fn large_diff_is_shrunk_to_its_largest_hunks() {
+        let hunk = |start: usize, lines: usize| {
+            let added = (0..lines)
+                .map(|i| format!("+line {i}\n"))
+                .collect::<String>();
+            format!("@@ -{start},0 +{start},{lines} @@\n{added}")
+        };
+        let diff = format!(
+            "diff --git a/a.rs b/a.rs\n--- a/a.rs\n+++ b/a.rs\n{}{}{}",
+            hunk(1, 3),
+            hunk(10, 400),
+            hunk(500, 5)
+        );
+
+        let shrunk = shrink_diff(&diff, 300);
+
+        assert!(shrunk.len() <= 300, "{} characters", shrunk.len());
+        assert!(shrunk.starts_with("diff --git a/a.rs b/a.rs"));
+        assert!(shrunk.contains("408 lines added and 0 removed in 3 hunks"));
+        // the 400 line hunk can't fit, so the next largest are shown in their order
+        assert!(!shrunk.contains("@@ -10,0"));
+        assert!(shrunk.find("@@ -1,0").unwrap() < shrunk.find("@@ -500,0").unwrap());
+    }
+
+    #[test]
+    fn summary_of_a_large_file_is_reused_until_it_changes() {
+        let large = (0..10).map(|i| format!("line {i}\n")).collect::<String>();
+        let repository = temp_repo("file-summary", &[("a.txt", &large)]);
+        let mut coder = coder(repository, Scripted::new(&["first", "second"]));
+        coder.whole_file_threshold = Some(FileSize::Lines(3));
+        coder.file_summaries = Some(FileSummaryCache::default());
+        let comment = comment("a.txt", (0, 0));
+
+        for _ in 0..2 {
+            let (_, prompt) = coder.request(&comment).unwrap();
+            assert!(prompt.contains("Summary of a.txt:\nfirst"));
+        }
+        assert_eq!(coder.llm.prompts.lock().unwrap().len(), 1);
+
+        write_file(&coder.repository, "a.txt", &format!("{large}line 10\n"));
+        let (_, prompt) = coder.request(&comment).unwrap();
+        assert!(prompt.contains("Summary of a.txt:\nsecond"));
+    }
+
+    #[test]
+    fn small_file_is_not_summarized() {
+        let repository = temp_repo("file-summary-small", &[("a.txt", "a\nb\n")]);
+        let mut coder = coder(repository, Scripted::default());
+        coder.whole_file_threshold = Some(FileSize::Lines(3));
+        coder.file_summaries = Some(FileSummaryCache::default());
+
+        let (_, prompt) = coder.request(&comment("a.txt", (0, 0))).unwrap();
+
+        assert!(!prompt.contains("Summary of"));
+        assert!(coder.llm.prompts.lock().unwrap().is_empty());
+    }
+
+    fn scored(line: usize, content: &str, score: Option<f32>) -> ScoredTransformation {
+        ScoredTransformation {
+            transformation: Transformation::UpdateFragment {
+                fragment: Fragment {
+                    filepath: "a.txt".to_string(),
+                    line_range: (line, line),
+                    column: None,
+                },
+                updated_lines: vec![content.to_string()],
+                expected: None,
+            },
+            score,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn highest_scored_transformations_are_kept() {
+        let repository = temp_repo("scored-cap", &[("a.txt", "a\nb\nc\n")]);
+        let mut coder = coder(repository, Scripted::default());
+        coder.transformation_count = 2;
+
+        let kept = coder
+            .apply(
+                &comment("a.txt", (1, 1)),
+                vec![
+                    scored(0, "A", None),
+                    scored(1, "B", Some(0.9)),
+                    scored(2, "C", Some(0.2)),
+                ],
+            )
+            .unwrap();
+
+        let scores = kept.iter().map(|scored| scored.score).collect::<Vec<_>>();
+        assert_eq!(scores, [Some(0.9), Some(0.2)]);
+        assert!(kept
+            .iter()
+            .all(|scored| scored.source.as_deref() == Some("a.txt:1")));
+        assert_eq!(coder.repository.read_file("a.txt").unwrap(), "a\nB\nC\n");
+    }
+
+    #[test]
+    fn conventional_commits_are_accepted() {
+        for message in [
+            "feat: add a parser",
+            "fix(coder): keep the enclosing function",
+            "refactor!: drop the old planner",
+            "docs(readme): explain the config\n\nWith an example of every setting.",
+        ] {
+            assert_eq!(
+                check_conventional_commit(message, CONVENTIONAL_TYPES),
+                Ok(()),
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_commits_are_rejected() {
+        for message in [
+            "Add a parser",
+            "feat:add a parser",
+            "feat(): add a parser",
+            "Feat: add a parser",
+            "",
+        ] {
+            let error = check_conventional_commit(message, CONVENTIONAL_TYPES).unwrap_err();
+            assert!(error.contains("type(scope): subject"), "{message}");
+        }
+    }
+
+    #[test]
+    fn commit_types_can_be_restricted() {
+        let error = check_conventional_commit("chore: bump", &["feat", "fix"]).unwrap_err();
+
+        assert_eq!(error, "`chore` is not one of the allowed types: feat, fix");
+        assert!(check_conventional_commit("anything: goes", &[]).is_ok());
+    }
+
+    #[test]
+    fn body_needs_a_blank_line() {
+        let error = check_conventional_commit("fix: a\nbody", CONVENTIONAL_TYPES).unwrap_err();
+
+        assert!(error.contains("blank line"));
+    }
+
+    #[test]
+    fn disallowed_delete_is_rejected() {
+        let repository = temp_repo("disallowed-delete", &[("a.txt", "a\n")]);
+        let answer = r#"[{"kind": "DeleteFile", "path": "a.txt"}]"#;
+        let mut coder = coder_with(
+            repository,
+            Scripted::new(&[answer]),
+            crate::parser::JsonParser,
+        );
+        coder.allowed_transformations = HashSet::from([
+            TransformationKind::UpdateFragment,
+            TransformationKind::InsertFragment,
+        ]);
+
+        let error = coder
+            .generate_transformations(&comment("a.txt", (0, 0)))
+            .unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<RakuneError>(),
+            Some(RakuneError::DisallowedTransformation {
+                kind: TransformationKind::DeleteFile,
+                filepath: Some(path),
+            }) if path == "a.txt"
+        ));
+        assert!(coder.repository.path("a.txt").is_file());
+    }
+
+    #[test]
+    fn retry_after_a_bad_reply_offers_only_allowed_templates() {
+        let repository = temp_repo("retry-allowed", &[("a.txt", "a\n")]);
+        let answers = ["no template here", &update_answer("a.txt", 0, "A")];
+        let mut coder = coder(repository, Scripted::new(&answers));
+        coder.max_parse_attempts = 2;
+        coder.allowed_transformations = HashSet::from([TransformationKind::UpdateFragment]);
+
+        coder
+            .generate_transformations(&comment("a.txt", (0, 0)))
+            .unwrap();
+
+        let prompts = coder.llm.prompts.lock().unwrap();
+        assert!(prompts[1].contains("Your last reply didn't match the template"));
+        assert!(!prompts[1].contains("InsertFragment"));
+    }
+
+    #[test]
+    fn allowed_delete_is_applied() {
+        let repository = temp_repo("allowed-delete", &[("a.txt", "a\n"), ("b.txt", "b\n")]);
+        let answer = r#"[{"kind": "DeleteFile", "path": "a.txt"}]"#;
+        let mut coder = coder_with(
+            repository,
+            Scripted::new(&[answer]),
+            crate::parser::JsonParser,
+        );
+
+        coder
+            .generate_transformations(&comment("b.txt", (0, 0)))
+            .unwrap();
+
+        assert!(!coder.repository.path("a.txt").exists());
+        assert!(coder.repository.path("b.txt").is_file());
+    }
+}
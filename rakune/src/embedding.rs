@@ -0,0 +1,104 @@
+/// a collection of embeddings that can be searched for those closest to a query
+pub trait EmbeddingStore<Id> {
+    /// Store the embedding of `id`, replacing the one it had before
+    fn add(&mut self, id: Id, embedding: Vec<f32>);
+
+    /// The `k` stored ids most similar to `query`, from most to least similar along with their
+    /// scores
+    fn search(&self, query: &[f32], k: usize) -> Vec<(Id, f32)>;
+}
+
+/// keeps every embedding in memory and compares the query against all of them, which is plenty
+/// for the context blocks of a single repository
+#[derive(Clone, Debug)]
+pub struct InMemoryStore<Id> {
+    pub entries: Vec<(Id, Vec<f32>)>,
+}
+
+impl<Id> Default for InMemoryStore<Id> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<Id: Clone + PartialEq> EmbeddingStore<Id> for InMemoryStore<Id> {
+    fn add(&mut self, id: Id, embedding: Vec<f32>) {
+        match self.entries.iter_mut().find(|(stored, _)| *stored == id) {
+            Some((_, stored)) => *stored = embedding,
+            None => self.entries.push((id, embedding)),
+        }
+    }
+
+    fn search(&self, query: &[f32], k: usize) -> Vec<(Id, f32)> {
+        let mut scored = self
+            .entries
+            .iter()
+            .map(|(id, embedding)| (id.clone(), cosine_similarity(query, embedding)))
+            .collect::<Vec<_>>();
+
+        scored.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        scored.truncate(k);
+
+        scored
+    }
+}
+
+/// The cosine of the angle between two embeddings of the same model, or 0 when either of them
+/// is all zeros
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    match norm(a) * norm(b) {
+        0.0 => 0.0,
+        norms => dot / norms,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_ranks_the_top_k_by_similarity() {
+        let mut store = InMemoryStore::default();
+        store.add("opposite", vec![-1.0, 0.0]);
+        store.add("same", vec![2.0, 0.0]);
+        store.add("diagonal", vec![1.0, 1.0]);
+        store.add("orthogonal", vec![0.0, 1.0]);
+
+        let ids = store
+            .search(&[1.0, 0.0], 3)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+
+        assert_eq!(ids, ["same", "diagonal", "orthogonal"]);
+    }
+
+    #[test]
+    fn adding_an_id_again_replaces_its_embedding() {
+        let mut store = InMemoryStore::default();
+        store.add("a", vec![1.0, 0.0]);
+        store.add("a", vec![0.0, 1.0]);
+
+        let results = store.search(&[0.0, 1.0], 5);
+
+        assert_eq!(results.len(), 1);
+        assert!((results[0].1 - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn empty_store_finds_nothing() {
+        let store = InMemoryStore::<&str>::default();
+
+        assert!(store.search(&[1.0, 0.0], 3).is_empty());
+    }
+
+    #[test]
+    fn zero_vector_scores_zero() {
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+}
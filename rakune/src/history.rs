@@ -0,0 +1,114 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::repository::{Comment, Transformation};
+use crate::{DataSource, Result};
+
+/// a comment the agent addressed, what it changed, and whether the result built
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Exchange {
+    pub comment: Comment,
+    pub transformations: Vec<Transformation>,
+    /// unset until the build following the transformations has finished
+    pub built: Option<bool>,
+}
+
+/// find past exchanges whose comments resemble `message`
+#[derive(Clone, Debug)]
+pub struct SimilarTo {
+    pub message: String,
+    /// prefer exchanges that touched this file
+    pub filepath: Option<String>,
+    pub limit: usize,
+}
+
+/// a log of every exchange, persisted as JSON so it survives across runs
+#[derive(Clone, Debug, Default)]
+pub struct ConversationLog {
+    pub path: PathBuf,
+    pub exchanges: Vec<Exchange>,
+}
+
+impl ConversationLog {
+    /// Load the log stored at `path`, starting an empty one if the file doesn't exist yet
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let exchanges = match path.exists() {
+            true => serde_json::from_str(&std::fs::read_to_string(&path)?)?,
+            false => Vec::new(),
+        };
+
+        Ok(Self { path, exchanges })
+    }
+
+    /// Record the transformations made for a comment, pending the outcome of the next build
+    pub fn record(&mut self, comment: &Comment, transformations: &[Transformation]) -> Result<()> {
+        self.exchanges.push(Exchange {
+            comment: comment.clone(),
+            transformations: transformations.to_vec(),
+            built: None,
+        });
+        self.save()
+    }
+
+    /// Settle every pending exchange with the outcome of the latest build
+    pub fn resolve(&mut self, built: bool) -> Result<()> {
+        self.exchanges
+            .iter_mut()
+            .filter(|e| e.built.is_none())
+            .for_each(|e| e.built = Some(built));
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        Ok(std::fs::write(
+            &self.path,
+            serde_json::to_string_pretty(&self.exchanges)?,
+        )?)
+    }
+}
+
+impl DataSource<SimilarTo, Vec<Exchange>> for ConversationLog {
+    /// Rank settled exchanges by the word overlap of their comment with the query
+    fn query(&self, query: &SimilarTo) -> Result<Vec<Exchange>> {
+        let words = |message: &str| {
+            message
+                .split(|c: char| !c.is_alphanumeric() && c != '_')
+                .filter(|w| !w.is_empty())
+                .map(str::to_lowercase)
+                .collect::<HashSet<_>>()
+        };
+        let target = words(&query.message);
+
+        let mut scored = self
+            .exchanges
+            .iter()
+            .filter(|e| e.built.is_some())
+            .filter_map(|e| {
+                let candidate = words(&e.comment.message);
+                let shared = target.intersection(&candidate).count();
+                if shared == 0 {
+                    return None;
+                }
+
+                let mut score = shared as f32 / target.union(&candidate).count() as f32;
+                if let Some(filepath) = &query.filepath {
+                    if e.comment.fragments.iter().any(|f| &f.filepath == filepath) {
+                        score += 1.0;
+                    }
+                }
+                Some((score, e))
+            })
+            .collect::<Vec<_>>();
+
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        Ok(scored
+            .into_iter()
+            .take(query.limit)
+            .map(|(_, e)| e.clone())
+            .collect())
+    }
+}
@@ -0,0 +1,140 @@
+use regex::Regex;
+
+/// file at the root of a repository listing globs, one per line, of paths that rakune leaves
+/// out of context gathering and repository-wide operations
+pub const IGNORE_FILE: &str = ".rakuneignore";
+
+/// Read the globs out of an ignore file in gitignore syntax, skipping blank lines and comments
+pub fn parse_ignore_file(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        // a leading backslash escapes a literal `#`
+        .map(|line| match line.strip_prefix("\\#") {
+            Some(rest) => format!("#{rest}"),
+            None => line.to_string(),
+        })
+        .collect()
+}
+
+/// Whether `path` is ignored by a list of globs. Like in gitignore, a glob starting with `!`
+/// re-includes the paths it matches, and later globs take precedence over earlier ones.
+pub fn is_ignored_by(globs: &[String], path: &str) -> bool {
+    globs
+        .iter()
+        .rev()
+        .find_map(|glob| match glob.strip_prefix('!') {
+            Some(negated) => glob_match(negated, path).then_some(false),
+            None => glob_match(glob, path).then_some(true),
+        })
+        .unwrap_or(false)
+}
+
+/// Whether `path` (relative to the repository root) matches a gitignore-style glob. `*` and `?`
+/// stay within a path component while `**` crosses them. A trailing `/` matches everything
+/// inside a directory, and a pattern without any other `/` may match at any depth.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let (pattern, directory) = match pattern.strip_suffix('/') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let anchored = pattern.contains('/');
+    let pattern = pattern.trim_start_matches('/');
+
+    let mut expression = String::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no directories at all
+                match chars.peek() == Some(&'/') {
+                    true => {
+                        chars.next();
+                        expression += "(?:.*/)?";
+                    }
+                    false => expression += ".*",
+                }
+            }
+            '*' => expression += "[^/]*",
+            '?' => expression += "[^/]",
+            c => expression += &regex::escape(&c.to_string()),
+        }
+    }
+
+    let prefix = match anchored {
+        true => "^",
+        false => "^(?:.*/)?",
+    };
+    let suffix = match directory {
+        true => "/.*$",
+        false => "(?:/.*)?$",
+    };
+
+    Regex::new(&format!("{prefix}{expression}{suffix}"))
+        .map(|re| re.is_match(path))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_stays_within_a_component() {
+        assert!(glob_match("src/*.rs", "src/lib.rs"));
+        assert!(!glob_match("src/*.rs", "src/nested/lib.rs"));
+        assert!(glob_match("src/?.rs", "src/a.rs"));
+    }
+
+    #[test]
+    fn double_star_crosses_components() {
+        assert!(glob_match("**/generated.rs", "generated.rs"));
+        assert!(glob_match("**/generated.rs", "src/deep/generated.rs"));
+        assert!(glob_match("src/**/*.rs", "src/a/b/c.rs"));
+        assert!(!glob_match("src/**/*.rs", "tests/c.rs"));
+    }
+
+    #[test]
+    fn trailing_slash_matches_inside_a_directory() {
+        assert!(glob_match("target/", "target/debug/rakune"));
+        assert!(glob_match("target/", "crate/target/debug/rakune"));
+        assert!(!glob_match("target/", "target"));
+    }
+
+    #[test]
+    fn unanchored_patterns_match_at_any_depth() {
+        assert!(glob_match("*.lock", "Cargo.lock"));
+        assert!(glob_match("*.lock", "rakune-cli/Cargo.lock"));
+        assert!(glob_match(
+            "node_modules",
+            "web/node_modules/react/index.js"
+        ));
+        assert!(!glob_match("/Cargo.lock", "rakune-cli/Cargo.lock"));
+        assert!(glob_match("/Cargo.lock", "Cargo.lock"));
+    }
+
+    #[test]
+    fn ignore_file_skips_blank_lines_and_comments() {
+        let globs = parse_ignore_file("# generated\n\ntarget/\n\\#literal\n  \n");
+
+        assert_eq!(globs, ["target/", "#literal"]);
+    }
+
+    #[test]
+    fn negation_re_includes_a_path() {
+        let globs = parse_ignore_file("generated/\n!generated/keep.rs\n");
+
+        assert!(is_ignored_by(&globs, "generated/a.rs"));
+        assert!(!is_ignored_by(&globs, "generated/keep.rs"));
+        assert!(!is_ignored_by(&globs, "src/lib.rs"));
+    }
+
+    #[test]
+    fn later_globs_take_precedence() {
+        let globs = parse_ignore_file("!*.rs\n*.rs\n");
+
+        assert!(is_ignored_by(&globs, "src/lib.rs"));
+    }
+}
@@ -1,24 +1,109 @@
 use std::error::Error;
 use std::result::Result as Res;
 
+pub mod agent;
+pub mod audit;
+pub mod builder;
+pub mod coder;
+pub mod embedding;
+pub mod history;
+pub mod ignore;
 pub mod llm;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+pub mod parser;
+pub mod planner;
+pub mod prompt;
 pub mod repository;
 
 pub(crate) type Result<T> = Res<T, Box<dyn Error>>;
 
-type Prompt = str;
+/// failures of rakune itself that are worth explaining to the model, so that it can correct
+/// its answer
+#[derive(Debug, PartialEq, Eq)]
+pub enum RakuneError {
+    /// an edit names a file that isn't in the repository, along with the closest one that is
+    MissingFile {
+        filepath: String,
+        suggestion: Option<String>,
+    },
+    /// a file is over the size rakune is allowed to read and edit, likely because it's generated
+    FileTooLarge {
+        filepath: String,
+        size: u64,
+        limit: u64,
+    },
+    /// an edit of a kind that [`coder::Coder::allowed_transformations`] doesn't permit
+    DisallowedTransformation {
+        kind: repository::TransformationKind,
+        filepath: Option<String>,
+    },
+    /// a path that leads outside the repository, through `..` or a symlink
+    OutsideRepository {
+        filepath: String,
+        resolved: std::path::PathBuf,
+    },
+    /// a path through a symlink when [`repository::GitRepository::deny_symlinks`] is set
+    Symlink { filepath: String },
+}
+
+impl std::fmt::Display for RakuneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RakuneError::MissingFile {
+                filepath,
+                suggestion: Some(suggestion),
+            } => write!(f, "{filepath} does not exist, did you mean {suggestion}?"),
+            RakuneError::MissingFile {
+                filepath,
+                suggestion: None,
+            } => write!(f, "{filepath} does not exist"),
+            RakuneError::FileTooLarge {
+                filepath,
+                size,
+                limit,
+            } => write!(
+                f,
+                "{filepath} is {size} bytes, over the limit of {limit} bytes for files to edit"
+            ),
+            RakuneError::DisallowedTransformation {
+                kind,
+                filepath: Some(filepath),
+            } => write!(
+                f,
+                "{kind} is not allowed here, so {filepath} can't be changed that way"
+            ),
+            RakuneError::DisallowedTransformation {
+                kind,
+                filepath: None,
+            } => write!(f, "{kind} is not allowed here"),
+            RakuneError::OutsideRepository { filepath, resolved } => write!(
+                f,
+                "{filepath} leads to {}, outside the repository",
+                resolved.display()
+            ),
+            RakuneError::Symlink { filepath } => {
+                write!(f, "{filepath} goes through a symlink, which isn't followed")
+            }
+        }
+    }
+}
+
+impl Error for RakuneError {}
+
+pub type Prompt = str;
 pub type Diff = String;
 
 /// steps are meant to be limited context units of work
 pub type Step = String;
 
 pub trait Planner {
-    ///
+    /// whether every step of the plan has been carried out
     fn completed(&self) -> bool;
-    ///
+    /// the next step that still needs to be carried out
     fn next_pending(&self) -> Step;
-    ///
-    fn from_prompt(&self, p: &Prompt) -> Self
+    /// build a plan from an initial user prompt
+    fn from_prompt(p: &Prompt) -> Self
     where
         Self: Sized;
 }
@@ -28,4 +113,3 @@ pub trait Planner {
 pub trait DataSource<Q, A> {
     fn query(&self, query: &Q) -> Result<A>;
 }
-
@@ -1,10 +1,80 @@
+use std::collections::HashSet;
 use std::error::Error;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
 
 use reqwest;
 use serde::{Deserialize, Serialize};
 
+use crate::repository::TransformationKind;
+
 pub trait LLM {
     fn prompt(&self, prompt: &str) -> Result<String, Box<dyn Error>>;
+
+    /// Prompt with instructions kept apart from the request, for chat models that perform better
+    /// with a distinct system role. Backends without one receive both in a single prompt.
+    fn prompt_with_system(&self, system: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
+        self.prompt(&format!("{system}\n\n{prompt}"))
+    }
+}
+
+/// failures specific to talking with a model
+#[derive(Debug, PartialEq, Eq)]
+pub enum LLMError {
+    /// the model replied with nothing but whitespace. usually transient, so worth a retry
+    EmptyResponse,
+}
+
+impl std::fmt::Display for LLMError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LLMError::EmptyResponse => write!(f, "the model returned an empty response"),
+        }
+    }
+}
+
+impl Error for LLMError {}
+
+/// caps how many requests to a model server are in flight at once, so that concurrent work
+/// doesn't overwhelm a local server
+pub struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// a slot taken from a [`Semaphore`], given back when dropped
+pub struct Permit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    /// A semaphore letting `permits` requests through at once, and at least one
+    pub fn new(permits: usize) -> Self {
+        Self {
+            available: Mutex::new(permits.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait for a free slot and take it
+    pub fn acquire(&self) -> Permit<'_> {
+        // a panic while holding the lock can't leave the count inconsistent, so poisoning is
+        // ignored
+        let mut available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        while *available == 0 {
+            available = self
+                .released
+                .wait(available)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        *available -= 1;
+        Permit(self)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.0.released.notify_one();
+    }
 }
 
 #[derive(Deserialize)]
@@ -16,23 +86,196 @@ struct OllamaResponse {
 struct OllamaRequest<'a> {
     model: String,
     prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<&'a str>,
     stream: bool,
     context: &'a [usize],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'a serde_json::Value>,
+    #[serde(skip_serializing_if = "OllamaOptions::is_empty")]
+    options: OllamaOptions<'a>,
+}
+
+/// model parameters of a request
+#[derive(Serialize)]
+struct OllamaOptions<'a> {
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop: &'a [&'a str],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<usize>,
+}
+
+impl OllamaOptions<'_> {
+    fn is_empty(&self) -> bool {
+        self.stop.is_empty() && self.num_predict.is_none()
+    }
+}
+
+/// a request to `/api/embed`, which embeds several inputs at once on newer servers
+#[derive(Serialize)]
+struct OllamaEmbedRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct OllamaEmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+/// a request to `/api/embeddings`, which embeds a single prompt on every server version
+#[derive(Serialize)]
+struct OllamaEmbeddingRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct OllamaEmbeddingResponse {
+    embedding: Vec<f32>,
 }
 
 pub struct Ollama<'a> {
     pub endpoint: &'a str,
     pub model: &'a str,
+    /// how long the model stays loaded after a request (e.g. `"5m"`, or `"0"` to unload
+    /// immediately). the server default is used when unset
+    pub keep_alive: Option<&'a str>,
+    /// generation ends as soon as the model emits one of these sequences
+    pub stop: &'a [&'a str],
+    /// upper bound on the number of tokens generated for a reply
+    pub num_predict: Option<usize>,
+    /// constrains replies to `"json"`, or to a JSON schema such as
+    /// [`crate::parser::JsonParser::schema`]
+    pub format: Option<serde_json::Value>,
 }
 impl LLM for Ollama<'_> {
     fn prompt(&self, prompt: &str) -> Result<String, Box<dyn Error>> {
+        self.generate(None, prompt)
+    }
+
+    fn prompt_with_system(&self, system: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
+        self.generate(Some(system), prompt)
+    }
+}
+
+impl Ollama<'_> {
+    /// Check that the Ollama server answers before relying on it, so an unreachable host fails
+    /// fast instead of timing out deep inside a generation
+    pub fn health_check(&self) -> Result<(), Box<dyn Error>> {
+        let unreachable = || format!("Ollama not reachable at {}", self.endpoint);
+
+        let status = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?
+            .get(self.route("tags"))
+            .send()
+            .map_err(|e| format!("{}: {e}", unreachable()))?
+            .status();
+
+        if !status.is_success() {
+            return Err(format!("{} ({status})", unreachable()).into());
+        }
+
+        Ok(())
+    }
+
+    /// The url of another route of the api on the same server as `endpoint`
+    fn route(&self, route: &str) -> String {
+        // the endpoint points at a route of the api, e.g. http://localhost:11434/api/generate
+        let base = self.endpoint.split("/api/").next().unwrap_or(self.endpoint);
+        format!("{base}/api/{route}")
+    }
+
+    /// The embedding of `text` by `model`, for ranking context blocks by their similarity to a
+    /// comment, see [`crate::embedding::EmbeddingStore`]
+    pub fn embed(&self, text: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+        let request = OllamaEmbeddingRequest {
+            model: self.model,
+            prompt: text,
+            keep_alive: self.keep_alive,
+        };
+
+        let response = reqwest::blocking::Client::new()
+            .post(self.route("embeddings"))
+            .body(serde_json::to_string(&request)?)
+            .send()?;
+
+        let status = response.status();
+        let response = response.text()?;
+        if !status.is_success() {
+            return Err(format!("Ollama failed to embed the text ({status}): {response}").into());
+        }
+
+        Ok(serde_json::from_str::<OllamaEmbeddingResponse>(&response)?.embedding)
+    }
+
+    /// The embeddings of every one of `texts`, in the same order, asked for in a single request
+    /// so that embedding the blocks of a large file doesn't take a round trip for each. Servers
+    /// too old to embed several inputs at once are asked for one embedding at a time instead
+    pub fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let request = OllamaEmbedRequest {
+            model: self.model,
+            input: texts,
+            keep_alive: self.keep_alive,
+        };
+
+        let response = reqwest::blocking::Client::new()
+            .post(self.route("embed"))
+            .body(serde_json::to_string(&request)?)
+            .send()?;
+
+        let status = response.status();
+        let response = response.text()?;
+        // `/api/embed` only exists on servers that accept several inputs. newer servers answer a
+        // missing model with a 404 too, but explain it with a JSON `error`
+        let has_error = serde_json::from_str::<serde_json::Value>(&response)
+            .is_ok_and(|body| body.get("error").is_some());
+        if status == reqwest::StatusCode::NOT_FOUND && !has_error {
+            return texts.iter().map(|text| self.embed(text)).collect();
+        }
+        if !status.is_success() {
+            return Err(format!("Ollama failed to embed the texts ({status}): {response}").into());
+        }
+
+        let embeddings = serde_json::from_str::<OllamaEmbedResponse>(&response)?.embeddings;
+        if embeddings.len() != texts.len() {
+            let error_message = format!(
+                "Ollama returned {} embeddings for {} texts.",
+                embeddings.len(),
+                texts.len()
+            );
+            return Err(error_message.into());
+        }
+
+        Ok(embeddings)
+    }
+
+    fn generate(&self, system: Option<&str>, prompt: &str) -> Result<String, Box<dyn Error>> {
         let client = reqwest::blocking::Client::new();
 
         let ollama_request = &OllamaRequest {
             prompt: prompt.to_string(),
+            system,
             model: self.model.to_string(),
             stream: false,
             context: &[],
+            keep_alive: self.keep_alive,
+            format: self.format.as_ref(),
+            options: OllamaOptions {
+                stop: self.stop,
+                num_predict: self.num_predict,
+            },
         };
 
         #[cfg(debug_assertions)]
@@ -55,7 +298,560 @@ impl LLM for Ollama<'_> {
             response.response
         );
 
+        if response.response.trim().is_empty() {
+            return Err(LLMError::EmptyResponse.into());
+        }
+
         Ok(response.response.to_string())
     }
 }
 
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Serialize)]
+struct ClaudeRequest<'a> {
+    model: &'a str,
+    max_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<&'a str>,
+    messages: [ChatMessage<'a>; 1],
+}
+
+#[derive(Deserialize)]
+struct ClaudeContent {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct ClaudeResponse {
+    content: Vec<ClaudeContent>,
+}
+
+#[derive(Deserialize)]
+struct ClaudeErrorResponse {
+    error: ClaudeErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ClaudeErrorBody {
+    #[serde(rename = "type")]
+    kind: String,
+    message: String,
+}
+
+/// Anthropic's messages api
+pub struct Claude<'a> {
+    /// the messages route, e.g. `https://api.anthropic.com/v1/messages`
+    pub endpoint: &'a str,
+    pub model: &'a str,
+    pub api_key: &'a str,
+    /// upper bound on the length of a reply, in tokens
+    pub max_tokens: usize,
+}
+
+impl Claude<'_> {
+    /// the version of the api the requests are written against
+    const API_VERSION: &'static str = "2023-06-01";
+
+    fn message(&self, system: Option<&str>, prompt: &str) -> Result<String, Box<dyn Error>> {
+        let claude_request = &ClaudeRequest {
+            model: self.model,
+            max_tokens: self.max_tokens,
+            system,
+            messages: [ChatMessage {
+                role: "user",
+                content: prompt,
+            }],
+        };
+
+        #[cfg(debug_assertions)]
+        eprintln!("*************** Payload **************\n{:#}\n", prompt);
+
+        let response = reqwest::blocking::Client::new()
+            .post(self.endpoint)
+            .header("x-api-key", self.api_key)
+            .header("anthropic-version", Self::API_VERSION)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(claude_request)?)
+            .send()?;
+
+        let status = response.status();
+        let body = response.text()?;
+
+        if !status.is_success() {
+            let error_message = match serde_json::from_str::<ClaudeErrorResponse>(&body) {
+                Ok(e) => format!(
+                    "Claude request failed ({status}): {}: {}",
+                    e.error.kind, e.error.message
+                ),
+                Err(_) => format!("Claude request failed ({status}): {body}"),
+            };
+            return Err(error_message.into());
+        }
+
+        let response = serde_json::from_str::<ClaudeResponse>(&body)
+            .map_err(|e| format!("Unexpected response from Claude ({e}): {body}"))?;
+
+        let text = response
+            .content
+            .into_iter()
+            .next()
+            .map(|c| c.text)
+            .unwrap_or_default();
+
+        #[cfg(debug_assertions)]
+        eprintln!("*************** Response **************\n{:#}\n", text);
+
+        if text.trim().is_empty() {
+            return Err(LLMError::EmptyResponse.into());
+        }
+
+        Ok(text)
+    }
+}
+
+impl LLM for Claude<'_> {
+    fn prompt(&self, prompt: &str) -> Result<String, Box<dyn Error>> {
+        self.message(None, prompt)
+    }
+
+    fn prompt_with_system(&self, system: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
+        self.message(Some(system), prompt)
+    }
+}
+
+#[derive(Serialize)]
+struct OpenAIRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct OpenAIResponse {
+    choices: Vec<OpenAIChoice>,
+}
+
+#[derive(Deserialize)]
+struct OpenAIChoice {
+    message: OpenAIMessage,
+}
+
+#[derive(Deserialize)]
+struct OpenAIMessage {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<OpenAIToolCall>,
+}
+
+#[derive(Deserialize)]
+struct OpenAIToolCall {
+    function: OpenAIFunctionCall,
+}
+
+#[derive(Deserialize)]
+struct OpenAIFunctionCall {
+    name: String,
+    /// the arguments as a string of JSON
+    arguments: String,
+}
+
+/// OpenAI's chat completions api, or any server compatible with it
+pub struct OpenAI<'a> {
+    /// the chat completions route, e.g. `https://api.openai.com/v1/chat/completions`
+    pub endpoint: &'a str,
+    pub model: &'a str,
+    pub api_key: &'a str,
+    /// upper bound on the length of a reply, in tokens. the server default is used when unset
+    pub max_tokens: Option<usize>,
+    /// offer every transformation as a tool and have the model call them instead of writing
+    /// templates. the calls are returned as the JSON read by [`crate::parser::JsonParser`]. every
+    /// reply has to be a tool call then, so commits are better written by another model, see
+    /// [`crate::coder::Coder::models`]
+    pub tool_calling: bool,
+    /// the transformations offered as tools when `tool_calling` is set. calls to any other are
+    /// rejected, so keep it to [`crate::coder::Coder::allowed_transformations`]
+    pub tools: HashSet<TransformationKind>,
+}
+
+impl OpenAI<'_> {
+    fn complete(&self, system: Option<&str>, prompt: &str) -> Result<String, Box<dyn Error>> {
+        let mut messages = Vec::new();
+        if let Some(system) = system {
+            messages.push(ChatMessage {
+                role: "system",
+                content: system,
+            });
+        }
+        messages.push(ChatMessage {
+            role: "user",
+            content: prompt,
+        });
+
+        let tools = match self.tool_calling {
+            true => crate::parser::JsonParser::variant_schemas()
+                .into_iter()
+                .filter(|(kind, _)| self.tools.iter().any(|tool| tool.to_string() == *kind))
+                .map(|(kind, parameters)| {
+                    serde_json::json!({
+                        "type": "function",
+                        "function": {
+                            "name": kind,
+                            "description": format!("apply the {kind} transformation to the repository"),
+                            "parameters": parameters,
+                        },
+                    })
+                })
+                .collect(),
+            false => Vec::new(),
+        };
+
+        let openai_request = &OpenAIRequest {
+            model: self.model,
+            messages,
+            max_tokens: self.max_tokens,
+            tool_choice: self.tool_calling.then_some("required"),
+            tools,
+        };
+
+        #[cfg(debug_assertions)]
+        eprintln!("*************** Payload **************\n{:#}\n", prompt);
+
+        let response = reqwest::blocking::Client::new()
+            .post(self.endpoint)
+            .bearer_auth(self.api_key)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(openai_request)?)
+            .send()?;
+
+        let status = response.status();
+        let body = response.text()?;
+
+        if !status.is_success() {
+            return Err(format!("OpenAI request failed ({status}): {body}").into());
+        }
+
+        let response = serde_json::from_str::<OpenAIResponse>(&body)
+            .map_err(|e| format!("Unexpected response from OpenAI ({e}): {body}"))?;
+        let message = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("Unexpected response from OpenAI (no choices): {body}"))?
+            .message;
+
+        let text = match message.tool_calls.is_empty() {
+            true => message.content.unwrap_or_default(),
+            false => tool_calls_json(message.tool_calls)?,
+        };
+
+        #[cfg(debug_assertions)]
+        eprintln!("*************** Response **************\n{:#}\n", text);
+
+        if text.trim().is_empty() {
+            return Err(LLMError::EmptyResponse.into());
+        }
+
+        Ok(text)
+    }
+}
+
+/// Turn the tool calls of a reply into a JSON array of transformations tagged by their `kind`
+fn tool_calls_json(tool_calls: Vec<OpenAIToolCall>) -> Result<String, Box<dyn Error>> {
+    let transformations = tool_calls
+        .into_iter()
+        .map(|call| {
+            let mut arguments =
+                serde_json::from_str::<serde_json::Value>(&call.function.arguments)?;
+            let fields = arguments
+                .as_object_mut()
+                .ok_or("The arguments of a tool call are not an object.")?;
+            fields.insert("kind".to_string(), call.function.name.into());
+            Ok(arguments)
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+    Ok(serde_json::to_string(&transformations)?)
+}
+
+impl LLM for OpenAI<'_> {
+    fn prompt(&self, prompt: &str) -> Result<String, Box<dyn Error>> {
+        self.complete(None, prompt)
+    }
+
+    fn prompt_with_system(&self, system: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
+        self.complete(Some(system), prompt)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    use super::*;
+
+    /// counts the calls running through [`Gauge::measure`] at once, keeping the most seen
+    #[derive(Default)]
+    pub(crate) struct Gauge {
+        running: AtomicUsize,
+        pub(crate) peak: AtomicUsize,
+    }
+
+    impl Gauge {
+        /// Run `f` while counted as running, lingering a bit so that calls get to overlap
+        pub(crate) fn measure<T>(&self, f: impl FnOnce() -> T) -> T {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(running, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(30));
+            let result = f();
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            result
+        }
+    }
+
+    /// a request received by [`serve`]: its path and JSON body
+    type Received = (String, serde_json::Value);
+
+    /// Answer the requests to a local server with `replies` in turn, each a status and a body,
+    /// returning the endpoint for [`Ollama`] and the requests as they arrive
+    fn serve(replies: Vec<(u16, String)>) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            for (status, reply) in replies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line
+                    .split(' ')
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_string();
+
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = header.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let body = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+                sender.send((path, body)).unwrap();
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reply}",
+                    reply.len()
+                )
+                .unwrap();
+            }
+        });
+
+        (format!("http://{address}/api/generate"), receiver)
+    }
+
+    fn ollama(endpoint: &str) -> Ollama<'_> {
+        Ollama {
+            endpoint,
+            model: "codellama",
+            keep_alive: None,
+            stop: &[],
+            num_predict: None,
+            format: None,
+        }
+    }
+
+    #[test]
+    fn stop_and_num_predict_are_sent() {
+        let (endpoint, received) = serve(vec![(200, r#"{"response": "done"}"#.to_string())]);
+        let llm = Ollama {
+            stop: &["```\n\n"],
+            num_predict: Some(256),
+            ..ollama(&endpoint)
+        };
+
+        assert_eq!(llm.prompt("hi").unwrap(), "done");
+
+        let (path, body) = received.recv().unwrap();
+        assert_eq!(path, "/api/generate");
+        assert_eq!(body["options"]["stop"], serde_json::json!(["```\n\n"]));
+        assert_eq!(body["options"]["num_predict"], 256);
+    }
+
+    #[test]
+    fn options_are_left_out_when_unset() {
+        let (endpoint, received) = serve(vec![(200, r#"{"response": "done"}"#.to_string())]);
+
+        ollama(&endpoint).prompt("hi").unwrap();
+
+        let (_, body) = received.recv().unwrap();
+        assert!(body.get("options").is_none());
+    }
+
+    #[test]
+    fn format_schema_is_sent() {
+        let (endpoint, received) = serve(vec![(200, r#"{"response": "[]"}"#.to_string())]);
+        let schema = crate::parser::JsonParser::schema();
+        let llm = Ollama {
+            format: Some(schema.clone()),
+            ..ollama(&endpoint)
+        };
+
+        llm.prompt("hi").unwrap();
+
+        let (_, body) = received.recv().unwrap();
+        assert_eq!(body["format"], schema);
+    }
+
+    #[test]
+    fn json_format_is_sent_as_a_string() {
+        let (endpoint, received) = serve(vec![(200, r#"{"response": "{}"}"#.to_string())]);
+        let llm = Ollama {
+            format: Some(serde_json::json!("json")),
+            ..ollama(&endpoint)
+        };
+
+        llm.prompt("hi").unwrap();
+
+        let (_, body) = received.recv().unwrap();
+        assert_eq!(body["format"], "json");
+    }
+
+    #[test]
+    fn semaphore_caps_its_holders() {
+        let semaphore = Semaphore::new(3);
+        let gauge = Gauge::default();
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let _permit = semaphore.acquire();
+                    gauge.measure(|| {});
+                });
+            }
+        });
+
+        let peak = gauge.peak.load(Ordering::SeqCst);
+        assert!((2..=3).contains(&peak));
+    }
+
+    #[test]
+    fn semaphore_lets_at_least_one_through() {
+        let semaphore = Semaphore::new(0);
+
+        drop(semaphore.acquire());
+        drop(semaphore.acquire());
+    }
+
+    #[test]
+    fn texts_are_embedded_in_one_batch() {
+        let reply = r#"{"embeddings": [[1.0, 0.0], [0.0, 1.0]]}"#;
+        let (endpoint, received) = serve(vec![(200, reply.to_string())]);
+
+        let embeddings = ollama(&endpoint).embed_batch(&["a", "b"]).unwrap();
+
+        assert_eq!(embeddings, [[1.0, 0.0], [0.0, 1.0]]);
+        let (path, body) = received.recv().unwrap();
+        assert_eq!(path, "/api/embed");
+        assert_eq!(body["input"], serde_json::json!(["a", "b"]));
+    }
+
+    #[test]
+    fn old_servers_embed_one_text_at_a_time() {
+        let (endpoint, received) = serve(vec![
+            (404, "404 page not found".to_string()),
+            (200, r#"{"embedding": [1.0]}"#.to_string()),
+            (200, r#"{"embedding": [2.0]}"#.to_string()),
+        ]);
+
+        let embeddings = ollama(&endpoint).embed_batch(&["a", "b"]).unwrap();
+
+        assert_eq!(embeddings, [[1.0], [2.0]]);
+        let requests = received.iter().take(3).collect::<Vec<_>>();
+        assert_eq!(requests[0].0, "/api/embed");
+        assert_eq!(requests[1].0, "/api/embeddings");
+        assert_eq!(requests[1].1["prompt"], "a");
+        assert_eq!(requests[2].1["prompt"], "b");
+    }
+
+    #[test]
+    fn missing_model_is_not_taken_for_an_old_server() {
+        let reply = r#"{"error": "model \"codellama\" not found, try pulling it first"}"#;
+        let (endpoint, _received) = serve(vec![(404, reply.to_string())]);
+
+        let error = ollama(&endpoint)
+            .embed_batch(&["a"])
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("404"));
+        assert!(error.contains("try pulling it first"));
+    }
+
+    #[test]
+    fn failed_embedding_surfaces_the_body() {
+        let (endpoint, _received) = serve(vec![(500, r#"{"error": "out of memory"}"#.to_string())]);
+
+        let error = ollama(&endpoint).embed("a").unwrap_err().to_string();
+
+        assert!(error.contains("500"));
+        assert!(error.contains("out of memory"));
+    }
+
+    #[test]
+    fn wrong_number_of_embeddings_is_an_error() {
+        let (endpoint, _received) = serve(vec![(200, r#"{"embeddings": [[1.0]]}"#.to_string())]);
+
+        let error = ollama(&endpoint).embed_batch(&["a", "b"]).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Ollama returned 1 embeddings for 2 texts."
+        );
+    }
+
+    #[test]
+    fn only_the_given_tools_are_offered() {
+        let reply = r#"{"choices": [{"message": {"content": "[]"}}]}"#;
+        let (endpoint, received) = serve(vec![(200, reply.to_string())]);
+        let llm = OpenAI {
+            endpoint: &endpoint,
+            model: "gpt",
+            api_key: "key",
+            max_tokens: None,
+            tool_calling: true,
+            tools: HashSet::from([TransformationKind::UpdateFragment]),
+        };
+
+        llm.prompt("hi").unwrap();
+
+        let (_, body) = received.recv().unwrap();
+        let tools = body["tools"].as_array().unwrap();
+        assert_eq!(tools.len(), 1);
+        assert_eq!(tools[0]["function"]["name"], "UpdateFragment");
+    }
+}
@@ -0,0 +1,389 @@
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::repository::{numbered_lines, Fragment};
+use crate::{DataSource, Result};
+
+/// find where the symbol at the start of a fragment is defined and used
+#[derive(Clone, Debug)]
+pub struct SymbolAt {
+    /// root of the workspace the language server indexes
+    pub root: PathBuf,
+    pub fragment: Fragment,
+    /// the number of the first line of a file, as shown to the model
+    pub line_base: usize,
+}
+
+/// context from a language server such as rust-analyzer, started for every query and spoken to
+/// over stdio
+#[derive(Clone, Debug)]
+pub struct LspContext {
+    /// command that starts the language server
+    pub command: Vec<String>,
+    /// longest wait for the answer to a single request, after which the query fails rather than
+    /// hanging on a server that is stuck
+    pub timeout: Duration,
+}
+
+impl Default for LspContext {
+    fn default() -> Self {
+        Self {
+            command: vec!["rust-analyzer".to_string()],
+            // indexing a large workspace before the first answer can take a while
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl LspContext {
+    /// most references shown as context of a symbol
+    const MAX_REFERENCES: usize = 3;
+    /// longest part of a definition shown as context
+    const MAX_DEFINITION_LINES: usize = 20;
+    /// longest wait for the server to acknowledge a shutdown before it is killed
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+}
+
+impl DataSource<SymbolAt, Vec<String>> for LspContext {
+    /// The definition and references of the symbol at the fragment, as context blocks
+    fn query(&self, query: &SymbolAt) -> Result<Vec<String>> {
+        let program = self.command.first().ok_or("No language server command.")?;
+        let mut server =
+            LanguageServer::start(program, &self.command[1..], &query.root, self.timeout)?;
+        let result = server.symbol_context(query);
+        server.stop();
+        result
+    }
+}
+
+/// a running language server, killed when dropped
+struct LanguageServer {
+    child: Child,
+    stdin: ChildStdin,
+    /// the messages of the server, read on a thread of their own so that waiting for them can
+    /// time out
+    messages: Receiver<std::result::Result<Value, String>>,
+    timeout: Duration,
+    next_id: u64,
+}
+
+impl LanguageServer {
+    fn start(program: &str, args: &[String], root: &Path, timeout: Duration) -> Result<Self> {
+        let mut child = Command::new(program)
+            .args(args)
+            .current_dir(root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Failed to start the language server {program}: {e}"))?;
+
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or("Failed to open stdin of the language server.")?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or("Failed to open stdout of the language server.")?;
+
+        let (sender, messages) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut stdout = BufReader::new(stdout);
+            loop {
+                let message = read_message(&mut stdout).map_err(|e| e.to_string());
+                let failed = message.is_err();
+                // stop once the server is gone or no one is listening anymore
+                if sender.send(message).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let mut server = Self {
+            child,
+            stdin,
+            messages,
+            timeout,
+            next_id: 0,
+        };
+
+        let root = std::fs::canonicalize(root)?;
+        server.request(
+            "initialize",
+            json!({
+                "processId": std::process::id(),
+                "rootUri": file_uri(&root),
+                "capabilities": {},
+            }),
+        )?;
+        server.notify("initialized", json!({}))?;
+
+        Ok(server)
+    }
+
+    /// Ask for the definition and references of the symbol at the fragment
+    fn symbol_context(&mut self, query: &SymbolAt) -> Result<Vec<String>> {
+        let root = std::fs::canonicalize(&query.root)?;
+        let filepath = root.join(&query.fragment.filepath);
+        let content = std::fs::read_to_string(&filepath)?;
+        let uri = file_uri(&filepath);
+
+        self.notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": {
+                    "uri": uri,
+                    "languageId": "rust",
+                    "version": 1,
+                    "text": content,
+                },
+            }),
+        )?;
+
+        // without a column, the symbol is the first word of the fragment
+        let line = query.fragment.line_range.0;
+        let text = content.lines().nth(line).unwrap_or_default();
+        let offset = match query.fragment.column {
+            Some(column) => text
+                .char_indices()
+                .nth(column)
+                .map_or(text.len(), |(offset, _)| offset),
+            None => text.find(|c: char| !c.is_whitespace()).unwrap_or(0),
+        };
+        let position = json!({
+            "textDocument": { "uri": uri },
+            "position": { "line": line, "character": utf16_column(text, offset) },
+        });
+
+        let definitions = locations(&self.request("textDocument/definition", position.clone())?);
+
+        let mut references = position;
+        references["context"] = json!({ "includeDeclaration": false });
+        let references = locations(&self.request("textDocument/references", references)?);
+
+        let mut context = Vec::new();
+        if !definitions.is_empty() {
+            let blocks =
+                show_locations(&root, &definitions, LspContext::MAX_DEFINITION_LINES, query);
+            context.push(format!(
+                "The symbol at line {} of {} is defined as:\n\n{}",
+                line + query.line_base,
+                query.fragment.filepath,
+                blocks.join("\n\n")
+            ));
+        }
+        if !references.is_empty() {
+            let references = &references[..references.len().min(LspContext::MAX_REFERENCES)];
+            context.push(format!(
+                "The symbol at line {} of {} is used in:\n\n{}",
+                line + query.line_base,
+                query.fragment.filepath,
+                show_locations(&root, references, 1, query).join("\n\n")
+            ));
+        }
+
+        Ok(context)
+    }
+
+    /// Send a request and wait for its response, answering whatever the server asks in between
+    fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.request_within(method, params, self.timeout)
+    }
+
+    /// Send a request and wait at most `timeout` for its response
+    fn request_within(&mut self, method: &str, params: Value, timeout: Duration) -> Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let message = match self
+                .messages
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(message) => message?,
+                Err(RecvTimeoutError::Timeout) => {
+                    let error_message =
+                        format!("The language server didn't answer {method} within {timeout:?}.");
+                    return Err(error_message.into());
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err("The language server exited.".into())
+                }
+            };
+            if message.get("method").is_some() {
+                // requests from the server, e.g. for progress tokens, only need an answer
+                if let Some(request_id) = message.get("id") {
+                    self.send(&json!({ "jsonrpc": "2.0", "id": request_id, "result": null }))?;
+                }
+                continue;
+            }
+            if message.get("id") != Some(&json!(id)) {
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                let error_message =
+                    format!("The language server failed to answer {method}: {error}");
+                return Err(error_message.into());
+            }
+
+            return Ok(message.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> Result<()> {
+        self.send(&json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    fn send(&mut self, message: &Value) -> Result<()> {
+        let body = serde_json::to_string(message)?;
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        Ok(self.stdin.flush()?)
+    }
+
+    /// Ask the server to shut down, killing it if it doesn't
+    fn stop(mut self) {
+        let _ = self.request_within("shutdown", Value::Null, LspContext::SHUTDOWN_TIMEOUT);
+        let _ = self.notify("exit", Value::Null);
+    }
+}
+
+impl Drop for LanguageServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Read a message of the language server protocol: headers, then a JSON body
+fn read_message(reader: &mut impl BufRead) -> Result<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Err("The language server exited.".into());
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse::<usize>()?);
+        }
+    }
+
+    let mut body = vec![0; length.ok_or("The language server sent no Content-Length.")?];
+    reader.read_exact(&mut body)?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// The column of the byte `offset` of `line` in UTF-16 code units, which is how the language
+/// server protocol counts characters by default
+fn utf16_column(line: &str, offset: usize) -> usize {
+    line.char_indices()
+        .take_while(|(i, _)| *i < offset)
+        .map(|(_, c)| c.len_utf16())
+        .sum()
+}
+
+fn file_uri(path: &Path) -> String {
+    format!("file://{}", path.display())
+}
+
+/// The files and line ranges of a `Location`, `Location[]`, or `LocationLink[]` result
+fn locations(result: &Value) -> Vec<(PathBuf, usize, usize)> {
+    let results = match result {
+        Value::Array(results) => results.iter().collect(),
+        Value::Null => Vec::new(),
+        result => vec![result],
+    };
+
+    results
+        .into_iter()
+        .filter_map(|location| {
+            let uri = location
+                .get("targetUri")
+                .or(location.get("uri"))?
+                .as_str()?;
+            let range = location.get("targetRange").or(location.get("range"))?;
+            let line = |end: &str| range.get(end)?.get("line")?.as_u64().map(|l| l as usize);
+            Some((
+                PathBuf::from(uri.strip_prefix("file://")?),
+                line("start")?,
+                line("end")?,
+            ))
+        })
+        .collect()
+}
+
+/// Number the lines at each location, showing at most `max_lines` of each
+fn show_locations(
+    root: &Path,
+    locations: &[(PathBuf, usize, usize)],
+    max_lines: usize,
+    query: &SymbolAt,
+) -> Vec<String> {
+    locations
+        .iter()
+        .filter_map(|(path, start, end)| {
+            let content = std::fs::read_to_string(path).ok()?;
+            let lines = content.lines().collect::<Vec<_>>();
+            let end = (*end)
+                .min(start + max_lines - 1)
+                .min(lines.len().checked_sub(1)?);
+            if *start > end {
+                return None;
+            }
+            Some(format!(
+                "{}\n>>>>\n{}\n<<<<",
+                path.strip_prefix(root).unwrap_or(path).display(),
+                numbered_lines(&lines, *start, end, query.line_base)
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_count_utf16_units() {
+        assert_eq!(utf16_column("let x = 1;", 4), 4);
+        // `é` is two bytes but one unit, `𝄞` four bytes but two units
+        assert_eq!(utf16_column("é = 1", "é ".len()), 2);
+        assert_eq!(utf16_column("\"𝄞\".len()", "\"𝄞\"".len()), 4);
+    }
+
+    #[test]
+    fn unresponsive_server_times_out() {
+        let root = std::env::temp_dir().join(format!("rakune-lsp-timeout-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let context = LspContext {
+            command: vec!["sleep".to_string(), "30".to_string()],
+            timeout: Duration::from_millis(200),
+        };
+        let query = SymbolAt {
+            root,
+            fragment: Fragment {
+                filepath: "src/lib.rs".to_string(),
+                line_range: (0, 0),
+                column: None,
+            },
+            line_base: 0,
+        };
+
+        let started = Instant::now();
+        let error = context.query(&query).unwrap_err();
+
+        assert!(error.to_string().contains("didn't answer initialize"));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,386 @@
+use regex::Regex;
+use serde_json::json;
+
+use crate::repository::{Fragment, ScoredTransformation, Transformation};
+use crate::Result;
+
+/// turns a raw LLM response into the transformations it describes
+pub trait TransformationParser {
+    fn parse(&self, response: &str) -> Result<Vec<Transformation>>;
+
+    /// Parse like [`TransformationParser::parse`], along with the confidence the response gave
+    /// each transformation. Formats without one leave every score unset
+    fn parse_scored(&self, response: &str) -> Result<Vec<ScoredTransformation>> {
+        Ok(self.parse(response)?.into_iter().map(Into::into).collect())
+    }
+}
+
+/// parses the `UpdateFragment:` and `InsertFragment:` blocks requested by
+/// [`crate::prompt::Prompter::template_code`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TemplateParser;
+
+impl TransformationParser for TemplateParser {
+    fn parse(&self, response: &str) -> Result<Vec<Transformation>> {
+        let update = Regex::new(
+            "filepath: (.*?),?\n.*start_line: (.*?),?\n.*(end_line|line_count): (.*?),?\n.*content: ([\\s\\S]*?)```",
+        )
+        .expect("Regex failed to compile.");
+        let insert =
+            Regex::new("filepath: (.*?),?\n.*line_no: (.*?),?\n.*content: ([\\s\\S]*?)```")
+                .expect("Regex failed to compile.");
+
+        let updates = update.captures_iter(response).map(|c| {
+            let position = c.get(0).map_or(0, |m| m.start());
+            let (_, [filepath, start, field, end, content]) = c.extract();
+            let transformation = parse_line("start_line", start).and_then(|start_line| {
+                // some models count the replaced lines rather than naming the last one
+                let fragment = match field {
+                    "line_count" => {
+                        Fragment::from_start_count(filepath, start_line, parse_line(field, end)?)?
+                    }
+                    _ => Fragment {
+                        filepath: filepath.into(),
+                        line_range: (start_line, parse_line(field, end)?),
+                        column: None,
+                    },
+                };
+                Ok(Transformation::UpdateFragment {
+                    fragment,
+                    updated_lines: content.lines().map(|s| s.to_string()).collect(),
+                    expected: None,
+                })
+            });
+            (position, transformation)
+        });
+        let inserts = insert.captures_iter(response).map(|c| {
+            let position = c.get(0).map_or(0, |m| m.start());
+            let (_, [filepath, line_no, content]) = c.extract();
+            let transformation =
+                parse_line("line_no", line_no).map(|line_no| Transformation::InsertFragment {
+                    filepath: filepath.into(),
+                    line_no,
+                    content: content.lines().map(|s| s.to_string()).collect(),
+                });
+            (position, transformation)
+        });
+
+        // blocks of both kinds are applied in the order the model wrote them
+        let mut blocks = updates.chain(inserts).collect::<Vec<_>>();
+        blocks.sort_by_key(|(position, _)| *position);
+
+        let transformations = blocks
+            .into_iter()
+            .map(|(_, transformation)| transformation)
+            .collect::<Result<Vec<_>>>()?;
+
+        if transformations.is_empty() {
+            return Err(ParseError::diagnose(response).into());
+        }
+
+        Ok(transformations)
+    }
+}
+
+/// Read the value of a line number field of the template
+fn parse_line(field: &str, value: &str) -> Result<usize> {
+    value.trim().parse().map_err(|_| {
+        format!(
+            "`{field}` should be a line number, but was `{}`.",
+            value.trim()
+        )
+        .into()
+    })
+}
+
+/// why a response didn't contain any transformation
+#[derive(Debug)]
+pub struct ParseError {
+    /// the start of the offending input
+    pub snippet: String,
+    /// the first template field that couldn't be extracted from a partial match
+    pub missing_field: Option<&'static str>,
+    /// 1-based line and column where the partial match begins
+    pub position: Option<(usize, usize)>,
+}
+
+impl ParseError {
+    /// maximum number of characters of the response kept in the snippet
+    const SNIPPET_CHARS: usize = 200;
+
+    /// Work out how far a response got through the `UpdateFragment` template
+    pub fn diagnose(response: &str) -> Self {
+        let fields = [
+            ("filepath", "filepath: "),
+            ("start_line", "start_line: \\d+"),
+            ("end_line", "(?:end_line|line_count): \\d+"),
+            ("content", "content: "),
+            ("content", "content: [\\s\\S]*?```"),
+        ];
+
+        let start = response.find("filepath:");
+        let partial = &response[start.unwrap_or(0)..];
+
+        let missing_field = fields
+            .iter()
+            .find(|(_, field)| {
+                !Regex::new(field)
+                    .expect("Regex failed to compile.")
+                    .is_match(partial)
+            })
+            .map(|(name, _)| *name);
+
+        let position = start.map(|start| {
+            let before = &response[..start];
+            let line = before.matches('\n').count() + 1;
+            let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+            (line, column)
+        });
+
+        Self {
+            snippet: partial.chars().take(Self::SNIPPET_CHARS).collect(),
+            missing_field,
+            position,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to parse transformation")?;
+        if let Some((line, column)) = self.position {
+            write!(f, " at line {line}, column {column}")?;
+        }
+        match self.missing_field {
+            Some(field) => write!(f, ": could not extract `{field}` from")?,
+            None => write!(f, " from")?,
+        }
+        write!(f, ":\n{}", self.snippet)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// parses a JSON array of transformations tagged by their `kind`, for example
+/// `[{"kind": "CreateFile", "path": "src/new.rs"}]`. a single object is also accepted.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonParser;
+
+impl JsonParser {
+    /// A JSON schema for the arrays of transformations this parser reads, for backends that can
+    /// constrain their output to one, like [`crate::llm::Ollama::format`]
+    pub fn schema() -> serde_json::Value {
+        let variants = Self::variant_schemas()
+            .into_iter()
+            .map(|(kind, mut schema)| {
+                schema["properties"]["kind"] = json!({ "const": kind });
+                // optional, so models that can't judge their own edits needn't make a score up
+                schema["properties"]["score"] =
+                    json!({ "type": "number", "minimum": 0, "maximum": 1 });
+                if let Some(required) = schema["required"].as_array_mut() {
+                    required.insert(0, json!("kind"));
+                }
+                schema
+            })
+            .collect::<Vec<_>>();
+
+        json!({ "type": "array", "items": { "anyOf": variants } })
+    }
+
+    /// The kind of every transformation paired with a JSON schema of its fields, leaving out the
+    /// `kind` tag itself. Backends with tool calling offer each of them as a tool
+    pub fn variant_schemas() -> Vec<(&'static str, serde_json::Value)> {
+        let string = json!({ "type": "string" });
+        let line = json!({ "type": "integer", "minimum": 0 });
+        let lines = json!({ "type": "array", "items": string });
+        let object = |properties: serde_json::Value| {
+            let required = properties
+                .as_object()
+                .map(|properties| properties.keys().cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            json!({ "type": "object", "properties": properties, "required": required })
+        };
+
+        vec![
+            (
+                "UpdateFragment",
+                object(json!({
+                    "fragment": object(json!({
+                        "filepath": string,
+                        "line_range": { "type": "array", "items": line, "minItems": 2, "maxItems": 2 },
+                    })),
+                    "updated_lines": lines,
+                })),
+            ),
+            (
+                "UpdateSpan",
+                object(json!({
+                    "fragment": object(json!({
+                        "filepath": string,
+                        "line_range": { "type": "array", "items": line, "minItems": 2, "maxItems": 2 },
+                    })),
+                    "columns": { "type": "array", "items": line, "minItems": 2, "maxItems": 2 },
+                    "replacement": string,
+                })),
+            ),
+            (
+                "InsertFragment",
+                object(json!({ "filepath": string, "line_no": line, "content": lines })),
+            ),
+            (
+                "ReplaceMatching",
+                object(json!({ "filepath": string, "pattern": string, "content": lines })),
+            ),
+            (
+                "RenameSymbol",
+                object(json!({ "old": string, "new": string })),
+            ),
+            ("CreateFile", object(json!({ "path": string }))),
+            ("DeleteFile", object(json!({ "path": string }))),
+            ("MoveFile", object(json!({ "old": string, "new": string }))),
+            ("ApplyPatch", object(json!({ "patch": string }))),
+            ("Noop", object(json!({}))),
+        ]
+    }
+}
+
+impl TransformationParser for JsonParser {
+    fn parse(&self, response: &str) -> Result<Vec<Transformation>> {
+        Ok(self
+            .parse_scored(response)?
+            .into_iter()
+            .map(|scored| scored.transformation)
+            .collect())
+    }
+
+    /// Read the optional `score` next to the `kind` of each transformation
+    fn parse_scored(&self, response: &str) -> Result<Vec<ScoredTransformation>> {
+        // models tend to wrap json in prose or code fences, so only look at the outermost value
+        let start = response
+            .find(['[', '{'])
+            .ok_or("No JSON value in the response.")?;
+        let end = response
+            .rfind([']', '}'])
+            .ok_or("No JSON value in the response.")?;
+        let json = &response[start..=end];
+
+        match json.starts_with('[') {
+            true => Ok(serde_json::from_str(json)?),
+            false => Ok(vec![serde_json::from_str(json)?]),
+        }
+    }
+}
+
+/// parses the ```` ```diff ```` blocks requested by [`crate::prompt::Prompter::template_patch`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PatchParser;
+
+impl TransformationParser for PatchParser {
+    fn parse(&self, response: &str) -> Result<Vec<Transformation>> {
+        let re =
+            Regex::new("```(?:diff|patch)?\n([\\s\\S]*?)```").expect("Regex failed to compile.");
+
+        Ok(re
+            .captures_iter(response)
+            .map(|c| c.extract())
+            .filter(|(_, [patch])| patch.contains("@@"))
+            .map(|(_, [patch])| Transformation::ApplyPatch {
+                patch: patch.to_string(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::TransformationKind;
+
+    #[test]
+    fn non_numeric_line_is_an_error() {
+        let response = "```\nUpdateFragment:\n    filepath: src/a.rs\n    start_line: one\n    end_line: 2\n    content: x\n```";
+
+        let error = TemplateParser.parse(response).unwrap_err().to_string();
+
+        assert!(error.contains("start_line"));
+        assert!(error.contains("one"));
+    }
+
+    #[test]
+    fn non_numeric_insert_line_is_an_error() {
+        let response =
+            "```\nInsertFragment:\n    filepath: src/a.rs\n    line_no: last\n    content: x\n```";
+
+        let error = TemplateParser.parse(response).unwrap_err().to_string();
+
+        assert!(error.contains("line_no"));
+    }
+
+    #[test]
+    fn update_and_insert_blocks_keep_their_order() {
+        let response = "```\nInsertFragment:\n    filepath: src/a.rs\n    line_no: 0\n    content: use std::fs;\n```\n\
+            ```\nUpdateFragment:\n    filepath: src/a.rs\n    start_line: 3\n    end_line: 4\n    content: fs::read(p)\n```\n\
+            ```\nInsertFragment:\n    filepath: src/b.rs\n    line_no: 7\n    content: // done\n```";
+
+        let transformations = TemplateParser.parse(response).unwrap();
+
+        let kinds = transformations
+            .iter()
+            .map(Transformation::kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                TransformationKind::InsertFragment,
+                TransformationKind::UpdateFragment,
+                TransformationKind::InsertFragment,
+            ]
+        );
+        assert!(matches!(
+            &transformations[1],
+            Transformation::UpdateFragment { fragment, updated_lines, .. }
+                if fragment.line_range == (3, 4) && updated_lines == &["fs::read(p)"]
+        ));
+        assert!(matches!(
+            &transformations[2],
+            Transformation::InsertFragment { filepath, line_no: 7, .. } if filepath == "src/b.rs"
+        ));
+    }
+
+    /// The fragment of the single update in `response`
+    fn updated_fragment(response: &str) -> Fragment {
+        match TemplateParser.parse(response).unwrap().as_slice() {
+            [Transformation::UpdateFragment { fragment, .. }] => fragment.clone(),
+            transformations => panic!("expected a single update, got {transformations:?}"),
+        }
+    }
+
+    #[test]
+    fn line_count_and_end_line_give_the_same_fragment() {
+        let end = "```\nUpdateFragment:\n    filepath: src/a.rs\n    start_line: 3\n    end_line: 5\n    content: x\n```";
+        let count = "```\nUpdateFragment:\n    filepath: src/a.rs\n    start_line: 3\n    line_count: 3\n    content: x\n```";
+
+        assert_eq!(updated_fragment(end), updated_fragment(count));
+        assert_eq!(updated_fragment(count).line_range, (3, 5));
+    }
+
+    #[test]
+    fn json_score_is_optional() {
+        let response = r#"Here you go:
+```json
+[
+  {"kind": "CreateFile", "path": "a.rs", "score": 0.75},
+  {"kind": "DeleteFile", "path": "b.rs"}
+]
+```"#;
+
+        let scored = JsonParser.parse_scored(response).unwrap();
+
+        let scores = scored.iter().map(|scored| scored.score).collect::<Vec<_>>();
+        assert_eq!(scores, [Some(0.75), None]);
+        assert!(matches!(
+            &scored[0].transformation,
+            Transformation::CreateFile { path } if path == "a.rs"
+        ));
+    }
+}
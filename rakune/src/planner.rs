@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+use std::path::Path;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{Planner, Prompt, Result, Step};
+
+/// carries out the steps of a plan one after another, in the order they were written
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LinearPlanner {
+    pub pending: VecDeque<Step>,
+    pub done: Vec<Step>,
+}
+
+impl LinearPlanner {
+    /// Mark the next pending step as carried out
+    pub fn advance(&mut self) {
+        if let Some(step) = self.pending.pop_front() {
+            self.done.push(step);
+        }
+    }
+
+    /// Write the pending and carried out steps to `path`, so that a later run can resume
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        Ok(std::fs::write(path, serde_json::to_string_pretty(self)?)?)
+    }
+
+    /// Resume a plan written by [`LinearPlanner::save`]
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+impl Planner for LinearPlanner {
+    fn completed(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn next_pending(&self) -> Step {
+        self.pending.front().cloned().unwrap_or_default()
+    }
+
+    /// Each non-empty line of the prompt is a step, with any list markers removed
+    fn from_prompt(p: &Prompt) -> Self {
+        let marker = Regex::new("^\\s*(?:\\d+[.)]|[-*])?\\s*").expect("Regex failed to compile.");
+
+        let pending = p
+            .lines()
+            .map(|line| marker.replace(line, "").trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect();
+
+        Self {
+            pending,
+            done: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_done_plan_resumes() {
+        let mut plan = LinearPlanner::from_prompt("1. add a flag\n2. parse it\n- document it\n");
+        plan.advance();
+
+        let path = std::env::temp_dir().join(format!("rakune-plan-{}.json", std::process::id()));
+        plan.save(&path).unwrap();
+        let resumed = LinearPlanner::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!resumed.completed());
+        assert_eq!(resumed.next_pending(), "parse it");
+        assert_eq!(resumed.done, vec!["add a flag".to_string()]);
+        assert_eq!(resumed.pending.len(), 2);
+    }
+}
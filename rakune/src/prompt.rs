@@ -0,0 +1,366 @@
+use std::collections::HashSet;
+
+use crate::repository::TransformationKind;
+
+/// the template the model is asked to describe code edits with
+pub const UPDATE_FRAGMENT_FORMAT: &str = r#"```
+UpdateFragment:
+    filepath: the path to the file being changes (string)
+    start_line: the starting line to update (int)
+    end_line: the ending line to update (int)
+    content: the code the replace within the lines (string)
+```"#;
+
+/// the template the model is asked to describe added code with
+pub const INSERT_FRAGMENT_FORMAT: &str = r#"```
+InsertFragment:
+    filepath: the path to the file being changed (string)
+    line_no: the line to insert the code before, or the number of lines to append at the end (int)
+    content: the code to insert (string)
+```"#;
+
+/// a request shown to the model along with the answer expected for it
+pub struct Example {
+    pub request: &'static str,
+    /// the lines of code shown as context, numbered when rendered
+    pub code: &'static [&'static str],
+    /// the answer, where `{l0}` to `{l3}` stand for the numbers of the first four lines
+    pub answer: &'static str,
+    /// the kind of transformation the answer describes
+    pub kind: TransformationKind,
+}
+
+/// templates used to phrase requests to the LLM
+pub struct Prompter;
+
+impl Prompter {
+    /// the few-shot examples of code edits, from the most to the least instructive
+    pub const EXAMPLES: &'static [Example] = &[
+        Example {
+            request: "Update the function foo to print \"hello!\"",
+            code: &["fn foo() {", "    println!(\"chili dogs\")", "}"],
+            answer: r#"```
+UpdateFragment:
+    filepath: src/hello.rs
+    start_line: {l1}
+    end_line: {l1}
+    content: println!("hello!")
+```"#,
+            kind: TransformationKind::UpdateFragment,
+        },
+        Example {
+            request: "Remove the uneeded code in add_5().",
+            code: &[
+                "fn add_5(x: u8) -> u8 {",
+                "  let ans = x + 5;",
+                "  return ans;",
+                "}",
+            ],
+            answer: r#"```
+UpdateFragment:
+    filepath: src/addition.rs
+    start_line: {l1}
+    end_line: {l2}
+    content: return x + 5;
+```"#,
+            kind: TransformationKind::UpdateFragment,
+        },
+        Example {
+            request: "Add a function that subtracts 5 after add_5().",
+            code: &["fn add_5(x: u8) -> u8 {", "  x + 5", "}"],
+            answer: r#"```
+InsertFragment:
+    filepath: src/addition.rs
+    line_no: {l3}
+    content: fn sub_5(x: u8) -> u8 {
+  x - 5
+}
+```"#,
+            kind: TransformationKind::InsertFragment,
+        },
+    ];
+
+    /// Everything needed to phrase a code edit for a model, ahead of the request itself
+    pub fn template_code(
+        language: &str,
+        line_base: usize,
+        examples: usize,
+        allowed: &HashSet<TransformationKind>,
+        p: &str,
+    ) -> String {
+        format!(
+            "{}\n{}",
+            Self::system_code(language, line_base, examples, allowed),
+            p
+        )
+    }
+
+    /// The persona and output format for code edits, suitable for a system prompt, followed by
+    /// up to `examples` of [`Prompter::EXAMPLES`]. The examples number lines starting from
+    /// `line_base`, like the context shown to the model. Templates and examples of kinds that
+    /// aren't `allowed` are left out
+    pub fn system_code(
+        language: &str,
+        line_base: usize,
+        examples: usize,
+        allowed: &HashSet<TransformationKind>,
+    ) -> String {
+        let mut system = format!("You are a {} programmer.\n", language);
+        system += &Self::templates(allowed);
+
+        let examples = Self::EXAMPLES
+            .iter()
+            .filter(|example| allowed.contains(&example.kind))
+            .take(examples)
+            .map(|example| Self::template_example_edit(example, line_base))
+            .collect::<Vec<_>>();
+        if !examples.is_empty() {
+            system += "\n## Here are a couple of examples:\n\n";
+            system += &examples.join("\n---\n\n");
+        }
+
+        system
+    }
+
+    /// The templates of the `allowed` kinds of edit, for the model to describe its edits with
+    fn templates(allowed: &HashSet<TransformationKind>) -> String {
+        let mut templates = String::new();
+
+        let update = allowed.contains(&TransformationKind::UpdateFragment);
+        let insert = allowed.contains(&TransformationKind::InsertFragment);
+        if update {
+            templates += &format!(
+                "\nPlease use the following template to describe where to update the code:\n\n{}\n",
+                UPDATE_FRAGMENT_FORMAT
+            );
+        }
+        match (update, insert) {
+            (true, true) => {
+                templates += &format!(
+                    "\nTo add code without replacing any lines, use this template instead:\n\n{}\n",
+                    INSERT_FRAGMENT_FORMAT
+                )
+            }
+            (false, true) => {
+                templates += &format!(
+                    "\nPlease use the following template to describe where to add code:\n\n{}\n",
+                    INSERT_FRAGMENT_FORMAT
+                )
+            }
+            _ => {}
+        }
+        if !templates.is_empty() {
+            templates += "\nDo NOT provide any extra content beyond this template.\n";
+        }
+
+        templates
+    }
+
+    /// An example rendered with the lines of its code numbered from `line_base`
+    fn template_example_edit(example: &Example, line_base: usize) -> String {
+        let code = example
+            .code
+            .iter()
+            .enumerate()
+            .map(|(i, line)| format!("{} {}", line_base + i, line))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let answer = (0..4).fold(example.answer.to_string(), |answer, i| {
+            answer.replace(&format!("{{l{i}}}"), &(line_base + i).to_string())
+        });
+
+        format!(
+            "{}\n\n>>>>\n{}\n<<<<\n\n{}\n",
+            example.request, code, answer
+        )
+    }
+
+    /// A recent change to the repository, shown as an example of the style its code is written in
+    pub fn template_example(diff: &str) -> String {
+        format!(
+            r#"
+## Here is a recent change to this repository. Write code in the same style:
+
+```diff
+{}
+```
+"#,
+            diff.trim_end()
+        )
+    }
+
+    pub fn template_patch(language: &str, p: &str) -> String {
+        format!(
+            r#"You are a {} programmer. {}
+
+Please describe the change as a unified diff against the current code, with paths relative to the
+root of the repository and at least 3 lines of unchanged context around each hunk:
+
+```diff
+--- a/path/to/file
++++ b/path/to/file
+@@ -start,count +start,count @@
+ context
+-removed line
++added line
+ context
+```
+
+Do NOT provide any extra content beyond the diff.
+"#,
+            language, p
+        )
+    }
+
+    /// Follow-up to a reply that couldn't be parsed, explaining what was wrong with it. Only the
+    /// templates of the `allowed` kinds of edit are repeated, like in [`Prompter::system_code`]
+    pub fn template_retry(
+        answer: &str,
+        error: &str,
+        allowed: &HashSet<TransformationKind>,
+    ) -> String {
+        let templates = Self::templates(allowed);
+        let again = match templates.is_empty() {
+            true => "",
+            false => " Here's the exact format again:",
+        };
+        format!(
+            r#"### Your last reply was:
+
+{}
+
+Your last reply didn't match the template ({}).{}
+{}"#,
+            answer, error, again, templates
+        )
+    }
+
+    /// Ask for a commit message following Conventional Commits, with one of `types`, for the
+    /// changes summarized in `p`
+    pub fn template_conventional_commit(types: &[&str], p: &str) -> String {
+        format!(
+            r#"write a commit message for the following changes that follows Conventional Commits:
+
+<type>(<scope>): <subject>
+
+<body>
+
+- <type> is one of: {}
+- (<scope>) is optional and names the part of the code that changed, e.g. (parser)
+- <subject> is a short imperative summary in lower case, without a trailing period
+- <body> is optional and goes after a blank line
+
+Do NOT provide any extra content beyond the commit message.
+
+{}"#,
+            types.join(", "),
+            p
+        )
+    }
+
+    /// Follow-up to a commit message that didn't follow Conventional Commits
+    pub fn template_conventional_retry(answer: &str, error: &str) -> String {
+        format!(
+            "### Your last reply was:\n\n{}\n\nIt isn't a valid commit message ({}). Reply with \
+            the commit message only.",
+            answer, error
+        )
+    }
+
+    pub fn template_debug(p: &str) -> String {
+        format!("fix this build error:\n\n{}", p)
+    }
+
+    pub fn template_lint(p: &str) -> String {
+        format!("fix this lint:\n\n{}", p)
+    }
+
+    /// Say which stage of a [`crate::builder::Pipeline`] reported the problem `p`
+    pub fn template_stage(stage: &str, p: &str) -> String {
+        format!("the `{}` stage of the build failed.\n\n{}", stage, p)
+    }
+
+    pub fn template_test_failure(test: &str, p: &str) -> String {
+        format!(
+            "fix the code so that the test `{}` passes. it failed with:\n\n{}",
+            test, p
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all() -> HashSet<TransformationKind> {
+        TransformationKind::ALL.iter().copied().collect()
+    }
+
+    /// The number of [`Prompter::EXAMPLES`] rendered in `system`
+    fn shown_examples(system: &str) -> usize {
+        Prompter::EXAMPLES
+            .iter()
+            .filter(|example| system.contains(example.request))
+            .count()
+    }
+
+    #[test]
+    fn system_prompt_renders_the_requested_examples() {
+        for count in 1..=Prompter::EXAMPLES.len() {
+            let system = Prompter::system_code("Rust", 0, count, &all());
+
+            assert_eq!(shown_examples(&system), count);
+        }
+    }
+
+    #[test]
+    fn zero_examples_leaves_them_out() {
+        let system = Prompter::system_code("Rust", 0, 0, &all());
+
+        assert_eq!(shown_examples(&system), 0);
+        assert!(!system.contains("examples"));
+        assert!(system.contains(UPDATE_FRAGMENT_FORMAT));
+    }
+
+    #[test]
+    fn examples_are_capped_at_those_there_are() {
+        let system = Prompter::system_code("Rust", 0, 100, &all());
+
+        assert_eq!(shown_examples(&system), Prompter::EXAMPLES.len());
+    }
+
+    #[test]
+    fn retry_repeats_only_the_allowed_templates() {
+        let updates = HashSet::from([TransformationKind::UpdateFragment]);
+
+        let retry = Prompter::template_retry("oops", "no template", &updates);
+
+        assert!(retry.contains("oops"));
+        assert!(retry.contains("(no template)"));
+        assert!(retry.contains(UPDATE_FRAGMENT_FORMAT));
+        assert!(!retry.contains(INSERT_FRAGMENT_FORMAT));
+    }
+
+    #[test]
+    fn retry_repeats_the_templates_of_the_system_prompt() {
+        let retry = Prompter::template_retry("oops", "no template", &all());
+        let system = Prompter::system_code("Rust", 0, 0, &all());
+
+        assert!(retry.ends_with(&Prompter::templates(&all())));
+        assert!(system.contains(&Prompter::templates(&all())));
+    }
+
+    #[test]
+    fn no_template_is_promised_without_an_allowed_edit() {
+        let allowed = HashSet::from([TransformationKind::RenameSymbol]);
+
+        let system = Prompter::system_code("Rust", 0, 2, &allowed);
+        let retry = Prompter::template_retry("answer", "error", &allowed);
+
+        for prompt in [&system, &retry] {
+            assert!(!prompt.contains("beyond this template"));
+            assert!(!prompt.contains("exact format again"));
+        }
+    }
+}
@@ -1,208 +1,3462 @@
+use std::cmp::Reverse;
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::io::Write;
-use std::process::Command;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use regex::Regex;
+use regex::{NoExpand, Regex};
+use serde::{Deserialize, Serialize};
 
-use crate::Diff;
+use crate::history::{ConversationLog, SimilarTo};
+use crate::ignore::{glob_match, is_ignored_by, parse_ignore_file, IGNORE_FILE};
+#[cfg(feature = "lsp")]
+use crate::lsp::{LspContext, SymbolAt};
+use crate::DataSource;
 use crate::Result;
+use crate::{Diff, RakuneError};
+
+/// manifest files that mark the root of a project, paired with the language they imply
+const MANIFESTS: &[(&str, &str)] = &[
+    ("Cargo.toml", "Rust"),
+    ("go.mod", "Go"),
+    ("pyproject.toml", "Python"),
+    ("setup.py", "Python"),
+    ("requirements.txt", "Python"),
+    ("tsconfig.json", "TypeScript"),
+    ("package.json", "JavaScript"),
+    ("pom.xml", "Java"),
+    ("build.gradle", "Java"),
+    ("Gemfile", "Ruby"),
+    ("CMakeLists.txt", "C++"),
+];
+
+/// formatters run by [`GitRepository::format_file`], by the language of the project and the
+/// extension of the files they handle. the path of the file is appended to the command
+const FORMATTERS: &[(&str, &str, &[&str])] = &[
+    ("Rust", "rs", &["rustfmt", "--edition", "2021"]),
+    ("Go", "go", &["gofmt", "-w"]),
+    ("Python", "py", &["black", "--quiet"]),
+    ("TypeScript", "ts", &["prettier", "--write"]),
+    ("JavaScript", "js", &["prettier", "--write"]),
+];
+
+/// extensions of the files that builds of each language read, besides the manifests
+const SOURCE_EXTENSIONS: &[(&str, &[&str])] = &[
+    ("Rust", &["rs", "toml", "lock"]),
+    ("Go", &["go", "mod", "sum"]),
+    ("Python", &["py", "pyi", "cfg", "toml"]),
+    ("TypeScript", &["ts", "tsx", "js", "jsx", "json"]),
+    ("JavaScript", &["js", "jsx", "mjs", "cjs", "json"]),
+    ("Java", &["java", "kt", "gradle", "xml", "properties"]),
+    ("Ruby", &["rb", "gemspec", "lock"]),
+    ("C++", &["c", "cc", "cpp", "cxx", "h", "hh", "hpp", "cmake"]),
+];
 
 #[derive(Clone, Default)]
-pub struct GitRepository;
+pub struct GitRepository {
+    /// revision that context is read from. the working tree is used when unset
+    pub revision: Option<String>,
+    /// past exchanges with the agent, used as temporal context
+    pub history: Option<ConversationLog>,
+    /// number of lines before and after a fragment to include as spatial context
+    pub context_window: usize,
+    /// the number shown for the first line of a file in context, usually 0 or 1. fragments are
+    /// always 0-based, so line numbers written by the model are converted back with
+    /// [`Transformation::into_zero_based`]
+    pub line_base: usize,
+    /// gitignore-style globs of files left out of context gathering and repository-wide
+    /// operations, on top of `.gitignore` and `.git/` which are always respected. globs starting
+    /// with `!` re-include paths, see [`GitRepository::read_ignore_file`]
+    pub ignore_globs: Vec<String>,
+    /// directory that git and file operations run from. relative paths resolve against it,
+    /// and the current directory is used when empty
+    pub root: PathBuf,
+    /// set when the repository is a temporary worktree made by [`GitRepository::in_worktree`]
+    pub worktree: Option<Worktree>,
+    /// run the formatter of the project's language on each file edited by a transformation, so
+    /// that the model's indentation doesn't have to match
+    pub post_transform_format: bool,
+    /// ask a language server about the symbols of rust fragments for context, instead of
+    /// searching calls with tree-sitter
+    #[cfg(feature = "lsp")]
+    pub lsp: Option<LspContext>,
+    /// largest file, in bytes, that is read or edited. keeps lockfiles and generated code out of
+    /// reach of the model. unlimited when unset
+    pub max_file_size: Option<u64>,
+    /// gitignore-style globs of files exempt from `max_file_size`
+    pub large_files: Vec<String>,
+    /// shift the lines of an update to the indentation of the first line they replace, in the
+    /// tabs or spaces the file is indented with, see [`reindent`]
+    pub reindent_updates: bool,
+    /// show the commit that last changed each line of a fragment as context, see
+    /// [`GitRepository::blame_context`]. costs a `git blame` per fragment
+    pub include_blame: bool,
+    /// refuse files reached through a symlink, even one that stays inside the repository.
+    /// files that resolve outside of it are refused either way, see [`resolve_within`]
+    pub deny_symlinks: bool,
+}
+
+/// a temporary `git worktree` checked out on a throwaway branch
+#[derive(Clone, Debug)]
+pub struct Worktree {
+    pub branch: String,
+    /// revision the worktree was created from
+    pub base: String,
+    /// root of the repository the worktree was created from
+    pub origin: PathBuf,
+}
 
 impl GitRepository {
-    /// Edit the state of a respository using a given agent capability
-    pub fn transform(&mut self, transformation: &Transformation) -> Result<()> {
+    /// most call sites and definitions each shown as context of a function
+    const MAX_CALL_SITES: usize = 3;
+    /// longest part of a called function's definition shown as context
+    const MAX_DEFINITION_LINES: usize = 20;
+
+    /// Open the git repository that `path` is in, rooted at the top of its working tree and
+    /// with the globs of its `.rakuneignore`. Fails when `path` isn't inside a working tree,
+    /// so that running in the wrong directory is caught before any edit is made
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let probe = GitRepository {
+            root: path.to_path_buf(),
+            ..Default::default()
+        };
+
+        let inside = probe.git(&["rev-parse", "--is-inside-work-tree"]);
+        if !inside.is_ok_and(|inside| inside.trim() == "true") {
+            let error_message = format!(
+                "{} is not inside a git working tree. Run rakune from a git repository.",
+                path.canonicalize()
+                    .unwrap_or_else(|_| path.to_path_buf())
+                    .display()
+            );
+            return Err(error_message.into());
+        }
+
+        let mut repository = GitRepository {
+            root: PathBuf::from(probe.git(&["rev-parse", "--show-toplevel"])?.trim()),
+            ..Default::default()
+        };
+        repository.read_ignore_file()?;
+
+        Ok(repository)
+    }
+
+    /// Resolve a path relative to the root of the repository
+    pub fn path(&self, filepath: impl AsRef<Path>) -> PathBuf {
+        self.root.join(filepath)
+    }
+
+    /// Resolve a path relative to the root of the repository to the file it lands on, refusing
+    /// it when that is outside the repository, see [`resolve_within`]
+    pub fn resolve(&self, filepath: &str) -> Result<PathBuf> {
+        resolve_within(&self.root, filepath, self.deny_symlinks)
+    }
+
+    /// The path of a file relative to the root as git lists it, e.g. `src/a.rs` for
+    /// `./src/../src/a.rs`, so that paths written by the model compare equal to those of git
+    pub fn relative_path(&self, filepath: &str) -> Result<String> {
+        let root = match self.root.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => &self.root,
+        };
+        let resolved = self.resolve(filepath)?;
+        let relative = resolved.strip_prefix(root.canonicalize()?)?;
+        Ok(relative.to_string_lossy().into_owned())
+    }
+
+    /// A command that runs from the root of the repository
+    pub fn command(&self, program: &str) -> Command {
+        let mut command = Command::new(program);
+        if !self.root.as_os_str().is_empty() {
+            command.current_dir(&self.root);
+        }
+        command
+    }
+
+    /// Run a git subcommand, returning its stdout or an error carrying stderr if it exits
+    /// unsuccessfully
+    fn git<S: AsRef<OsStr>>(&self, args: &[S]) -> Result<String> {
+        let output = self.command("git").args(args).output()?;
+        check_output(args, output)
+    }
+
+    /// Run a git subcommand that reads `input` from stdin
+    fn git_with_input<S: AsRef<OsStr>>(&self, args: &[S], input: &str) -> Result<String> {
+        let mut child = self
+            .command("git")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        child
+            .stdin
+            .take()
+            .ok_or("Failed to open stdin of git.")?
+            .write_all(input.as_bytes())?;
+
+        check_output(args, child.wait_with_output()?)
+    }
+
+    /// Check out a copy of the repository in a temporary `git worktree` on a throwaway branch.
+    /// Transformations, builds, and commits made through the returned repository happen there,
+    /// leaving this working tree untouched until [`GitRepository::merge_worktree`].
+    pub fn in_worktree(&self) -> Result<Self> {
+        let base = self.git(&["rev-parse", "HEAD"])?.trim().to_string();
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let branch = format!("rakune-{:x}-{:x}", started, std::process::id());
+        let path = std::env::temp_dir().join(&branch);
+
+        self.git(&[
+            OsStr::new("worktree"),
+            OsStr::new("add"),
+            OsStr::new("-b"),
+            OsStr::new(&branch),
+            path.as_os_str(),
+            OsStr::new(&base),
+        ])?;
+
+        let mut worktree = self.clone();
+        worktree.root = path;
+        worktree.worktree = Some(Worktree {
+            branch,
+            base,
+            origin: self.root.clone(),
+        });
+        Ok(worktree)
+    }
+
+    /// Bring every change made in the worktree, committed or not, into the working tree of the
+    /// repository it was created from as uncommitted changes
+    pub fn merge_worktree(&self) -> Result<()> {
+        let worktree = self
+            .worktree
+            .as_ref()
+            .ok_or("The repository is not a worktree.")?;
+
+        self.git(&["add", "--all"])?;
+        let patch = self.git(&["diff", "--cached", "--binary", &worktree.base])?;
+        if patch.is_empty() {
+            return Ok(());
+        }
+
+        let origin = GitRepository {
+            root: worktree.origin.clone(),
+            ..Default::default()
+        };
+        origin.git_with_input(&["apply", "--whitespace=nowarn", "-"], &patch)?;
+
+        Ok(())
+    }
+
+    /// Delete the worktree and its throwaway branch, discarding everything made in it
+    pub fn remove_worktree(self) -> Result<()> {
+        let worktree = self
+            .worktree
+            .as_ref()
+            .ok_or("The repository is not a worktree.")?;
+
+        let origin = GitRepository {
+            root: worktree.origin.clone(),
+            ..Default::default()
+        };
+        origin.git(&[
+            OsStr::new("worktree"),
+            OsStr::new("remove"),
+            OsStr::new("--force"),
+            self.root.as_os_str(),
+        ])?;
+        origin.git(&["branch", "-D", &worktree.branch])?;
+
+        Ok(())
+    }
+
+    /// A read-only view of the repository as it existed at `revision`. Context is read from
+    /// that revision's blobs, so the working tree is left untouched.
+    pub fn at(&self, revision: &str) -> Self {
+        let mut view = self.clone();
+        view.revision = Some(revision.to_string());
+        view
+    }
+
+    /// Read a file from the working tree, or from the blob at the pinned revision
+    pub fn read_file(&self, filepath: &str) -> Result<String> {
+        match &self.revision {
+            Some(revision) => {
+                let content = self.git(&["show", &format!("{revision}:{filepath}")])?;
+                self.check_file_size(filepath, content.len() as u64)?;
+                Ok(content)
+            }
+            None => {
+                let path = self.resolve(filepath)?;
+                // checked before reading, as the point is to not load huge files at all
+                if let Ok(metadata) = std::fs::metadata(&path) {
+                    self.check_file_size(filepath, metadata.len())?;
+                }
+                Ok(std::fs::read_to_string(path)?)
+            }
+        }
+    }
+
+    /// Refuse files larger than `max_file_size`, unless they are listed in `large_files`
+    fn check_file_size(&self, filepath: &str, size: u64) -> Result<()> {
+        let Some(limit) = self.max_file_size else {
+            return Ok(());
+        };
+
+        let exempt = self
+            .large_files
+            .iter()
+            .any(|glob| glob_match(glob, filepath));
+        if size > limit && !exempt {
+            return Err(RakuneError::FileTooLarge {
+                filepath: filepath.to_string(),
+                size,
+                limit,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Read the lines covered by a fragment, honoring the pinned revision
+    pub fn read_fragment(&self, fragment: &Fragment) -> Result<String> {
+        fragment.select_lines(&self.read_file(&fragment.filepath)?)
+    }
+
+    /// The lines of an update to `fragment` of `content`, re-indented when `reindent_updates` is
+    /// set
+    fn aligned_lines(&self, content: &str, fragment: &Fragment, lines: &[String]) -> Vec<String> {
+        match self.reindent_updates {
+            true => reindent(content, fragment.line_range.0, lines),
+            false => lines.to_vec(),
+        }
+    }
+
+    /// `transformation` with the lines it writes re-indented like those they replace in
+    /// `content`, when `reindent_updates` is set
+    fn aligned(&self, content: &str, transformation: &Transformation) -> Result<Transformation> {
         Ok(match transformation {
             Transformation::UpdateFragment {
                 fragment,
                 updated_lines,
-            } => {
-                let content = fragment.read_file()?;
-                let mut lines = content.lines().collect::<Vec<_>>();
+                expected,
+            } => Transformation::UpdateFragment {
+                fragment: fragment.clone(),
+                updated_lines: self.aligned_lines(content, fragment, updated_lines),
+                expected: expected.clone(),
+            },
+            Transformation::ReplaceMatching {
+                filepath,
+                pattern,
+                content: lines,
+            } if self.reindent_updates => {
+                let fragment = matching_fragment(content, filepath, pattern)?;
+                Transformation::ReplaceMatching {
+                    filepath: filepath.clone(),
+                    pattern: pattern.clone(),
+                    content: self.aligned_lines(content, &fragment, lines),
+                }
+            }
+            transformation => transformation.clone(),
+        })
+    }
 
-                if [fragment.line_range.0, fragment.line_range.1]
-                    .iter()
-                    .any(|r| !(0..=lines.len()).contains(r))
-                {
-                    let error_message = format!(
-                        "One of the line ranges {:?} was not in bound of the file [0..{}].",
-                        fragment.line_range,
-                        lines.len(),
-                    );
+    /// The content of the file of a line edit, along with the content that applying the edit
+    /// would leave it with
+    fn updated_content(&self, transformation: &Transformation) -> Result<(String, String)> {
+        let filepath = transformation
+            .filepath()
+            .ok_or("The transformation doesn't edit the lines of a single file.")?;
+        let content = self.read_file(filepath)?;
+        let updated = apply_to_content(&content, &self.aligned(&content, transformation)?)?;
+        Ok((content, updated))
+    }
+
+    /// Edit the state of a respository using a given agent capability
+    pub fn transform(&mut self, transformation: &Transformation) -> Result<()> {
+        self.apply_transformation(transformation)?;
+        self.post_transform(&[transformation]);
+        Ok(())
+    }
+
+    fn apply_transformation(&mut self, transformation: &Transformation) -> Result<()> {
+        if let Some(revision) = &self.revision {
+            let error_message = format!("Cannot transform the repository pinned at {revision}.");
+            return Err(error_message.into());
+        }
+
+        match transformation {
+            Transformation::UpdateFragment { .. }
+            | Transformation::UpdateSpan { .. }
+            | Transformation::InsertFragment { .. }
+            | Transformation::ReplaceMatching { .. } => {
+                let filepath = transformation.filepath().unwrap_or_default();
+                self.ensure_file(filepath)?;
+
+                let (content, updated) = self.updated_content(transformation)?;
+                // rewriting the file as it was would only bump its mtime
+                if updated == content {
+                    return Ok(());
+                }
+
+                std::fs::write(self.resolve(filepath)?, updated)?;
+            }
+            Transformation::RenameSymbol { old, new } => {
+                let symbol = Regex::new(&format!("\\b{}\\b", regex::escape(old)))?;
+
+                for file in self.workspace_files()? {
+                    // binary and other non utf-8 files can't contain the symbol, and files over
+                    // the size limit are left alone
+                    let Ok(content) = self.read_file(&file) else {
+                        continue;
+                    };
+
+                    if symbol.is_match(&content) {
+                        std::fs::write(
+                            self.resolve(&file)?,
+                            symbol.replace_all(&content, NoExpand(new)).as_ref(),
+                        )?;
+                    }
+                }
+            }
+            Transformation::CreateFile { path } => {
+                let path = self.resolve(path)?;
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+
+                File::options().write(true).create_new(true).open(path)?;
+            }
+            Transformation::DeleteFile { path } => {
+                self.ensure_file(path)?;
+                std::fs::remove_file(self.resolve(path)?)?;
+            }
+            Transformation::MoveFile { old, new } => {
+                self.ensure_file(old)?;
+                let destination = self.resolve(new)?;
+                if destination.exists() {
+                    let error_message =
+                        format!("Cannot move {old} to {new}, which already exists.");
                     return Err(error_message.into());
                 }
+                if let Some(parent) = destination.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
 
-                lines.splice(
-                    fragment.line_range.0..=fragment.line_range.1,
-                    updated_lines.into_iter().map(String::as_str),
-                );
+                std::fs::rename(self.resolve(old)?, destination)?;
+            }
+            Transformation::ApplyPatch { patch } => {
+                // git apply is all or nothing, so a patch that doesn't apply cleanly leaves the
+                // working tree as it was
+                self.git_with_input(&["apply", "--whitespace=nowarn", "-"], patch)?;
+            }
+            Transformation::Noop => {}
+        }
+
+        Ok(())
+    }
 
-                let mut file = File::options()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&fragment.filepath)?;
+    /// Run the hooks enabled for the files whose lines were just edited
+    fn post_transform(&self, transformations: &[&Transformation]) {
+        if !self.post_transform_format {
+            return;
+        }
 
-                file.write_all(&lines.join("\n").as_bytes())?;
+        let mut files = Vec::new();
+        for transformation in transformations {
+            let file = match transformation {
+                Transformation::ReplaceMatching { filepath, .. } => Some(filepath.as_str()),
+                _ => line_position(transformation).map(|(file, _, _)| file),
+            };
+            if let Some(file) = file {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
             }
-            _ => unreachable!(),
-        })
+        }
+
+        for file in files {
+            let _formatted = self.format_file(file);
+
+            #[cfg(debug_assertions)]
+            if let Err(e) = &_formatted {
+                eprintln!("*************** Format Error **************\n{file}: {e}\n");
+            }
+        }
     }
 
-    pub fn diff(&self, target: Option<&String>) -> Result<Diff> {
-        let mut command = Command::new("git");
-        let command = match target {
-            Some(other) => command.args(&["diff", other]),
-            None => command.args(&["diff"]),
+    /// Format a file with the formatter for the project's language. Returns whether it was
+    /// formatted, which it isn't when there is no known formatter or it isn't installed
+    pub fn format_file(&self, filepath: &str) -> Result<bool> {
+        let fragment = Fragment {
+            filepath: filepath.to_string(),
+            line_range: (0, 0),
+            column: None,
+        };
+        let language = self.detect_language(Some(&fragment));
+        let extension = Path::new(filepath).extension().and_then(OsStr::to_str);
+
+        let Some((_, _, formatter)) = FORMATTERS
+            .iter()
+            .find(|(lang, ext, _)| *lang == language && Some(*ext) == extension)
+        else {
+            return Ok(false);
+        };
+
+        let output = match self
+            .command(formatter[0])
+            .args(&formatter[1..])
+            .arg(filepath)
+            .output()
+        {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            output => output?,
         };
+        check_output(formatter, output)?;
+
+        Ok(true)
+    }
+
+    /// Apply several transformations whose line numbers all refer to the files as they are now.
+    /// Consecutive line edits are applied from the bottom of each file upwards so that none of
+    /// them shifts the lines of those still pending. Either all of them are applied, or the
+    /// files they touch are restored to how they were.
+    pub fn transform_all(&mut self, transformations: &[Transformation]) -> Result<()> {
+        let mut snapshot = Vec::new();
+        for file in self.touched_files(transformations)? {
+            let content = std::fs::read(self.path(&file)).ok();
+            snapshot.push((file, content));
+        }
+
+        let result = self.transform_in_order(transformations);
+        if result.is_err() {
+            for (file, content) in snapshot {
+                let path = self.path(&file);
+                match content {
+                    Some(content) => std::fs::write(path, content)?,
+                    None if path.is_file() => std::fs::remove_file(path)?,
+                    None => {}
+                }
+            }
+        }
 
-        let output = command.output()?;
-        let output = std::str::from_utf8(&output.stdout)?.to_string();
-        Ok(output)
+        result
     }
 
-    pub fn commit(&mut self, commit_message: &str) -> Result<String> {
-        Command::new("git").args(&["add", "."]).output()?;
-        Command::new("git")
-            .args(&["commit", "-m", commit_message])
-            .output()?;
-        let commit_revision = Command::new("git").args(&["rev-parse", "HEAD"]).output()?;
-        let commit_revision = std::str::from_utf8(&commit_revision.stdout)?.to_string();
-        Ok(commit_revision)
+    /// The files that applying `transformations` may change, create, or remove
+    pub(crate) fn touched_files(&self, transformations: &[Transformation]) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        for transformation in transformations {
+            match transformation {
+                Transformation::UpdateFragment { fragment, .. }
+                | Transformation::UpdateSpan { fragment, .. } => {
+                    files.push(fragment.filepath.clone())
+                }
+                Transformation::InsertFragment { filepath, .. }
+                | Transformation::ReplaceMatching { filepath, .. } => files.push(filepath.clone()),
+                Transformation::CreateFile { path } | Transformation::DeleteFile { path } => {
+                    files.push(path.clone())
+                }
+                Transformation::MoveFile { old, new } => files.extend([old.clone(), new.clone()]),
+                Transformation::RenameSymbol { .. } => files.extend(self.workspace_files()?),
+                Transformation::ApplyPatch { patch } => files.extend(
+                    patch
+                        .lines()
+                        .filter_map(|l| l.strip_prefix("--- a/").or(l.strip_prefix("+++ b/")))
+                        .map(|path| path.trim_end().to_string()),
+                ),
+                Transformation::Noop => {}
+            }
+        }
+
+        files.sort();
+        files.dedup();
+        Ok(files)
     }
 
-    /// Searches through git or conversation history for context on a particular code fragment
-    ///
-    /// X change built from Y context worked for scenario Z, and scenario A is similar to
-    /// scenario Z, so it should also read Y context.
-    pub fn temporal_context(&self, _fragment: &Fragment) -> Result<Vec<String>> {
-        Ok(Vec::new())
+    fn transform_in_order(&mut self, transformations: &[Transformation]) -> Result<()> {
+        let indexed = transformations.iter().enumerate().collect::<Vec<_>>();
+        for run in indexed
+            .chunk_by(|(_, a), (_, b)| line_position(a).is_some() == line_position(b).is_some())
+        {
+            let mut run = run.to_vec();
+            // insertions at the same line are applied last to first to keep their order
+            run.sort_by_key(|(i, transformation)| {
+                line_position(transformation)
+                    .map(|(file, line, kind)| (file, Reverse(line), kind, Reverse(*i)))
+            });
+
+            for (_, transformation) in run {
+                self.apply_transformation(transformation)?;
+            }
+        }
+
+        // formatting may move lines around, so it waits until every edit is in
+        self.post_transform(&transformations.iter().collect::<Vec<_>>());
+
+        Ok(())
     }
 
-    /// Searches through symbolic, lexical, or etc information on a particular code fragment
-    /// such as callee/caller functions, classes, etc..
-    #[allow(unreachable_code)]
-    pub fn spatial_context(&self, fragment: &Fragment) -> Result<Vec<String>> {
-        let context = vec![format!(
-            "The existing lines of code are:\n\n{}\n>>>>\n{}\n<<<<",
-            fragment.filepath,
-            fragment
-                .read_lines()?
-                .lines()
-                .into_iter()
-                .enumerate()
-                .map(|(i, s)| format!("{i} {s}"))
-                .collect::<Vec<_>>()
-                .join("\n"),
-        )];
+    /// Add an import after the last `use` statement of a file, or after its leading inner doc
+    /// comments and attributes when it has none. Returns `false` if the import already exists.
+    pub fn add_import(&mut self, filepath: &str, import_line: &str) -> Result<bool> {
+        let content = self.read_file(filepath)?;
+        let lines = content.lines().collect::<Vec<_>>();
 
-        return Ok(context);
+        if lines.iter().any(|line| line.trim() == import_line.trim()) {
+            return Ok(false);
+        }
 
-        use tree_sitter::Parser;
-        let mut parser = Parser::new();
-        parser
-            .set_language(tree_sitter_rust::language())
-            .expect("Error loading Rust grammar");
+        // only top level imports, not ones scoped inside of function bodies
+        let is_use = |line: &str| {
+            ["use ", "pub use ", "pub(crate) use "]
+                .iter()
+                .any(|prefix| line.starts_with(prefix))
+        };
 
-        let source_code = fragment.read_file()?;
+        let line_no = match lines.iter().rposition(|line| is_use(line)) {
+            // a use statement may span several lines, so insert after the one that ends it
+            Some(last_use) => {
+                lines[last_use..]
+                    .iter()
+                    .position(|line| line.trim_end().ends_with(';'))
+                    .map_or(last_use, |end| last_use + end)
+                    + 1
+            }
+            None => lines
+                .iter()
+                .position(|line| {
+                    let line = line.trim_start();
+                    !(line.starts_with("//!") || line.starts_with("#!["))
+                })
+                .unwrap_or(lines.len()),
+        };
 
-        let tree = parser
-            .parse(source_code, None)
-            .expect("Failed to parse tree.");
-        let _root_node = tree.root_node();
+        self.transform(&Transformation::InsertFragment {
+            filepath: filepath.to_string(),
+            line_no,
+            content: vec![import_line.to_string()],
+        })?;
 
-        Ok(Vec::new())
+        Ok(true)
     }
-}
 
-#[derive(Debug)]
-pub struct Fragment {
-    pub filepath: String,
-    pub line_range: LineRange,
-}
+    pub fn diff(&self, target: Option<&String>) -> Result<Diff> {
+        match target {
+            Some(other) => self.git(&["diff", other]),
+            None => self.git(&["diff"]),
+        }
+    }
 
-impl Fragment {
-    pub fn read_file(&self) -> Result<String> {
-        Ok(std::fs::read_to_string(&self.filepath)?)
+    /// The diff against `target` split up by the file each part changes
+    pub fn diff_files(&self, target: Option<&String>) -> Result<Vec<(String, Diff)>> {
+        Ok(split_diff(&self.diff(target)?))
     }
 
-    pub fn read_lines(&self) -> Result<String> {
-        let content = self.read_file()?;
-        let lines = content.lines().collect::<Vec<_>>();
+    /// The changes committed on this branch since it forked from `base`, leaving out whatever
+    /// happened on `base` in the meantime
+    pub fn diff_against(&self, base: &str) -> Result<Diff> {
+        self.git(&["diff", &format!("{base}...HEAD")])
+    }
 
-        if [self.line_range.0, self.line_range.1]
-            .iter()
-            .any(|r| !(0..=lines.len()).contains(r))
-        {
-            let error_message = format!(
-                "One of the line ranges {:?} was not in bound of the file [0..{}].",
-                self.line_range,
-                lines.len(),
-            );
+    /// The changes of the last `commits` commits together, or of as many as the history has.
+    /// `None` when there are no commits to take them from
+    pub fn recent_diff(&self, commits: usize) -> Result<Option<Diff>> {
+        let history = self
+            .git(&["rev-list", "--count", "HEAD"])
+            .ok()
+            .and_then(|count| count.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+
+        // the root commit has no parent to diff against
+        match commits.min(history.saturating_sub(1)) {
+            0 => Ok(None),
+            commits => self
+                .diff_against(&format!("HEAD~{commits}"))
+                .map(|diff| Some(diff).filter(|diff| !diff.trim().is_empty())),
+        }
+    }
+
+    /// The diff against `base` split up by the file each part changes
+    pub fn diff_files_against(&self, base: &str) -> Result<Vec<(String, Diff)>> {
+        Ok(split_diff(&self.diff_against(base)?))
+    }
+
+    /// Stage the changes to `paths`, including their removal, leaving the rest of the working
+    /// tree unstaged
+    pub fn stage(&self, paths: &[&Path]) -> Result<()> {
+        // without any path, git would stage the whole working tree
+        if paths.is_empty() {
+            return Ok(());
+        }
+
+        let mut args = vec![OsStr::new("add"), OsStr::new("-A"), OsStr::new("--")];
+        args.extend(paths.iter().map(|path| path.as_os_str()));
+        self.git(&args)?;
+        Ok(())
+    }
+
+    /// Restore a single file to its state at HEAD, undoing the edits made to it while keeping
+    /// those to other files. A file that isn't at HEAD was created since, and is removed
+    pub fn revert_file(&self, filepath: &str) -> Result<()> {
+        if let Some(revision) = &self.revision {
+            let error_message =
+                format!("Cannot revert a file of the repository pinned at {revision}.");
             return Err(error_message.into());
         }
 
-        Ok(lines[self.line_range.0..=self.line_range.1].join("\n"))
+        match self.git(&["cat-file", "-e", &format!("HEAD:{filepath}")]) {
+            Ok(_) => {
+                self.git(&["checkout", "HEAD", "--", filepath])?;
+            }
+            Err(_) => {
+                self.git(&[
+                    "rm",
+                    "--cached",
+                    "--ignore-unmatch",
+                    "--quiet",
+                    "--",
+                    filepath,
+                ])?;
+                if self.path(filepath).is_file() {
+                    std::fs::remove_file(self.path(filepath))?;
+                }
+            }
+        }
+
+        Ok(())
     }
-}
 
-type LineRange = (usize, usize);
+    /// Stage every change in the working tree, or only those to [`CommitOptions::paths`], and
+    /// commit it, returning the new HEAD revision
+    pub fn commit(&mut self, commit_message: &str, options: &CommitOptions) -> Result<String> {
+        let mut args = vec![
+            OsString::from("commit"),
+            OsString::from("-m"),
+            OsString::from(commit_message),
+        ];
+        if let Some(author) = &options.author {
+            args.push(format!("--author={} <{}>", author.name, author.email).into());
+        }
+        if options.signoff {
+            args.push("--signoff".into());
+        }
 
-#[derive(Debug)]
-pub struct Comment {
-    pub message: String,
-    pub fragments: Vec<Fragment>,
-}
+        match &options.paths {
+            Some(paths) if paths.is_empty() => {
+                return Err("There are no changes to the paths to commit.".into());
+            }
+            Some(paths) => {
+                self.stage(&paths.iter().map(PathBuf::as_path).collect::<Vec<_>>())?;
+                // changes to other files that were staged beforehand stay out of the commit
+                args.push("--".into());
+                args.extend(paths.iter().map(|path| path.as_os_str().to_owned()));
+            }
+            None => {
+                self.git(&["add", "."])?;
+            }
+        }
+        self.git(&args)?;
 
-pub enum Transformation {
-    RenameSymbol {
-        old: String,
-        new: String,
-    },
-    CreateFile {
-        path: String,
-    },
-    DeleteFile {
-        path: String,
-    },
-    MoveFile {
-        old: String,
-        new: String,
-    },
-    UpdateFragment {
-        fragment: Fragment,
-        updated_lines: Vec<String>,
-    },
-    InsertFragment {
-        line_no: usize,
-        content: Vec<String>,
-    },
-}
+        self.git(&["rev-parse", "HEAD"])
+    }
 
-impl Transformation {
-    pub fn parse_from(value: &str) -> std::result::Result<Vec<Self>, &'static str> {
-        let re = Regex::new(
-            "filepath: (.*?),?\n.*start_line: (\\d+),?\n.*end_line: (\\d+),?\n.*content: ([\\s\\S]*?)```",
-        )
-        .expect("Regex failed to compile.");
+    /// The name of the checked out branch, or `HEAD` when it is detached
+    pub fn current_branch(&self) -> Result<String> {
+        Ok(self
+            .git(&["rev-parse", "--abbrev-ref", "HEAD"])?
+            .trim()
+            .to_string())
+    }
 
-        Ok(re
-            .captures_iter(value)
-            .map(|c| c.extract())
-            .filter_map(|(_, [filepath, start, end, content])| {
-                let start_line = start.parse().unwrap();
-                let end_line = end.parse().unwrap();
-                let updated_lines = content.lines().map(|s| s.to_string()).collect();
+    /// Whether the working tree has no changes, staged or not, and no untracked files
+    pub fn is_clean(&self) -> Result<bool> {
+        Ok(self.git(&["status", "--porcelain"])?.trim().is_empty())
+    }
 
-                Some(Self::UpdateFragment {
-                    fragment: Fragment {
-                        filepath: filepath.into(),
-                        line_range: (start_line, end_line),
-                    },
-                    updated_lines,
-                })
-            })
+    /// Whether a path is excluded from repository-wide operations by `ignore_globs`
+    pub fn is_ignored(&self, path: &str) -> bool {
+        is_ignored_by(&self.ignore_globs, path)
+    }
+
+    /// Add the globs of the `.rakuneignore` file at the root to `ignore_globs`, if there is one
+    pub fn read_ignore_file(&mut self) -> Result<()> {
+        match std::fs::read_to_string(self.path(IGNORE_FILE)) {
+            Ok(content) => self.ignore_globs.extend(parse_ignore_file(&content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(())
+    }
+
+    /// Files considered by repository-wide operations: tracked and untracked files that are not
+    /// excluded by `.gitignore` or `ignore_globs`
+    fn workspace_files(&self) -> Result<Vec<String>> {
+        let Ok(files) = self.git(&["ls-files", "--cached", "--others", "--exclude-standard"])
+        else {
+            return Ok(self
+                .walk_files()?
+                .iter()
+                .map(|f| f.to_string_lossy().into())
+                .collect());
+        };
+
+        Ok(files
+            .lines()
+            .filter(|file| self.path(file).is_file() && !self.is_ignored(file))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Paths of the files tracked by git relative to the root, leaving out deleted files and
+    /// those excluded by `ignore_globs`. Outside of a git repository every file below the root is
+    /// listed instead, except in hidden or ignored directories.
+    pub fn tracked_files(&self) -> Result<Vec<PathBuf>> {
+        let Ok(files) = self.git(&["ls-files", "--cached", "--exclude-standard"]) else {
+            return self.walk_files();
+        };
+
+        Ok(files
+            .lines()
+            .filter(|file| self.path(file).is_file() && !self.is_ignored(file))
+            .map(PathBuf::from)
             .collect())
     }
+
+    /// Check that `filepath` is a file of the working tree, failing with a
+    /// [`RakuneError::MissingFile`] otherwise
+    pub fn ensure_file(&self, filepath: &str) -> Result<()> {
+        match self.path(filepath).is_file() {
+            true => Ok(()),
+            false => Err(self.missing_file(filepath).into()),
+        }
+    }
+
+    /// The error for an edit of a file that doesn't exist, suggesting the tracked file with the
+    /// closest path
+    fn missing_file(&self, filepath: &str) -> RakuneError {
+        let suggestion = self
+            .tracked_files()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|file| file.to_string_lossy().into_owned())
+            .map(|file| (edit_distance(filepath, &file), file))
+            // a path that has to be rewritten entirely is no suggestion
+            .filter(|(distance, _)| *distance <= filepath.chars().count() / 2)
+            .min()
+            .map(|(_, file)| file);
+
+        RakuneError::MissingFile {
+            filepath: filepath.to_string(),
+            suggestion,
+        }
+    }
+
+    /// Recursively list the files below the root, skipping hidden and ignored directories
+    fn walk_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        let mut directories = vec![PathBuf::new()];
+
+        while let Some(directory) = directories.pop() {
+            let mut entries = std::fs::read_dir(self.path(&directory))?
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            entries.sort_by_key(|entry| entry.file_name());
+
+            for entry in entries {
+                let path = directory.join(entry.file_name());
+                let relative = path.to_string_lossy();
+
+                if entry.file_type()?.is_dir() {
+                    let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                    if !hidden && !self.is_ignored(&format!("{relative}/")) {
+                        directories.push(path);
+                    }
+                } else if !self.is_ignored(&relative) {
+                    files.push(path);
+                }
+            }
+        }
+
+        files.sort();
+        Ok(files)
+    }
+
+    /// Paths of the files that differ from HEAD, including untracked files
+    pub fn changed_files(&self) -> Result<Vec<String>> {
+        let modified = self.git(&["diff", "--name-only", "HEAD"])?;
+        let untracked = self.git(&["ls-files", "--others", "--exclude-standard"])?;
+
+        Ok(modified
+            .lines()
+            .chain(untracked.lines())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Whether a change to `filepath` can change the outcome of a build, judged by its extension
+    /// for the language of the project it belongs to. Every file counts in projects of an
+    /// unknown language
+    pub fn affects_build(&self, filepath: &str) -> bool {
+        let path = Path::new(filepath);
+        let is_manifest = MANIFESTS
+            .iter()
+            .any(|(manifest, _)| path.file_name().is_some_and(|name| name == *manifest));
+
+        let fragment = Fragment {
+            filepath: filepath.to_string(),
+            line_range: (0, 0),
+            column: None,
+        };
+        let language = self.detect_language(Some(&fragment));
+        let Some((_, extensions)) = SOURCE_EXTENSIONS.iter().find(|(l, _)| *l == language) else {
+            return true;
+        };
+
+        is_manifest
+            || path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| extensions.contains(&extension))
+    }
+
+    /// Guess the language of the project from the manifests found in the repository, preferring
+    /// the manifest closest to the fragment being edited
+    pub fn detect_language(&self, fragment: Option<&Fragment>) -> String {
+        let mut directory = fragment
+            .and_then(|f| Path::new(&f.filepath).parent())
+            .unwrap_or(Path::new(""));
+
+        loop {
+            if let Some((_, language)) = MANIFESTS
+                .iter()
+                .find(|(manifest, _)| self.path(directory).join(manifest).is_file())
+            {
+                return language.to_string();
+            }
+
+            match directory.parent() {
+                Some(parent) => directory = parent,
+                None => return "unknown".to_string(),
+            }
+        }
+    }
+
+    /// Searches through git or conversation history for context on a particular code fragment
+    ///
+    /// X change built from Y context worked for scenario Z, and scenario A is similar to
+    /// scenario Z, so it should also read Y context.
+    pub fn temporal_context(&self, comment: &Comment, fragment: &Fragment) -> Result<Vec<String>> {
+        let Some(history) = &self.history else {
+            return Ok(Vec::new());
+        };
+
+        let similar = history.query(&SimilarTo {
+            message: comment.message.clone(),
+            filepath: Some(fragment.filepath.clone()),
+            limit: 3,
+        })?;
+
+        similar
+            .into_iter()
+            .map(|exchange| {
+                Ok(format!(
+                    "A similar request was made before:\n\n{}\n\nThese changes {}:\n\n{}",
+                    exchange.comment.message,
+                    match exchange.built {
+                        Some(true) => "worked",
+                        _ => "did NOT work",
+                    },
+                    serde_json::to_string_pretty(&exchange.transformations)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Searches through symbolic, lexical, or etc information on a particular code fragment
+    /// such as callee/caller functions, classes, etc..
+    pub fn spatial_context(&self, fragment: &Fragment) -> Result<Vec<String>> {
+        let content = self.read_file(&fragment.filepath)?;
+        let lines = content.lines().collect::<Vec<_>>();
+        fragment.check_range(lines.len())?;
+
+        // lines keep their position in the file so that the coordinates of an edit line up
+        let start = fragment.line_range.0.saturating_sub(self.context_window);
+        let end = (fragment.line_range.1 + self.context_window).min(lines.len().saturating_sub(1));
+
+        let mut context = vec![format!(
+            "The existing lines of code are:\n\n{}\n>>>>\n{}\n<<<<",
+            fragment.filepath,
+            numbered_lines(&lines, start, end, self.line_base),
+        )];
+
+        if fragment.filepath.ends_with(".rs") {
+            context.extend(self.symbol_context(fragment, &content)?);
+        }
+
+        if self.include_blame {
+            context.extend(self.blame_context(fragment)?);
+        }
+
+        Ok(context)
+    }
+
+    /// The whole of the innermost Rust function around a fragment, for prompts that can't show
+    /// its whole file. `None` when there's no such function, or when the lines shown by
+    /// [`GitRepository::spatial_context`] already cover it
+    pub fn enclosing_context(&self, fragment: &Fragment) -> Result<Option<String>> {
+        if !fragment.filepath.ends_with(".rs") {
+            return Ok(None);
+        }
+
+        let content = self.read_file(&fragment.filepath)?;
+        let Some(tree) = parse_rust(&content) else {
+            return Ok(None);
+        };
+        let (first, last) = fragment.line_range;
+
+        // nodes are visited outside in, so the last function found is the innermost one
+        let mut enclosing = None;
+        visit(tree.root_node(), &mut |node| {
+            let (start, end) = (node.start_position().row, node.end_position().row);
+            if node.kind() == "function_item" && start <= first && last <= end {
+                enclosing = Some((start, end));
+            }
+        });
+
+        let shown = (
+            first.saturating_sub(self.context_window),
+            last + self.context_window,
+        );
+        match enclosing {
+            Some((start, end)) if start < shown.0 || shown.1 < end => {
+                let lines = content.lines().collect::<Vec<_>>();
+                Ok(Some(format!(
+                    "The function around these lines is:\n\n{}\n>>>>\n{}\n<<<<",
+                    fragment.filepath,
+                    numbered_lines(&lines, start, end, self.line_base),
+                )))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The commit that last changed each line of a fragment, so that the model can tell recent
+    /// and deliberate changes apart. Empty for files git doesn't track
+    pub fn blame_context(&self, fragment: &Fragment) -> Result<Vec<String>> {
+        let tracked = self
+            .command("git")
+            .args(["ls-files", "--error-unmatch", "--", &fragment.filepath])
+            .output()?
+            .status
+            .success();
+        if !tracked {
+            return Ok(Vec::new());
+        }
+
+        let (start, end) = fragment.line_range;
+        let range = format!("{},{}", start + 1, end + 1);
+        let mut args = vec!["blame", "--porcelain", "-L", &range];
+        args.extend(self.revision.as_deref());
+        args.extend(["--", &fragment.filepath]);
+        let blame = self.git(&args)?;
+
+        // the porcelain format describes each commit once, on the first line it changed
+        let mut commits: Vec<(&str, &str, &str)> = Vec::new();
+        let mut lines = Vec::new();
+        let mut current = None;
+        for line in blame.lines() {
+            if let Some(code) = line.strip_prefix('\t') {
+                if let Some((hash, line_no)) = current.take() {
+                    lines.push((hash, line_no, code));
+                }
+            } else if let Some(author) = line.strip_prefix("author ") {
+                if let Some((hash, _)) = current {
+                    commits.push((hash, author, ""));
+                }
+            } else if let Some(summary) = line.strip_prefix("summary ") {
+                if let Some(commit) = commits.last_mut() {
+                    commit.2 = summary;
+                }
+            } else {
+                // every other line is either a header of a blamed line or a field of a commit
+                let mut fields = line.split(' ');
+                let header = (fields.next(), fields.next(), fields.next());
+                if let (Some(hash), Some(_), Some(line_no)) = header {
+                    if hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                        current = line_no.parse::<usize>().ok().map(|line_no| (hash, line_no));
+                    }
+                }
+            }
+        }
+
+        let blamed = lines
+            .into_iter()
+            .map(|(hash, line_no, code)| {
+                let changed_by = match commits.iter().find(|(h, _, _)| *h == hash) {
+                    _ if hash.trim_start_matches('0').is_empty() => "not committed yet".to_string(),
+                    Some((_, author, summary)) => {
+                        format!("{} by {}: {}", &hash[..hash.len().min(8)], author, summary)
+                    }
+                    None => hash[..hash.len().min(8)].to_string(),
+                };
+                format!("{} {} ({})", line_no - 1 + self.line_base, code, changed_by)
+            })
+            .collect::<Vec<_>>();
+
+        if blamed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(vec![format!(
+            "The lines of {} were last changed in these commits:\n\n>>>>\n{}\n<<<<",
+            fragment.filepath,
+            blamed.join("\n")
+        )])
+    }
+
+    /// What the language server knows about the symbol at a fragment, or the call context found
+    /// with tree-sitter when there is no server to ask
+    fn symbol_context(&self, fragment: &Fragment, content: &str) -> Result<Vec<String>> {
+        #[cfg(feature = "lsp")]
+        if let Some(lsp) = &self.lsp {
+            let query = SymbolAt {
+                root: self.root.clone(),
+                fragment: fragment.clone(),
+                line_base: self.line_base,
+            };
+            match lsp.query(&query) {
+                Ok(context) if !context.is_empty() => return Ok(context),
+                Ok(_) => {}
+                Err(_e) => {
+                    #[cfg(debug_assertions)]
+                    eprintln!("*************** Language server failed **************\n{_e}\n");
+                }
+            }
+        }
+
+        self.call_context(fragment, content)
+    }
+
+    /// The fragment spanning the Rust item named `symbol` in a file, such as a function or a
+    /// struct, so that comments can target code by name rather than by lines that drift with
+    /// every edit. The impl block of a type is named like `impl Type`
+    pub fn fragment_for_symbol(&self, filepath: &str, symbol: &str) -> Result<Fragment> {
+        if !filepath.ends_with(".rs") {
+            let error_message =
+                format!("Symbols can only be looked up in Rust files, not {filepath}.");
+            return Err(error_message.into());
+        }
+
+        let content = self.read_file(filepath)?;
+        let tree = parse_rust(&content).ok_or(format!("Failed to parse {filepath}."))?;
+
+        let impl_of = symbol.strip_prefix("impl ").map(str::trim);
+
+        let mut found = Vec::new();
+        visit(tree.root_node(), &mut |node| {
+            let name = match (node.kind(), impl_of) {
+                // `impl Trait for Type<T>` is found by the name of the type
+                ("impl_item", Some(_)) => node.child_by_field_name("type"),
+                ("impl_item", None) | (_, Some(_)) => None,
+                (kind, None) if kind.ends_with("_item") || kind == "macro_definition" => {
+                    node.child_by_field_name("name")
+                }
+                _ => None,
+            };
+            let name = name
+                .and_then(|name| name.utf8_text(content.as_bytes()).ok())
+                .map(|name| name.split('<').next().unwrap_or(name).trim());
+
+            if name == Some(impl_of.unwrap_or(symbol)) {
+                found.push((node.start_position().row, node.end_position().row));
+            }
+        });
+
+        match found.as_slice() {
+            [] => {
+                let error_message = format!("No item named `{symbol}` in {filepath}.");
+                Err(error_message.into())
+            }
+            [line_range] => Ok(Fragment {
+                filepath: filepath.to_string(),
+                line_range: *line_range,
+                column: None,
+            }),
+            found => {
+                let error_message = format!(
+                    "`{symbol}` is ambiguous in {filepath}, it names the items at lines {}.",
+                    found
+                        .iter()
+                        .map(|(start, end)| format!(
+                            "{}-{}",
+                            start + self.line_base,
+                            end + self.line_base
+                        ))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                Err(error_message.into())
+            }
+        }
+    }
+
+    /// The call sites of the function enclosing a fragment, and the definitions of the functions
+    /// called within it, found across the files of the repository
+    fn call_context(&self, fragment: &Fragment, content: &str) -> Result<Vec<String>> {
+        let Some(tree) = parse_rust(content) else {
+            return Ok(Vec::new());
+        };
+        let (first, last) = fragment.line_range;
+
+        let mut enclosing = None;
+        let mut callees = Vec::new();
+        visit(tree.root_node(), &mut |node| {
+            let (start, end) = (node.start_position().row, node.end_position().row);
+            match node.kind() {
+                "function_item" if start <= first && last <= end => {
+                    enclosing = node
+                        .child_by_field_name("name")
+                        .and_then(|name| name.utf8_text(content.as_bytes()).ok())
+                        .map(|name| (name.to_string(), start, end));
+                }
+                "call_expression" if first <= start && start <= last => {
+                    if let Some(callee) = callee_name(node, content) {
+                        if !callees.contains(&callee) {
+                            callees.push(callee);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        });
+
+        let mut callers = Vec::new();
+        let mut definitions = Vec::new();
+        for file in self.tracked_files()? {
+            let filepath = file.to_string_lossy().to_string();
+            if !filepath.ends_with(".rs") {
+                continue;
+            }
+            let Ok(source) = self.read_file(&filepath) else {
+                continue;
+            };
+            let Some(tree) = parse_rust(&source) else {
+                continue;
+            };
+            let lines = source.lines().collect::<Vec<_>>();
+
+            visit(tree.root_node(), &mut |node| {
+                let (start, end) = (node.start_position().row, node.end_position().row);
+                match node.kind() {
+                    "call_expression" if callers.len() < Self::MAX_CALL_SITES => {
+                        let Some((name, first, last)) = &enclosing else {
+                            return;
+                        };
+                        let within =
+                            filepath == fragment.filepath && *first <= start && start <= *last;
+                        if !within && callee_name(node, &source).as_ref() == Some(name) {
+                            callers.push(format!(
+                                "{}\n>>>>\n{}\n<<<<",
+                                filepath,
+                                numbered_lines(&lines, start, end, self.line_base)
+                            ));
+                        }
+                    }
+                    "function_item" if definitions.len() < Self::MAX_CALL_SITES => {
+                        let name = node
+                            .child_by_field_name("name")
+                            .and_then(|name| name.utf8_text(source.as_bytes()).ok());
+                        if name.is_some_and(|name| callees.iter().any(|callee| callee == name)) {
+                            definitions.push(format!(
+                                "{}\n>>>>\n{}\n<<<<",
+                                filepath,
+                                numbered_lines(
+                                    &lines,
+                                    start,
+                                    end.min(start + Self::MAX_DEFINITION_LINES - 1),
+                                    self.line_base,
+                                )
+                            ));
+                        }
+                    }
+                    _ => {}
+                }
+            });
+        }
+
+        let mut context = Vec::new();
+        if let Some((name, _, _)) = &enclosing {
+            if !callers.is_empty() {
+                context.push(format!(
+                    "`{}` is called from:\n\n{}",
+                    name,
+                    callers.join("\n\n")
+                ));
+            }
+        }
+        if !definitions.is_empty() {
+            context.push(format!(
+                "The functions called by this code are defined as:\n\n{}",
+                definitions.join("\n\n")
+            ));
+        }
+
+        Ok(context)
+    }
+}
+
+/// Identity used to attribute commits made by the agent
+#[derive(Clone, Debug)]
+pub struct Author {
+    pub name: String,
+    pub email: String,
+}
+
+/// Extra settings applied when creating a commit
+#[derive(Clone, Debug, Default)]
+pub struct CommitOptions {
+    /// commit as this identity instead of the globally configured git user
+    pub author: Option<Author>,
+    /// append a `Signed-off-by` trailer to the commit message
+    pub signoff: bool,
+    /// stage and commit the changes to these paths only, instead of everything in the working
+    /// tree, see [`GitRepository::stage`]
+    pub paths: Option<Vec<PathBuf>>,
+}
+
+/// The file and first line a line edit applies to, along with its rank among edits at the same
+/// line. A replacement goes before an insertion, so that the insertion still lands above the
+/// replaced lines
+fn line_position(transformation: &Transformation) -> Option<(&str, usize, u8)> {
+    match transformation {
+        Transformation::UpdateFragment { fragment, .. }
+        | Transformation::UpdateSpan { fragment, .. } => {
+            Some((fragment.filepath.as_str(), fragment.line_range.0, 0))
+        }
+        Transformation::InsertFragment {
+            filepath, line_no, ..
+        } => Some((filepath.as_str(), *line_no, 1)),
+        _ => None,
+    }
+}
+
+/// The content of a file after a transformation of its lines, without touching the disk, e.g.
+/// for previews. Dry runs of transformations of whole files or of the repository, such as
+/// [`Transformation::MoveFile`], need the files themselves, see [`Transformation::to_diff`]
+pub fn apply_to_content(content: &str, transformation: &Transformation) -> Result<String> {
+    match transformation {
+        Transformation::UpdateFragment {
+            fragment,
+            updated_lines,
+            expected,
+        } => update_lines(content, fragment, updated_lines, expected.as_deref()),
+        Transformation::UpdateSpan {
+            fragment,
+            columns,
+            replacement,
+        } => update_span(content, fragment, *columns, replacement),
+        Transformation::InsertFragment {
+            line_no,
+            content: inserted,
+            ..
+        } => insert_lines(content, *line_no, inserted),
+        Transformation::ReplaceMatching {
+            filepath,
+            pattern,
+            content: replacement,
+        } => {
+            let fragment = matching_fragment(content, filepath, pattern)?;
+            update_lines(content, &fragment, replacement, None)
+        }
+        Transformation::Noop => Ok(content.to_string()),
+        transformation => {
+            let error_message = format!(
+                "Only edits of the lines of a file apply to its content, unlike `{}`.",
+                transformation.summary()
+            );
+            Err(error_message.into())
+        }
+    }
+}
+
+/// Replace the lines of `fragment` in `content`, checking that they hold `expected` first
+fn update_lines(
+    content: &str,
+    fragment: &Fragment,
+    updated_lines: &[String],
+    expected: Option<&[String]>,
+) -> Result<String> {
+    let mut lines = content.lines().collect::<Vec<_>>();
+
+    fragment.check_range(lines.len())?;
+
+    // a freshly created file has no lines to replace, only a position to insert at
+    let replaced = if lines.is_empty() {
+        0..0
+    } else {
+        fragment.line_range.0..fragment.line_range.1 + 1
+    };
+
+    if let Some(expected) = expected {
+        let actual = &lines[replaced.clone()];
+        if actual != expected {
+            let mismatch = expected
+                .iter()
+                .map(|line| format!("-{line}"))
+                .chain(actual.iter().map(|line| format!("+{line}")))
+                .collect::<Vec<_>>()
+                .join("\n");
+            let error_message = format!(
+                "The lines {}..{} of {} do not hold the expected content:\n{}",
+                fragment.line_range.0, fragment.line_range.1, fragment.filepath, mismatch,
+            );
+            return Err(error_message.into());
+        }
+    }
+
+    lines.splice(replaced, updated_lines.iter().map(String::as_str));
+
+    Ok(join_lines(&lines, content))
+}
+
+/// The lines of the single match of `pattern` in `content`, extended to the brace closing the
+/// block it opens, if any. A `;` before any `{` ends the statement without a block
+fn matching_fragment(content: &str, filepath: &str, pattern: &str) -> Result<Fragment> {
+    let regex = Regex::new(pattern)
+        .map_err(|e| format!("The pattern `{pattern}` is not a valid regex: {e}"))?;
+    let line_of = |byte: usize| content[..byte].matches('\n').count();
+
+    let matches = regex.find_iter(content).collect::<Vec<_>>();
+    let found = match matches.as_slice() {
+        [] => {
+            let error_message =
+                format!("The pattern `{}` matches nothing in {}.", pattern, filepath);
+            return Err(error_message.into());
+        }
+        [found] => found,
+        matches => {
+            let error_message = format!(
+                "The pattern `{}` matches {} places in {}, on lines {}. It should match only one.",
+                pattern,
+                matches.len(),
+                filepath,
+                matches
+                    .iter()
+                    .map(|m| line_of(m.start()).to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            );
+            return Err(error_message.into());
+        }
+    };
+
+    let end = match block_end(content, found.start()) {
+        Some(end) => end.max(found.end()),
+        None if content[found.start()..].contains('{') => {
+            let error_message = format!(
+                "The block matching `{}` in {} has no closing brace.",
+                pattern, filepath
+            );
+            return Err(error_message.into());
+        }
+        None => found.end(),
+    };
+
+    let start = line_of(found.start());
+    Ok(Fragment {
+        filepath: filepath.to_string(),
+        line_range: (start, line_of(end.saturating_sub(1)).max(start)),
+        column: None,
+    })
+}
+
+/// The byte after the brace closing the first block opened from `from` in `content`, or `from`
+/// itself when a `;` comes first. Braces in strings, chars, and line comments don't count.
+/// `None` when there is no block or it isn't closed
+fn block_end(content: &str, from: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = content[from..].char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            // a char literal like '{' or '\'', rather than a lifetime
+            '\'' => {
+                let rest = &content[from + i + 1..];
+                let length = match rest.strip_prefix('\\') {
+                    Some(escaped) => escaped.find('\'').map(|end| end + 2),
+                    None => rest
+                        .chars()
+                        .next()
+                        .filter(|c| rest[c.len_utf8()..].starts_with('\''))
+                        .map(|c| c.len_utf8() + 1),
+                };
+                for _ in 0..length.map_or(0, |length| rest[..length].chars().count()) {
+                    chars.next();
+                }
+            }
+            '/' if chars.peek().is_some_and(|(_, c)| *c == '/') => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            ';' if depth == 0 => return Some(from),
+            '{' => depth += 1,
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(from + i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Replace the characters of `content` between column `columns.0` of the first line of
+/// `fragment` and column `columns.1` of its last line
+fn update_span(
+    content: &str,
+    fragment: &Fragment,
+    columns: (usize, usize),
+    replacement: &str,
+) -> Result<String> {
+    let mut lines = content.lines().collect::<Vec<_>>();
+
+    fragment.check_range(lines.len())?;
+
+    let (start, end) = fragment.line_range;
+    // a freshly created file still has an empty line 0 to edit
+    let line = |line_no: usize| lines.get(line_no).copied().unwrap_or("");
+    // columns count characters, so they're mapped to the byte offsets of their boundaries
+    let offset = |line_no: usize, column: usize| -> Result<usize> {
+        let text = line(line_no);
+        text.char_indices()
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .nth(column)
+            .ok_or_else(|| {
+                format!(
+                    "The column {} is past the end of line {} of {}, which has {} characters.",
+                    column,
+                    line_no,
+                    fragment.filepath,
+                    text.chars().count(),
+                )
+                .into()
+            })
+    };
+
+    let (from, to) = (offset(start, columns.0)?, offset(end, columns.1)?);
+    if start == end && from > to {
+        let error_message = format!(
+            "The start column {} is after the end column {} on line {} of {}.",
+            columns.0, columns.1, start, fragment.filepath,
+        );
+        return Err(error_message.into());
+    }
+
+    let updated = format!(
+        "{}{}{}",
+        &line(start)[..from],
+        replacement,
+        &line(end)[to..]
+    );
+    let replaced = match lines.is_empty() {
+        true => 0..0,
+        false => start..end + 1,
+    };
+    // splitting on newlines keeps a line emptied by the replacement, which `lines` would drop
+    lines.splice(
+        replaced,
+        updated
+            .split('\n')
+            .map(|line| line.strip_suffix('\r').unwrap_or(line)),
+    );
+
+    Ok(join_lines(&lines, content))
+}
+
+/// Shift `lines` so that the first of them is indented like the line `line_no` of `content`,
+/// keeping the indentation of the others relative to it. Indentation is rewritten in tabs when
+/// most indented lines of `content` start with a tab, and in spaces otherwise
+pub fn reindent(content: &str, line_no: usize, lines: &[String]) -> Vec<String> {
+    const TAB_WIDTH: usize = 4;
+
+    let indent_of = |line: &str| {
+        line.chars()
+            .take_while(|c| c.is_whitespace())
+            .map(|c| if c == '\t' { TAB_WIDTH } else { 1 })
+            .sum::<usize>()
+    };
+
+    let original = content.lines().collect::<Vec<_>>();
+    // a blank line says nothing about the indentation, so the next line with code decides
+    let Some(target) = original
+        .iter()
+        .skip(line_no)
+        .find(|line| !line.trim().is_empty())
+        .map(|line| indent_of(line))
+    else {
+        return lines.to_vec();
+    };
+    let Some(first) = lines
+        .iter()
+        .find(|line| !line.trim().is_empty())
+        .map(|line| indent_of(line))
+    else {
+        return lines.to_vec();
+    };
+
+    let tabs = original
+        .iter()
+        .filter(|line| line.starts_with('\t'))
+        .count();
+    let spaces = original.iter().filter(|line| line.starts_with(' ')).count();
+    let indentation = |width: usize| match tabs > spaces {
+        true => "\t".repeat(width / TAB_WIDTH) + &" ".repeat(width % TAB_WIDTH),
+        false => " ".repeat(width),
+    };
+
+    lines
+        .iter()
+        .map(|line| match line.trim().is_empty() {
+            true => String::new(),
+            false => {
+                let width = (indent_of(line) + target).saturating_sub(first);
+                indentation(width) + line.trim_start()
+            }
+        })
+        .collect()
+}
+
+/// Insert `inserted` before the line `line_no` of `content`
+fn insert_lines(content: &str, line_no: usize, inserted: &[String]) -> Result<String> {
+    let mut lines = content.lines().collect::<Vec<_>>();
+
+    if line_no > lines.len() {
+        let error_message = format!(
+            "The line {} to insert at was not in bound of the file [0..{}].",
+            line_no,
+            lines.len(),
+        );
+        return Err(error_message.into());
+    }
+
+    lines.splice(line_no..line_no, inserted.iter().map(String::as_str));
+
+    Ok(join_lines(&lines, content))
+}
+
+/// The number of characters inserted, removed, or replaced to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Split a diff up by the file each part changes
+fn split_diff(diff: &str) -> Vec<(String, Diff)> {
+    let mut files: Vec<(String, Diff)> = Vec::new();
+    for line in diff.lines() {
+        if let Some(paths) = line.strip_prefix("diff --git ") {
+            let path = paths.rsplit(" b/").next().unwrap_or(paths);
+            files.push((path.to_string(), Diff::new()));
+        }
+        if let Some((_, file_diff)) = files.last_mut() {
+            file_diff.push_str(line);
+            file_diff.push('\n');
+        }
+    }
+
+    files
+}
+
+/// The lines from `start` to `end` inclusive, prefixed with their line number counting from
+/// `line_base`
+pub(crate) fn numbered_lines(lines: &[&str], start: usize, end: usize, line_base: usize) -> String {
+    lines
+        .iter()
+        .enumerate()
+        .skip(start)
+        .take((end + 1).saturating_sub(start))
+        .map(|(i, s)| format!("{} {s}", i + line_base))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Parse Rust source code, giving up on grammars that fail to load
+fn parse_rust(source: &str) -> Option<tree_sitter::Tree> {
+    let mut parser = tree_sitter::Parser::new();
+    parser.set_language(tree_sitter_rust::language()).ok()?;
+    parser.parse(source, None)
+}
+
+/// Call `f` on `node` and every node below it, in document order
+fn visit<'a>(node: tree_sitter::Node<'a>, f: &mut impl FnMut(tree_sitter::Node<'a>)) {
+    f(node);
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        visit(child, f);
+    }
+}
+
+/// The name of the function called by a `call_expression`, without its path or receiver
+fn callee_name(call: tree_sitter::Node, source: &str) -> Option<String> {
+    let mut function = call.child_by_field_name("function")?;
+    loop {
+        function = match function.kind() {
+            "identifier" | "field_identifier" => break,
+            "scoped_identifier" => function.child_by_field_name("name")?,
+            "field_expression" => function.child_by_field_name("field")?,
+            "generic_function" => function.child_by_field_name("function")?,
+            _ => return None,
+        };
+    }
+
+    function
+        .utf8_text(source.as_bytes())
+        .ok()
+        .map(str::to_string)
+}
+
+/// The line ending used by most lines of `content`, defaulting to `\n`
+fn line_ending(content: &str) -> &'static str {
+    let crlf = content.matches("\r\n").count();
+    let lf = content.matches('\n').count() - crlf;
+
+    match crlf > lf {
+        true => "\r\n",
+        false => "\n",
+    }
+}
+
+/// Join edited lines back into file contents, keeping the line ending and trailing newline of
+/// the original contents so that untouched lines are left as they were
+fn join_lines(lines: &[&str], original: &str) -> String {
+    let ending = line_ending(original);
+
+    let mut content = lines.join(ending);
+    if original.ends_with('\n') && !lines.is_empty() {
+        content += ending;
+    }
+
+    content
+}
+
+/// Resolve `filepath` against `root` to the file it lands on once its symlinks and `..` are
+/// followed, refusing it when that is outside `root`, so that a symlink or path from the model
+/// can't reach files elsewhere on the machine. Parts of the path that don't exist yet, as for
+/// a file about to be created, are taken as written. With `deny_symlinks` any path through a
+/// symlink is refused. An empty `root` is the current directory
+pub fn resolve_within(root: &Path, filepath: &str, deny_symlinks: bool) -> Result<PathBuf> {
+    let root = match root.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => root,
+    };
+    let canonical_root = root.canonicalize()?;
+    let outside = |resolved: PathBuf| RakuneError::OutsideRepository {
+        filepath: filepath.to_string(),
+        resolved,
+    };
+
+    // the path as written up to the current component, and where it actually leads
+    let mut written = root.to_path_buf();
+    let mut resolved = canonical_root.clone();
+    let mut exists = true;
+    for component in Path::new(filepath).components() {
+        match component {
+            Component::CurDir => continue,
+            Component::Normal(_) | Component::ParentDir if exists => {
+                written.push(component);
+                match std::fs::symlink_metadata(&written) {
+                    Ok(metadata) => {
+                        if deny_symlinks && metadata.file_type().is_symlink() {
+                            return Err(RakuneError::Symlink {
+                                filepath: filepath.to_string(),
+                            }
+                            .into());
+                        }
+                        // a dangling symlink would be written through to wherever it points
+                        resolved = written.canonicalize().map_err(|_| {
+                            outside(std::fs::read_link(&written).unwrap_or(written.clone()))
+                        })?;
+                    }
+                    Err(_) => {
+                        exists = false;
+                        resolved.push(component);
+                    }
+                }
+            }
+            Component::Normal(part) => resolved.push(part),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            // absolute paths are never inside the repository
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(outside(PathBuf::from(filepath)).into())
+            }
+        }
+    }
+
+    match resolved.starts_with(&canonical_root) {
+        true => Ok(resolved),
+        false => Err(outside(resolved).into()),
+    }
+}
+
+fn check_output<S: AsRef<OsStr>>(args: &[S], output: Output) -> Result<String> {
+    if !output.status.success() {
+        // some failures (e.g. nothing to commit) are only reported on stdout
+        let reason = match String::from_utf8_lossy(&output.stderr).trim() {
+            "" => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            stderr => stderr.to_string(),
+        };
+        let error_message = format!(
+            "git {} failed: {}",
+            args.iter()
+                .map(|a| a.as_ref().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" "),
+            reason,
+        );
+        return Err(error_message.into());
+    }
+
+    Ok(std::str::from_utf8(&output.stdout)?.to_string())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fragment {
+    pub filepath: String,
+    pub line_range: LineRange,
+    /// 0-based column on the first line of the range that the fragment points at, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+impl Fragment {
+    /// Whether the fragment covers the 0-based line `line_no` of its file
+    pub fn contains_line(&self, line_no: usize) -> bool {
+        self.line_range.0 <= line_no && line_no <= self.line_range.1
+    }
+
+    /// Whether both fragments cover at least one common line of the same file. Fragments that
+    /// only touch, one ending right before the other starts, don't overlap
+    pub fn overlaps(&self, other: &Fragment) -> bool {
+        self.filepath == other.filepath
+            && self.line_range.0 <= other.line_range.1
+            && other.line_range.0 <= self.line_range.1
+    }
+
+    /// Read the file of this fragment relative to the current directory, refusing it when it
+    /// resolves outside of it, see [`resolve_within`]
+    pub fn read_file(&self) -> Result<String> {
+        let path = resolve_within(Path::new(""), &self.filepath, false)?;
+        Ok(std::fs::read_to_string(path)?)
+    }
+
+    /// Build the fragment spanning the lines of the byte range `start_byte..end_byte` of a file
+    /// in `repository`, such as the span of a tree-sitter node. The file is read through
+    /// [`GitRepository::read_file`], so a path outside of the repository is refused
+    pub fn from_byte_range(
+        repository: &GitRepository,
+        filepath: &str,
+        start_byte: usize,
+        end_byte: usize,
+    ) -> Result<Fragment> {
+        let content = repository.read_file(filepath)?;
+
+        if start_byte > end_byte || end_byte > content.len() {
+            let error_message = format!(
+                "The byte range {}..{} is not within the {} bytes of {}.",
+                start_byte,
+                end_byte,
+                content.len(),
+                filepath,
+            );
+            return Err(error_message.into());
+        }
+
+        if !content.is_char_boundary(start_byte) || !content.is_char_boundary(end_byte) {
+            let error_message = format!(
+                "The byte range {}..{} splits a character of {}.",
+                start_byte, end_byte, filepath,
+            );
+            return Err(error_message.into());
+        }
+
+        // newlines are never part of a multi-byte character, so counting bytes is safe
+        let line_of = |byte: usize| {
+            content.as_bytes()[..byte]
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count()
+        };
+        let last_byte = match end_byte > start_byte {
+            true => end_byte - 1,
+            false => start_byte,
+        };
+
+        Ok(Fragment {
+            filepath: filepath.to_string(),
+            line_range: (line_of(start_byte), line_of(last_byte)),
+            column: None,
+        })
+    }
+
+    /// Build the fragment of the `count` lines starting at `start`, the form edits take when
+    /// they say how many lines to replace rather than which is the last
+    pub fn from_start_count(filepath: &str, start: usize, count: usize) -> Result<Fragment> {
+        if count == 0 {
+            let error_message = format!(
+                "A fragment of {} starting at line {} should span at least one line.",
+                filepath, start,
+            );
+            return Err(error_message.into());
+        }
+
+        Ok(Fragment {
+            filepath: filepath.to_string(),
+            line_range: (start, start + count - 1),
+            column: None,
+        })
+    }
+
+    /// Combine fragments of the same file whose line ranges overlap or are adjacent, so that no
+    /// line is covered twice. Fragments keep the order their files first appear in.
+    pub fn merge_overlapping(fragments: Vec<Fragment>) -> Vec<Fragment> {
+        let mut merged: Vec<Fragment> = Vec::new();
+
+        let mut files = Vec::new();
+        for fragment in &fragments {
+            if !files.contains(&fragment.filepath) {
+                files.push(fragment.filepath.clone());
+            }
+        }
+
+        for file in files {
+            let mut ranges = fragments
+                .iter()
+                .filter(|f| f.filepath == file)
+                .map(|f| f.line_range)
+                .collect::<Vec<_>>();
+            ranges.sort();
+
+            let start = merged.len();
+            for (begin, end) in ranges {
+                match merged[start..].last_mut() {
+                    Some(last) if begin <= last.line_range.1 + 1 => {
+                        last.line_range.1 = last.line_range.1.max(end);
+                    }
+                    _ => merged.push(Fragment {
+                        filepath: file.clone(),
+                        line_range: (begin, end),
+                        column: None,
+                    }),
+                }
+            }
+        }
+
+        merged
+    }
+
+    pub fn read_lines(&self) -> Result<String> {
+        self.select_lines(&self.read_file()?)
+    }
+
+    /// Pick out the lines covered by this fragment from the contents of its file
+    pub fn select_lines(&self, content: &str) -> Result<String> {
+        let lines = content.lines().collect::<Vec<_>>();
+
+        self.check_range(lines.len())?;
+
+        // the line 0 of an empty file is only a position to insert at, with nothing on it
+        if lines.is_empty() {
+            return Ok(String::new());
+        }
+
+        Ok(lines[self.line_range.0..=self.line_range.1].join("\n"))
+    }
+
+    /// Ensure the line range is ordered and lies within a file of `line_count` lines
+    fn check_range(&self, line_count: usize) -> Result<()> {
+        let (start, end) = self.line_range;
+
+        if start > end {
+            let error_message = format!(
+                "The start line {} is after the end line {} of the range.",
+                start, end,
+            );
+            return Err(error_message.into());
+        }
+
+        // the range is inclusive, though an empty file still has a line 0 to insert at
+        if [start, end].iter().any(|r| *r >= line_count.max(1)) {
+            let error_message = match line_count {
+                0 => format!(
+                    "The line range {:?} is not in bound of the file, which is empty so only line 0 can be used.",
+                    self.line_range,
+                ),
+                _ => format!(
+                    "The line range {:?} is not in bound of the file, whose last line is {}.",
+                    self.line_range,
+                    line_count - 1,
+                ),
+            };
+            return Err(error_message.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// the first and last line of a fragment, both included and counted from 0. this is the one
+/// convention used by every fragment and transformation, whatever the numbering shown to the
+/// model (see [`GitRepository::line_base`]) or used by compilers
+type LineRange = (usize, usize);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Comment {
+    pub message: String,
+    pub fragments: Vec<Fragment>,
+}
+
+/// the fields of a GitHub pull request review comment that locate it in the code
+#[derive(Deserialize)]
+struct GithubReviewComment {
+    body: String,
+    path: String,
+    line: Option<usize>,
+    start_line: Option<usize>,
+    // outdated comments no longer have a line, only the one they were originally made on
+    original_line: Option<usize>,
+    original_start_line: Option<usize>,
+}
+
+impl Comment {
+    /// Build a comment from the JSON payload of a GitHub pull request review comment. GitHub's
+    /// 1-based inclusive lines are converted to the 0-based inclusive range of a [`Fragment`].
+    pub fn from_github_review(json: &str) -> Result<Comment> {
+        let review = serde_json::from_str::<GithubReviewComment>(json)?;
+
+        let end = review
+            .line
+            .or(review.original_line)
+            .ok_or("The review comment is not attached to a line.")?;
+        let start = match review.line {
+            Some(_) => review.start_line,
+            None => review.original_start_line,
+        }
+        .unwrap_or(end);
+
+        if start == 0 || end == 0 {
+            return Err("GitHub review comment lines start at 1.".into());
+        }
+
+        Ok(Comment {
+            message: review.body,
+            fragments: vec![Fragment {
+                filepath: review.path,
+                line_range: (start - 1, end - 1),
+                column: None,
+            }],
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum Transformation {
+    RenameSymbol {
+        old: String,
+        new: String,
+    },
+    CreateFile {
+        path: String,
+    },
+    DeleteFile {
+        path: String,
+    },
+    MoveFile {
+        old: String,
+        new: String,
+    },
+    UpdateFragment {
+        fragment: Fragment,
+        updated_lines: Vec<String>,
+        /// the lines the fragment is believed to hold. the update is rejected when the file
+        /// differs, as the line numbers are probably stale
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expected: Option<Vec<String>>,
+    },
+    /// replace the characters from column `columns.0` of the first line of `fragment` up to
+    /// column `columns.1` of its last line. columns are 0-based counts of characters, and the
+    /// end is exclusive
+    UpdateSpan {
+        fragment: Fragment,
+        columns: (usize, usize),
+        replacement: String,
+    },
+    /// replace the lines of the single match of the regex `pattern`, along with the rest of the
+    /// block when a brace opens after the match, e.g. a whole function for `fn old_name`
+    ReplaceMatching {
+        filepath: String,
+        pattern: String,
+        content: Vec<String>,
+    },
+    /// insert lines before `line_no`, or after the last line when it equals the line count
+    InsertFragment {
+        filepath: String,
+        line_no: usize,
+        content: Vec<String>,
+    },
+    /// a unified diff relative to the repository root
+    ApplyPatch {
+        patch: String,
+    },
+    /// a deliberate decision to change nothing, e.g. an update to the lines a fragment already
+    /// holds
+    Noop,
+}
+
+/// a parsed transformation along with how far it can be trusted and where it came from, for
+/// preferring some edits over others and surfacing them for approval
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScoredTransformation {
+    #[serde(flatten)]
+    pub transformation: Transformation,
+    /// confidence in the edit from 0 to 1, when the model gave one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
+    /// what the edit was made for, e.g. the location of the comment or build error behind it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl From<Transformation> for ScoredTransformation {
+    fn from(transformation: Transformation) -> Self {
+        Self {
+            transformation,
+            score: None,
+            source: None,
+        }
+    }
+}
+
+/// the variant of a [`Transformation`] without its fields, for deciding which are allowed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TransformationKind {
+    RenameSymbol,
+    CreateFile,
+    DeleteFile,
+    MoveFile,
+    UpdateFragment,
+    UpdateSpan,
+    ReplaceMatching,
+    InsertFragment,
+    ApplyPatch,
+    Noop,
+}
+
+impl TransformationKind {
+    /// every kind of transformation
+    pub const ALL: &'static [TransformationKind] = &[
+        TransformationKind::RenameSymbol,
+        TransformationKind::CreateFile,
+        TransformationKind::DeleteFile,
+        TransformationKind::MoveFile,
+        TransformationKind::UpdateFragment,
+        TransformationKind::UpdateSpan,
+        TransformationKind::ReplaceMatching,
+        TransformationKind::InsertFragment,
+        TransformationKind::ApplyPatch,
+        TransformationKind::Noop,
+    ];
+
+    /// the kinds that only edit files that already exist, never creating, deleting, or moving
+    /// one. patches are left out since they can do any of those
+    pub const EDITS: &'static [TransformationKind] = &[
+        TransformationKind::RenameSymbol,
+        TransformationKind::UpdateFragment,
+        TransformationKind::UpdateSpan,
+        TransformationKind::ReplaceMatching,
+        TransformationKind::InsertFragment,
+        TransformationKind::Noop,
+    ];
+}
+
+impl std::fmt::Display for TransformationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl Transformation {
+    /// The variant of this transformation
+    pub fn kind(&self) -> TransformationKind {
+        match self {
+            Transformation::RenameSymbol { .. } => TransformationKind::RenameSymbol,
+            Transformation::CreateFile { .. } => TransformationKind::CreateFile,
+            Transformation::DeleteFile { .. } => TransformationKind::DeleteFile,
+            Transformation::MoveFile { .. } => TransformationKind::MoveFile,
+            Transformation::UpdateFragment { .. } => TransformationKind::UpdateFragment,
+            Transformation::UpdateSpan { .. } => TransformationKind::UpdateSpan,
+            Transformation::ReplaceMatching { .. } => TransformationKind::ReplaceMatching,
+            Transformation::InsertFragment { .. } => TransformationKind::InsertFragment,
+            Transformation::ApplyPatch { .. } => TransformationKind::ApplyPatch,
+            Transformation::Noop => TransformationKind::Noop,
+        }
+    }
+
+    /// Convert the line numbers of a transformation written against context numbered from
+    /// `line_base` into the 0-based lines that [`GitRepository::transform`] expects
+    pub fn into_zero_based(self, line_base: usize) -> Result<Self> {
+        let line = |line: usize| {
+            line.checked_sub(line_base).ok_or_else(|| {
+                format!("The line {line} is before the first line {line_base} of the file.")
+            })
+        };
+
+        Ok(match self {
+            Transformation::UpdateFragment {
+                mut fragment,
+                updated_lines,
+                expected,
+            } => {
+                fragment.line_range = (line(fragment.line_range.0)?, line(fragment.line_range.1)?);
+                Transformation::UpdateFragment {
+                    fragment,
+                    updated_lines,
+                    expected,
+                }
+            }
+            Transformation::UpdateSpan {
+                mut fragment,
+                columns,
+                replacement,
+            } => {
+                fragment.line_range = (line(fragment.line_range.0)?, line(fragment.line_range.1)?);
+                Transformation::UpdateSpan {
+                    fragment,
+                    columns,
+                    replacement,
+                }
+            }
+            Transformation::InsertFragment {
+                filepath,
+                line_no,
+                content,
+            } => Transformation::InsertFragment {
+                filepath,
+                line_no: line(line_no)?,
+                content,
+            },
+            transformation => transformation,
+        })
+    }
+
+    /// Replace a line edit that would leave its file as it is in `repository` with
+    /// [`Transformation::Noop`]. Edits that can't be applied are kept, so that applying them
+    /// reports why
+    pub fn or_noop(self, repository: &GitRepository) -> Self {
+        let unchanged = match &self {
+            Transformation::UpdateFragment { .. }
+            | Transformation::UpdateSpan { .. }
+            | Transformation::ReplaceMatching { .. } => repository
+                .updated_content(&self)
+                .is_ok_and(|(content, updated)| updated == content),
+            Transformation::InsertFragment { content, .. } => content.is_empty(),
+            _ => false,
+        };
+
+        match unchanged {
+            true => Transformation::Noop,
+            false => self,
+        }
+    }
+
+    /// The file a transformation is mostly about, if it is about a single one
+    pub fn filepath(&self) -> Option<&str> {
+        match self {
+            Transformation::UpdateFragment { fragment, .. }
+            | Transformation::UpdateSpan { fragment, .. } => Some(&fragment.filepath),
+            Transformation::InsertFragment { filepath, .. }
+            | Transformation::ReplaceMatching { filepath, .. } => Some(filepath),
+            Transformation::CreateFile { path } | Transformation::DeleteFile { path } => Some(path),
+            Transformation::MoveFile { old, .. } => Some(old),
+            Transformation::RenameSymbol { .. }
+            | Transformation::ApplyPatch { .. }
+            | Transformation::Noop => None,
+        }
+    }
+
+    /// A short description of what the transformation does, e.g. `replace lines 3-5 with 2 lines`
+    pub fn summary(&self) -> String {
+        let lines = |count: usize| match count {
+            1 => "1 line".to_string(),
+            count => format!("{count} lines"),
+        };
+
+        match self {
+            Transformation::UpdateFragment {
+                fragment,
+                updated_lines,
+                ..
+            } => {
+                let (start, end) = fragment.line_range;
+                let range = match start == end {
+                    true => format!("line {start}"),
+                    false => format!("lines {start}-{end}"),
+                };
+                match updated_lines.is_empty() {
+                    true => format!("remove {range}"),
+                    false => format!("replace {range} with {}", lines(updated_lines.len())),
+                }
+            }
+            Transformation::UpdateSpan {
+                fragment, columns, ..
+            } => match fragment.line_range {
+                (start, end) if start == end => {
+                    format!(
+                        "replace columns {}-{} of line {start}",
+                        columns.0, columns.1
+                    )
+                }
+                (start, end) => format!(
+                    "replace from column {} of line {start} to column {} of line {end}",
+                    columns.0, columns.1
+                ),
+            },
+            Transformation::ReplaceMatching {
+                pattern, content, ..
+            } => format!(
+                "replace the block matching `{pattern}` with {}",
+                lines(content.len())
+            ),
+            Transformation::InsertFragment {
+                line_no, content, ..
+            } => format!("insert {} before line {line_no}", lines(content.len())),
+            Transformation::CreateFile { .. } => "create the file".to_string(),
+            Transformation::DeleteFile { .. } => "delete the file".to_string(),
+            Transformation::MoveFile { new, .. } => format!("move the file to {new}"),
+            Transformation::RenameSymbol { old, new } => {
+                format!("rename `{old}` to `{new}` everywhere")
+            }
+            Transformation::ApplyPatch { patch } => format!(
+                "apply a patch to {}",
+                patch
+                    .lines()
+                    .filter_map(|l| l.strip_prefix("+++ b/"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Transformation::Noop => "change nothing".to_string(),
+        }
+    }
+
+    /// Render the change this transformation would make to `repository` as a unified diff,
+    /// without applying it
+    pub fn to_diff(&self, repository: &GitRepository) -> Result<Diff> {
+        match self {
+            Transformation::UpdateFragment { .. }
+            | Transformation::UpdateSpan { .. }
+            | Transformation::InsertFragment { .. }
+            | Transformation::ReplaceMatching { .. } => {
+                let filepath = self.filepath().unwrap_or_default();
+                let (content, updated) = repository.updated_content(self)?;
+                Ok(unified_diff(
+                    Some(filepath),
+                    Some(filepath),
+                    &content,
+                    &updated,
+                ))
+            }
+            Transformation::CreateFile { path } => Ok(unified_diff(None, Some(path), "", "")),
+            Transformation::DeleteFile { path } => {
+                let content = repository.read_file(path)?;
+                Ok(unified_diff(Some(path), None, &content, ""))
+            }
+            Transformation::MoveFile { old, new } => {
+                let content = repository.read_file(old)?;
+                if repository.resolve(new)?.exists() {
+                    let error_message =
+                        format!("Cannot move {old} to {new}, which already exists.");
+                    return Err(error_message.into());
+                }
+                Ok(unified_diff(Some(old), Some(new), &content, &content))
+            }
+            Transformation::RenameSymbol { old, new } => {
+                let symbol = Regex::new(&format!("\\b{}\\b", regex::escape(old)))?;
+
+                let mut diff = Diff::new();
+                for file in repository.workspace_files()? {
+                    let Ok(content) = std::fs::read_to_string(repository.path(&file)) else {
+                        continue;
+                    };
+                    let renamed = symbol.replace_all(&content, NoExpand(new));
+                    diff += &unified_diff(Some(&file), Some(&file), &content, &renamed);
+                }
+
+                Ok(diff)
+            }
+            Transformation::ApplyPatch { patch } => Ok(patch.clone()),
+            Transformation::Noop => Ok(Diff::new()),
+        }
+    }
+}
+
+/// Render the change from `old` to `new` as a git style unified diff, using a single hunk around
+/// everything between the common leading and trailing lines. A missing path stands for a file
+/// that is created or deleted, and different paths for a move.
+fn unified_diff(old_path: Option<&str>, new_path: Option<&str>, old: &str, new: &str) -> Diff {
+    const CONTEXT: usize = 3;
+
+    let (a, b) = match (old_path, new_path) {
+        (Some(a), Some(b)) => (a, b),
+        (Some(path), None) | (None, Some(path)) => (path, path),
+        (None, None) => return Diff::new(),
+    };
+    if old_path == new_path && old == new {
+        return Diff::new();
+    }
+
+    let mut diff = format!("diff --git a/{a} b/{b}\n");
+    match (old_path, new_path) {
+        (None, _) => diff += "new file mode 100644\n",
+        (_, None) => diff += "deleted file mode 100644\n",
+        _ if a != b => diff += &format!("similarity index 100%\nrename from {a}\nrename to {b}\n"),
+        _ => {}
+    }
+    if old == new {
+        return diff;
+    }
+
+    diff += &match old_path {
+        Some(a) => format!("--- a/{a}\n"),
+        None => "--- /dev/null\n".to_string(),
+    };
+    diff += &match new_path {
+        Some(b) => format!("+++ b/{b}\n"),
+        None => "+++ /dev/null\n".to_string(),
+    };
+
+    let old_lines = old.lines().collect::<Vec<_>>();
+    let new_lines = new.lines().collect::<Vec<_>>();
+
+    // the last line of a side without a final newline is flagged like git does, and differs
+    // from the same line with a newline
+    let old_unterminated = |i: usize| i + 1 == old_lines.len() && !old.ends_with('\n');
+    let new_unterminated = |i: usize| i + 1 == new_lines.len() && !new.ends_with('\n');
+    let same = |i: usize, j: usize| {
+        old_lines[i] == new_lines[j] && old_unterminated(i) == new_unterminated(j)
+    };
+
+    let common = old_lines.len().min(new_lines.len());
+    let prefix = (0..common).take_while(|&i| same(i, i)).count();
+    let suffix = (0..common - prefix)
+        .take_while(|&k| same(old_lines.len() - 1 - k, new_lines.len() - 1 - k))
+        .count();
+
+    let start = prefix.saturating_sub(CONTEXT);
+    let (old_end, new_end) = (old_lines.len() - suffix, new_lines.len() - suffix);
+    let trailing = suffix.min(CONTEXT);
+
+    let range = |start: usize, count: usize| match count {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    };
+    diff += &format!(
+        "@@ -{} +{} @@\n",
+        range(start, old_end + trailing - start),
+        range(start, new_end + trailing - start),
+    );
+
+    let mut push = |marker: char, line: &str, unterminated: bool| {
+        diff.push(marker);
+        diff += line;
+        diff.push('\n');
+        if unterminated {
+            diff += "\\ No newline at end of file\n";
+        }
+    };
+
+    for line in &old_lines[start..prefix] {
+        push(' ', line, false);
+    }
+    for (i, line) in old_lines.iter().enumerate().take(old_end).skip(prefix) {
+        push('-', line, old_unterminated(i));
+    }
+    for (i, line) in new_lines.iter().enumerate().take(new_end).skip(prefix) {
+        push('+', line, new_unterminated(i));
+    }
+    for (i, line) in old_lines.iter().enumerate().skip(old_end).take(trailing) {
+        push(' ', line, old_unterminated(i));
+    }
+
+    diff
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A git repository in a fresh temporary directory named after `name`, holding `files`
+    /// committed as its first commit
+    pub(crate) fn temp_repo(name: &str, files: &[(&str, &str)]) -> GitRepository {
+        let root = std::env::temp_dir().join(format!("rakune-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+
+        let repository = GitRepository {
+            root,
+            ..Default::default()
+        };
+        repository.git(&["init", "-q"]).unwrap();
+        repository.git(&["config", "user.name", "rakune"]).unwrap();
+        repository
+            .git(&["config", "user.email", "rakune@example.com"])
+            .unwrap();
+        repository
+            .git(&["config", "commit.gpgsign", "false"])
+            .unwrap();
+
+        for (filepath, content) in files {
+            write_file(&repository, filepath, content);
+        }
+        if !files.is_empty() {
+            repository.git(&["add", "-A"]).unwrap();
+            repository.git(&["commit", "-q", "-m", "init"]).unwrap();
+        }
+
+        repository
+    }
+
+    /// Write `content` to `filepath` in `repository`, creating its directories
+    pub(crate) fn write_file(repository: &GitRepository, filepath: &str, content: &str) {
+        let path = repository.path(filepath);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn commit_uses_author_and_signoff() {
+        let mut repository = temp_repo("commit-author", &[("a.txt", "a\n")]);
+        write_file(&repository, "a.txt", "b\n");
+
+        let options = CommitOptions {
+            author: Some(Author {
+                name: "rakune-bot".to_string(),
+                email: "bot@example.com".to_string(),
+            }),
+            signoff: true,
+            ..Default::default()
+        };
+        repository.commit("edit a", &options).unwrap();
+
+        let log = repository
+            .git(&["log", "-1", "--format=%an <%ae>%n%B"])
+            .unwrap();
+        assert!(log.starts_with("rakune-bot <bot@example.com>"));
+        assert!(log.contains("Signed-off-by: rakune"));
+    }
+
+    #[test]
+    fn commit_without_changes_fails() {
+        let mut repository = temp_repo("commit-nothing", &[("a.txt", "a\n")]);
+        let head = repository.git(&["rev-parse", "HEAD"]).unwrap();
+
+        let result = repository.commit("nothing", &CommitOptions::default());
+
+        assert!(result.is_err());
+        assert_eq!(repository.git(&["rev-parse", "HEAD"]).unwrap(), head);
+    }
+
+    #[test]
+    fn failed_git_reports_stderr() {
+        let repository = temp_repo("git-stderr", &[("a.txt", "a\n")]);
+
+        let error = repository
+            .diff(Some(&"no-such-revision".to_string()))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.starts_with("git diff no-such-revision failed"));
+        assert!(error.contains("unknown revision"));
+    }
+
+    #[test]
+    fn created_file_is_populated() {
+        let mut repository = temp_repo("create-populate", &[("a.txt", "a\n")]);
+        let lines = ["fn main() {", "    println!(\"new\");", "}"];
+
+        repository
+            .transform_all(&[
+                Transformation::CreateFile {
+                    path: "src/new.rs".to_string(),
+                },
+                Transformation::UpdateFragment {
+                    fragment: Fragment {
+                        filepath: "src/new.rs".to_string(),
+                        line_range: (0, 0),
+                        column: None,
+                    },
+                    updated_lines: lines.iter().map(|line| line.to_string()).collect(),
+                    expected: None,
+                },
+            ])
+            .unwrap();
+
+        let content = repository.read_file("src/new.rs").unwrap();
+        assert_eq!(content.lines().collect::<Vec<_>>(), lines);
+    }
+
+    fn fragment(filepath: &str, line_range: (usize, usize)) -> Fragment {
+        Fragment {
+            filepath: filepath.to_string(),
+            line_range,
+            column: None,
+        }
+    }
+
+    #[test]
+    fn reversed_range_names_both_lines() {
+        let error = fragment("a.txt", (2, 0))
+            .select_lines("a\nb\nc")
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains('2'));
+        assert!(error.contains('0'));
+    }
+
+    #[test]
+    fn adjacent_fragments_merge() {
+        let merged =
+            Fragment::merge_overlapping(vec![fragment("a.rs", (4, 6)), fragment("a.rs", (0, 3))]);
+
+        assert_eq!(merged, vec![fragment("a.rs", (0, 6))]);
+    }
+
+    #[test]
+    fn overlapping_fragments_merge() {
+        let merged = Fragment::merge_overlapping(vec![
+            fragment("a.rs", (2, 8)),
+            fragment("a.rs", (0, 4)),
+            fragment("a.rs", (3, 5)),
+        ]);
+
+        assert_eq!(merged, vec![fragment("a.rs", (0, 8))]);
+    }
+
+    #[test]
+    fn disjoint_fragments_stay_apart() {
+        let merged = Fragment::merge_overlapping(vec![
+            fragment("b.rs", (0, 1)),
+            fragment("a.rs", (5, 6)),
+            fragment("a.rs", (0, 2)),
+            fragment("b.rs", (1, 3)),
+        ]);
+
+        assert_eq!(
+            merged,
+            vec![
+                fragment("b.rs", (0, 3)),
+                fragment("a.rs", (0, 2)),
+                fragment("a.rs", (5, 6)),
+            ]
+        );
+    }
+
+    fn update(filepath: &str, line_range: (usize, usize), lines: &[&str]) -> Transformation {
+        Transformation::UpdateFragment {
+            fragment: fragment(filepath, line_range),
+            updated_lines: lines.iter().map(|line| line.to_string()).collect(),
+            expected: None,
+        }
+    }
+
+    #[test]
+    fn crlf_endings_survive_an_update() {
+        let mut repository = temp_repo("crlf", &[("a.txt", "one\r\ntwo\r\nthree\r\n")]);
+
+        repository
+            .transform(&update("a.txt", (1, 1), &["TWO"]))
+            .unwrap();
+
+        let content = std::fs::read_to_string(repository.path("a.txt")).unwrap();
+        assert_eq!(content, "one\r\nTWO\r\nthree\r\n");
+    }
+
+    fn insert(filepath: &str, line_no: usize, lines: &[&str]) -> Transformation {
+        Transformation::InsertFragment {
+            filepath: filepath.to_string(),
+            line_no,
+            content: lines.iter().map(|line| line.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn insert_goes_before_the_line() {
+        let content = apply_to_content("a\nc\n", &insert("a.txt", 1, &["b"])).unwrap();
+
+        assert_eq!(content, "a\nb\nc\n");
+    }
+
+    #[test]
+    fn insert_at_the_line_count_appends() {
+        let content = apply_to_content("a\nb\n", &insert("a.txt", 2, &["c"])).unwrap();
+
+        assert_eq!(content, "a\nb\nc\n");
+    }
+
+    #[test]
+    fn append_after_an_unterminated_last_line() {
+        let mut repository = temp_repo("append-unterminated", &[("a.txt", "a\nb")]);
+
+        repository.transform(&insert("a.txt", 2, &["c"])).unwrap();
+
+        assert_eq!(repository.read_file("a.txt").unwrap(), "a\nb\nc");
+    }
+
+    #[test]
+    fn import_goes_after_the_last_use() {
+        let content = "use std::fs;\nuse std::io::{\n    Read,\n};\n\nfn main() {}\n";
+        let mut repository = temp_repo("import-after-use", &[("src/main.rs", content)]);
+
+        let added = repository
+            .add_import("src/main.rs", "use std::path::Path;")
+            .unwrap();
+
+        assert!(added);
+        assert_eq!(
+            repository.read_file("src/main.rs").unwrap(),
+            "use std::fs;\nuse std::io::{\n    Read,\n};\nuse std::path::Path;\n\nfn main() {}\n"
+        );
+    }
+
+    #[test]
+    fn import_goes_after_inner_attributes_without_uses() {
+        let content = "//! the crate\n#![allow(dead_code)]\nfn main() {}\n";
+        let mut repository = temp_repo("import-no-use", &[("src/main.rs", content)]);
+
+        repository
+            .add_import("src/main.rs", "use std::fs;")
+            .unwrap();
+
+        assert_eq!(
+            repository.read_file("src/main.rs").unwrap(),
+            "//! the crate\n#![allow(dead_code)]\nuse std::fs;\nfn main() {}\n"
+        );
+    }
+
+    #[test]
+    fn duplicate_import_is_skipped() {
+        let content = "use std::fs;\n\nfn main() {}\n";
+        let mut repository = temp_repo("import-duplicate", &[("src/main.rs", content)]);
+
+        let added = repository
+            .add_import("src/main.rs", "use std::fs;")
+            .unwrap();
+
+        assert!(!added);
+        assert_eq!(repository.read_file("src/main.rs").unwrap(), content);
+    }
+
+    #[test]
+    fn update_ending_past_the_last_line_is_an_error() {
+        let error = apply_to_content("a\nb\nc\n", &update("a.txt", (1, 3), &["x"]))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("(1, 3)"));
+        assert!(error.contains("last line is 2"));
+    }
+
+    #[test]
+    fn empty_file_selects_nothing() {
+        assert_eq!(fragment("a.txt", (0, 0)).select_lines("").unwrap(), "");
+        assert!(fragment("a.txt", (0, 1)).select_lines("").is_err());
+    }
+
+    #[test]
+    fn rendered_diff_matches_git() {
+        let mut repository = temp_repo("diff-render", &[("a.txt", "a\nb\nc\nd\n")]);
+        let transformation = update("a.txt", (1, 2), &["B", "C", "C2"]);
+
+        let rendered = transformation.to_diff(&repository).unwrap();
+        repository.transform(&transformation).unwrap();
+        let diff = repository.diff(None).unwrap();
+
+        let hunks = |diff: &str| diff[diff.find("--- ").unwrap()..].to_string();
+        assert_eq!(hunks(&rendered), hunks(&diff));
+    }
+
+    #[test]
+    fn update_with_mismatched_expected_lines_is_rejected() {
+        let transformation = Transformation::UpdateFragment {
+            fragment: fragment("a.txt", (1, 1)),
+            updated_lines: vec!["B".to_string()],
+            expected: Some(vec!["x".to_string()]),
+        };
+
+        let error = apply_to_content("a\nb\nc\n", &transformation).unwrap_err();
+
+        assert!(error
+            .to_string()
+            .contains("do not hold the expected content"));
+    }
+
+    #[test]
+    fn update_with_matching_expected_lines_applies() {
+        let transformation = Transformation::UpdateFragment {
+            fragment: fragment("a.txt", (1, 1)),
+            updated_lines: vec!["B".to_string()],
+            expected: Some(vec!["b".to_string()]),
+        };
+
+        let content = apply_to_content("a\nb\nc\n", &transformation).unwrap();
+
+        assert_eq!(content, "a\nB\nc\n");
+    }
+
+    #[test]
+    fn batch_of_edits_uses_the_original_line_numbers() {
+        let mut repository = temp_repo("batch-edits", &[("a.txt", "a\nb\nc\nd\n")]);
+
+        repository
+            .transform_all(&[
+                insert("a.txt", 1, &["after a"]),
+                update("a.txt", (2, 2), &["C"]),
+                insert("a.txt", 3, &["after c", "still after c"]),
+            ])
+            .unwrap();
+
+        let content = repository.read_file("a.txt").unwrap();
+        assert_eq!(content, "a\nafter a\nb\nC\nafter c\nstill after c\nd\n");
+    }
+
+    #[test]
+    fn failing_edit_rolls_back_the_others() {
+        let mut repository = temp_repo("atomic-rollback", &[("a.txt", "a\n"), ("b.txt", "b\n")]);
+
+        let result = repository.transform_all(&[
+            update("a.txt", (0, 0), &["A"]),
+            update("b.txt", (5, 5), &["B"]),
+        ]);
+
+        assert!(result.is_err());
+        assert_eq!(repository.read_file("a.txt").unwrap(), "a\n");
+        assert_eq!(repository.read_file("b.txt").unwrap(), "b\n");
+    }
+
+    #[test]
+    fn ignore_file_scopes_the_workspace() {
+        let mut repository = temp_repo(
+            "ignore-file",
+            &[
+                (IGNORE_FILE, "vendor/\n!vendor/keep.rs\n"),
+                ("src/lib.rs", ""),
+                ("vendor/dep.rs", ""),
+                ("vendor/keep.rs", ""),
+            ],
+        );
+        repository.read_ignore_file().unwrap();
+
+        let files = repository.workspace_files().unwrap();
+
+        assert!(files.contains(&"src/lib.rs".to_string()));
+        assert!(files.contains(&"vendor/keep.rs".to_string()));
+        assert!(!files.contains(&"vendor/dep.rs".to_string()));
+    }
+
+    #[test]
+    fn ill_indented_insert_is_formatted() {
+        let mut repository = GitRepository {
+            post_transform_format: true,
+            ..temp_repo(
+                "format-insert",
+                &[
+                    ("Cargo.toml", "[package]\nname = \"x\"\n"),
+                    ("src/lib.rs", "fn f() {\n}\n"),
+                ],
+            )
+        };
+
+        repository
+            .transform(&insert("src/lib.rs", 1, &["let x   =  1;"]))
+            .unwrap();
+
+        let content = repository.read_file("src/lib.rs").unwrap();
+        // rustfmt may not be installed, in which case the file is left as it was edited
+        if repository.format_file("src/lib.rs").unwrap() {
+            assert_eq!(content, "fn f() {\n    let x = 1;\n}\n");
+        } else {
+            assert_eq!(content, "fn f() {\nlet x   =  1;\n}\n");
+        }
+    }
+
+    #[test]
+    fn files_without_a_formatter_are_left_alone() {
+        let repository = temp_repo("format-unknown", &[("notes.txt", "  a\n")]);
+
+        assert!(!repository.format_file("notes.txt").unwrap());
+        assert_eq!(repository.read_file("notes.txt").unwrap(), "  a\n");
+    }
+
+    #[test]
+    fn fragments_that_only_touch_do_not_overlap() {
+        assert!(!fragment("a.rs", (0, 3)).overlaps(&fragment("a.rs", (4, 6))));
+        assert!(!fragment("a.rs", (4, 6)).overlaps(&fragment("a.rs", (0, 3))));
+    }
+
+    #[test]
+    fn equal_and_nested_fragments_overlap() {
+        assert!(fragment("a.rs", (2, 5)).overlaps(&fragment("a.rs", (2, 5))));
+        assert!(fragment("a.rs", (0, 9)).overlaps(&fragment("a.rs", (3, 4))));
+        assert!(fragment("a.rs", (3, 4)).overlaps(&fragment("a.rs", (0, 9))));
+        assert!(fragment("a.rs", (3, 3)).overlaps(&fragment("a.rs", (0, 3))));
+        assert!(!fragment("a.rs", (0, 9)).overlaps(&fragment("b.rs", (3, 4))));
+    }
+
+    #[test]
+    fn fragment_contains_its_bounds() {
+        let fragment = fragment("a.rs", (2, 4));
+
+        assert!(!fragment.contains_line(1));
+        assert!(fragment.contains_line(2));
+        assert!(fragment.contains_line(4));
+        assert!(!fragment.contains_line(5));
+    }
+
+    #[test]
+    fn oversized_file_is_refused() {
+        let big = "x\n".repeat(100);
+        let mut repository = GitRepository {
+            max_file_size: Some(64),
+            ..temp_repo("oversized", &[("big.txt", &big), ("small.txt", "a\n")])
+        };
+
+        let error = repository.read_file("big.txt").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RakuneError>(),
+            Some(RakuneError::FileTooLarge {
+                size: 200,
+                limit: 64,
+                ..
+            })
+        ));
+        assert!(repository
+            .transform(&update("big.txt", (0, 0), &["y"]))
+            .is_err());
+        assert_eq!(
+            std::fs::read_to_string(repository.path("big.txt")).unwrap(),
+            big
+        );
+
+        assert!(repository.read_file("small.txt").is_ok());
+    }
+
+    #[test]
+    fn exempt_large_file_is_read() {
+        let big = "x\n".repeat(100);
+        let repository = GitRepository {
+            max_file_size: Some(64),
+            large_files: vec!["*.txt".to_string()],
+            ..temp_repo("oversized-exempt", &[("big.txt", &big)])
+        };
+
+        assert_eq!(repository.read_file("big.txt").unwrap(), big);
+    }
+
+    #[test]
+    fn relative_path_is_normalized() {
+        let repository = temp_repo("relative-path", &[("src/a.rs", "")]);
+
+        assert_eq!(repository.relative_path("./src/a.rs").unwrap(), "src/a.rs");
+        assert_eq!(
+            repository.relative_path("src/x/../a.rs").unwrap(),
+            "src/a.rs"
+        );
+        assert_eq!(
+            repository.relative_path("src/new.rs").unwrap(),
+            "src/new.rs"
+        );
+        assert!(repository.relative_path("../a.rs").is_err());
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn reindent_follows_spaces() {
+        let content = "fn f() {\n    let a = 1;\n}\n";
+
+        let reindented = reindent(content, 1, &lines(&["let b = 2;", "if b {", "  b;", "}"]));
+
+        assert_eq!(
+            reindented,
+            ["    let b = 2;", "    if b {", "      b;", "    }"]
+        );
+    }
+
+    #[test]
+    fn reindent_follows_tabs() {
+        let content = "fn f() {\n\tif a {\n\t\tlet a = 1;\n\t}\n}\n";
+
+        let reindented = reindent(content, 2, &lines(&["    let b = 2;", "", "    b;"]));
+
+        assert_eq!(reindented, ["\t\tlet b = 2;", "", "\t\tb;"]);
+    }
+
+    #[test]
+    fn reindent_skips_blank_lines_for_the_target() {
+        let content = "fn f() {\n\n    let a = 1;\n}\n";
+
+        let reindented = reindent(content, 1, &lines(&["let b = 2;"]));
+
+        assert_eq!(reindented, ["    let b = 2;"]);
+    }
+
+    #[test]
+    fn identity_edit_touches_neither_file_nor_git() {
+        let mut repository = temp_repo("identity-edit", &[("a.txt", "a\nb\n")]);
+        let identity = update("a.txt", (1, 1), &["b"]);
+        let path = repository.path("a.txt");
+        let modified = || std::fs::metadata(&path).unwrap().modified();
+        let before = modified().unwrap();
+        let head = repository.git(&["rev-parse", "HEAD"]).unwrap();
+
+        assert!(matches!(
+            identity.clone().or_noop(&repository),
+            Transformation::Noop
+        ));
+        // give a rewrite the chance to show up in the mtime
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        repository.transform(&identity).unwrap();
+
+        assert_eq!(modified().unwrap(), before);
+        assert_eq!(repository.git(&["status", "--porcelain"]).unwrap(), "");
+        assert_eq!(repository.git(&["rev-parse", "HEAD"]).unwrap(), head);
+    }
+
+    #[test]
+    fn real_edit_is_not_a_noop() {
+        let repository = temp_repo("real-edit", &[("a.txt", "a\nb\n")]);
+        let edit = update("a.txt", (1, 1), &["B"]);
+
+        assert!(matches!(
+            edit.or_noop(&repository),
+            Transformation::UpdateFragment { .. }
+        ));
+    }
+
+    fn span(
+        line_range: (usize, usize),
+        columns: (usize, usize),
+        replacement: &str,
+    ) -> Transformation {
+        Transformation::UpdateSpan {
+            fragment: fragment("a.rs", line_range),
+            columns,
+            replacement: replacement.to_string(),
+        }
+    }
+
+    #[test]
+    fn span_edits_mid_line() {
+        let content =
+            apply_to_content("let old = 1;\nold;\n", &span((0, 0), (4, 7), "new")).unwrap();
+
+        assert_eq!(content, "let new = 1;\nold;\n");
+    }
+
+    #[test]
+    fn span_columns_count_characters() {
+        let content = apply_to_content("let é = \"ü\";\n", &span((0, 0), (9, 10), "ö")).unwrap();
+
+        assert_eq!(content, "let é = \"ö\";\n");
+    }
+
+    #[test]
+    fn span_may_cross_lines() {
+        let content = apply_to_content("foo(a,\n    b);\n", &span((0, 1), (4, 5), "x")).unwrap();
+
+        assert_eq!(content, "foo(x);\n");
+    }
+
+    #[test]
+    fn span_past_the_end_of_the_line_is_an_error() {
+        let error = apply_to_content("ab\n", &span((0, 0), (1, 3), "x"))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("column 3 is past the end of line 0"));
+    }
+
+    #[test]
+    fn reversed_span_is_an_error() {
+        let error = apply_to_content("abc\n", &span((0, 0), (2, 1), "x"))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("start column 2 is after the end column 1"));
+    }
+
+    #[test]
+    fn revert_file_keeps_the_other_edits() {
+        let repository = temp_repo("revert-tracked", &[("a.txt", "a\n"), ("b.txt", "b\n")]);
+        write_file(&repository, "a.txt", "bad\n");
+        write_file(&repository, "b.txt", "good\n");
+
+        repository.revert_file("a.txt").unwrap();
+
+        assert_eq!(repository.read_file("a.txt").unwrap(), "a\n");
+        assert_eq!(repository.read_file("b.txt").unwrap(), "good\n");
+    }
+
+    #[test]
+    fn revert_file_removes_a_new_file() {
+        let repository = temp_repo("revert-created", &[("a.txt", "a\n")]);
+        write_file(&repository, "new.txt", "new\n");
+        repository.git(&["add", "new.txt"]).unwrap();
+
+        repository.revert_file("new.txt").unwrap();
+
+        assert!(!repository.path("new.txt").exists());
+        assert_eq!(repository.git(&["status", "--porcelain"]).unwrap(), "");
+    }
+
+    #[test]
+    fn start_and_count_give_an_inclusive_range() {
+        assert_eq!(
+            Fragment::from_start_count("a.rs", 3, 1).unwrap(),
+            fragment("a.rs", (3, 3))
+        );
+        assert_eq!(
+            Fragment::from_start_count("a.rs", 0, 4).unwrap(),
+            fragment("a.rs", (0, 3))
+        );
+    }
+
+    #[test]
+    fn zero_count_is_an_error() {
+        let error = Fragment::from_start_count("a.rs", 3, 0).unwrap_err();
+
+        assert!(error.to_string().contains("at least one line"));
+    }
+
+    const SMALL_RUST: &str =
+        "fn old_name() {\n    if true {\n        println!(\"}\");\n    }\n}\n\nfn other() {}\n";
+
+    fn replace_matching(pattern: &str, lines: &[&str]) -> Transformation {
+        Transformation::ReplaceMatching {
+            filepath: "a.rs".to_string(),
+            pattern: pattern.to_string(),
+            content: lines.iter().map(|line| line.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn matching_block_is_replaced_through_its_brace() {
+        let transformation = replace_matching("fn old_name", &["fn new_name() {}"]);
+
+        let content = apply_to_content(SMALL_RUST, &transformation).unwrap();
+
+        assert_eq!(content, "fn new_name() {}\n\nfn other() {}\n");
+    }
+
+    #[test]
+    fn pattern_without_a_match_is_an_error() {
+        let error = apply_to_content(SMALL_RUST, &replace_matching("fn missing", &["x"]))
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(error, "The pattern `fn missing` matches nothing in a.rs.");
+    }
+
+    #[test]
+    fn pattern_with_several_matches_is_an_error() {
+        let error = apply_to_content(SMALL_RUST, &replace_matching("fn \\w+", &["x"]))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("matches 2 places in a.rs, on lines 0, 6"));
+    }
+
+    #[test]
+    fn invalid_pattern_is_an_error() {
+        let error = apply_to_content(SMALL_RUST, &replace_matching("fn (", &["x"]))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("is not a valid regex"));
+    }
+
+    #[test]
+    fn only_sources_and_manifests_affect_a_build() {
+        let repository = temp_repo(
+            "affects-build",
+            &[
+                ("Cargo.toml", "[package]\nname = \"x\"\n"),
+                ("notes/a.txt", ""),
+            ],
+        );
+
+        assert!(repository.affects_build("src/lib.rs"));
+        assert!(repository.affects_build("Cargo.toml"));
+        assert!(!repository.affects_build("README.md"));
+        assert!(!repository.affects_build("notes/a.txt"));
+    }
+
+    #[test]
+    fn every_file_affects_a_build_of_an_unknown_language() {
+        let repository = temp_repo("affects-build-unknown", &[("README.md", "")]);
+
+        assert!(repository.affects_build("README.md"));
+    }
+
+    #[test]
+    fn update_applies_to_content() {
+        let content = apply_to_content("a\nb\nc\n", &update("a.txt", (1, 2), &["B", "C", "D"]));
+
+        assert_eq!(content.unwrap(), "a\nB\nC\nD\n");
+    }
+
+    #[test]
+    fn noop_leaves_content_as_it_is() {
+        let content = apply_to_content("a\r\nb", &Transformation::Noop).unwrap();
+
+        assert_eq!(content, "a\r\nb");
+    }
+
+    #[test]
+    fn whole_file_transformations_do_not_apply_to_content() {
+        let transformations = [
+            Transformation::CreateFile {
+                path: "a.txt".to_string(),
+            },
+            Transformation::DeleteFile {
+                path: "a.txt".to_string(),
+            },
+            Transformation::MoveFile {
+                old: "a.txt".to_string(),
+                new: "b.txt".to_string(),
+            },
+            Transformation::RenameSymbol {
+                old: "a".to_string(),
+                new: "b".to_string(),
+            },
+        ];
+
+        for transformation in &transformations {
+            let error = apply_to_content("a\n", transformation).unwrap_err();
+            assert!(error.to_string().starts_with("Only edits of the lines"));
+        }
+    }
+
+    #[test]
+    fn transform_writes_what_apply_to_content_returns() {
+        let mut repository = temp_repo("transform-content", &[("a.txt", "a\nb\n")]);
+        let transformation = insert("a.txt", 2, &["c"]);
+        let expected = apply_to_content("a\nb\n", &transformation).unwrap();
+
+        repository.transform(&transformation).unwrap();
+
+        assert_eq!(repository.read_file("a.txt").unwrap(), expected);
+    }
+
+    /// Whether resolving `filepath` in `repository` fails for being outside of it
+    fn is_outside(repository: &GitRepository, filepath: &str) -> bool {
+        let error = repository.resolve(filepath).unwrap_err();
+        matches!(
+            error.downcast_ref::<RakuneError>(),
+            Some(RakuneError::OutsideRepository { .. })
+        )
+    }
+
+    #[test]
+    fn path_inside_the_repository_resolves() {
+        let repository = temp_repo("resolve-inside", &[("src/a.rs", "")]);
+        let root = repository.root.canonicalize().unwrap();
+
+        assert_eq!(
+            repository.resolve("src/a.rs").unwrap(),
+            root.join("src/a.rs")
+        );
+        assert_eq!(
+            repository.resolve("src/new.rs").unwrap(),
+            root.join("src/new.rs")
+        );
+    }
+
+    #[test]
+    fn paths_leaving_the_repository_are_rejected() {
+        let repository = temp_repo("resolve-outside", &[("src/a.rs", "")]);
+
+        assert!(is_outside(&repository, "../x"));
+        assert!(is_outside(&repository, "src/../../x"));
+        assert!(is_outside(&repository, "/etc/passwd"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlink_out_of_the_repository_is_rejected() {
+        let mut repository = temp_repo("resolve-symlink", &[("src/a.rs", "")]);
+        std::os::unix::fs::symlink("/etc", repository.path("etc")).unwrap();
+        std::os::unix::fs::symlink("src/a.rs", repository.path("inside.rs")).unwrap();
+
+        assert!(is_outside(&repository, "etc/passwd"));
+        assert!(repository.resolve("inside.rs").is_ok());
+
+        repository.deny_symlinks = true;
+        let error = repository.resolve("inside.rs").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RakuneError>(),
+            Some(RakuneError::Symlink { .. })
+        ));
+    }
+
+    #[test]
+    fn move_file_renames_into_new_directories() {
+        let mut repository = temp_repo("move-file", &[("a.txt", "a\n")]);
+
+        repository
+            .transform(&Transformation::MoveFile {
+                old: "a.txt".to_string(),
+                new: "nested/b.txt".to_string(),
+            })
+            .unwrap();
+
+        assert!(!repository.path("a.txt").exists());
+        assert_eq!(repository.read_file("nested/b.txt").unwrap(), "a\n");
+    }
+
+    #[test]
+    fn move_file_keeps_an_existing_destination() {
+        let mut repository = temp_repo("move-onto", &[("a.txt", "a\n"), ("b.txt", "b\n")]);
+
+        let error = repository
+            .transform(&Transformation::MoveFile {
+                old: "a.txt".to_string(),
+                new: "b.txt".to_string(),
+            })
+            .unwrap_err();
+
+        assert!(error.to_string().contains("already exists"));
+        assert_eq!(repository.read_file("a.txt").unwrap(), "a\n");
+        assert_eq!(repository.read_file("b.txt").unwrap(), "b\n");
+    }
+
+    #[test]
+    fn failing_batch_restores_deleted_and_moved_files() {
+        let mut repository = temp_repo(
+            "delete-rollback",
+            &[("a.txt", "a\n"), ("b.txt", "b\n"), ("c.txt", "c\n")],
+        );
+
+        let result = repository.transform_all(&[
+            Transformation::DeleteFile {
+                path: "a.txt".to_string(),
+            },
+            Transformation::MoveFile {
+                old: "b.txt".to_string(),
+                new: "d.txt".to_string(),
+            },
+            update("c.txt", (5, 5), &["C"]),
+        ]);
+
+        assert!(result.is_err());
+        assert_eq!(repository.read_file("a.txt").unwrap(), "a\n");
+        assert_eq!(repository.read_file("b.txt").unwrap(), "b\n");
+        assert!(!repository.path("d.txt").exists());
+    }
+
+    #[test]
+    fn rendered_delete_matches_git() {
+        let mut repository = temp_repo("diff-delete", &[("a.txt", "a\nb\n")]);
+        let transformation = Transformation::DeleteFile {
+            path: "a.txt".to_string(),
+        };
+
+        let rendered = transformation.to_diff(&repository).unwrap();
+        repository.transform(&transformation).unwrap();
+        let diff = repository.diff(None).unwrap();
+
+        let hunks = |diff: &str| diff[diff.find("--- ").unwrap()..].to_string();
+        assert_eq!(hunks(&rendered), hunks(&diff));
+    }
+
+    #[test]
+    fn move_onto_an_existing_file_is_not_previewed() {
+        let repository = temp_repo("diff-move-onto", &[("a.txt", "a\n"), ("b.txt", "b\n")]);
+        let transformation = Transformation::MoveFile {
+            old: "a.txt".to_string(),
+            new: "b.txt".to_string(),
+        };
+
+        let error = transformation.to_diff(&repository).unwrap_err();
+
+        assert!(error.to_string().contains("already exists"));
+    }
+
+    #[test]
+    fn byte_range_spans_its_lines() {
+        let repository = temp_repo("byte-range", &[("a.txt", "a\nbc\nd\n")]);
+
+        let fragment = Fragment::from_byte_range(&repository, "a.txt", 2, 5).unwrap();
+
+        assert_eq!(fragment.line_range, (1, 1));
+        assert!(Fragment::from_byte_range(&repository, "a.txt", 2, 50).is_err());
+    }
+
+    #[test]
+    fn byte_range_outside_the_repository_is_refused() {
+        let repository = temp_repo("byte-range-outside", &[("a.txt", "a\n")]);
+
+        let error = Fragment::from_byte_range(&repository, "../a.txt", 0, 1).unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<RakuneError>(),
+            Some(RakuneError::OutsideRepository { .. })
+        ));
+    }
 }